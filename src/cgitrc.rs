@@ -0,0 +1,371 @@
+// Copyright (c) 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use thiserror;
+
+use crate::provider;
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+
+/// Separates the lines in a repository's "cgitrc" file that are generated by
+/// Reflectub from custom settings, which are preserved on update.
+pub const MARKER: &str = "# reflectub: settings below this line are preserved";
+
+/// Number of unchanged lines shown around changes in a `diff`.
+const DIFF_CONTEXT: usize = 3;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("cgitrc: cannot read base cgitrc '{path}'")]
+    ReadBase {
+        source: io::Error,
+        path: String,
+    },
+    #[error("cgitrc: cannot read '{path}'")]
+    Read {
+        source: io::Error,
+        path: String,
+    },
+    #[error("cgitrc: cannot write '{path}'")]
+    Write {
+        source: io::Error,
+        path: String,
+    },
+    #[error("cgitrc: cannot set permissions of '{path}'")]
+    SetMode {
+        source: io::Error,
+        path: String,
+    },
+}
+
+
+/// The initial contents of a mirrored repository's "cgitrc" file.
+#[derive(Debug, Clone, Copy)]
+pub enum Base<'a> {
+    /// Copy the file at this path.
+    Copy(&'a Path),
+
+    /// Render this template with the repository's metadata.
+    Template(&'a str),
+}
+
+/// Settings for a repository's "cgitrc" file that don't come from the
+/// repository's metadata.
+#[derive(Debug, Default)]
+pub struct Options<'a> {
+    pub base: Option<Base<'a>>,
+
+    /// The CGit section to list the repository in.
+    pub section: Option<&'a str>,
+
+    /// Note in the description that the repository was too large to mirror.
+    pub too_large: bool,
+
+    /// Leave the repository out of CGit's index page.
+    pub hide: bool,
+
+    /// Lines like "enable-html-serving=1" to add after the generated
+    /// settings.
+    pub extra_lines: &'a [String],
+
+    /// Permission bits for the file, like 0o644, instead of ones from the
+    /// umask.
+    pub mode: Option<u32>,
+}
+
+
+/// Write the Reflectub-managed section of the "cgitrc" file in the
+/// repository at `repo_path`.
+///
+/// The managed section consists of the base cgitrc followed by settings
+/// derived from `repo`. Lines below `MARKER` in an existing file are
+/// preserved. An existing file without a marker is replaced entirely.
+pub fn write<P: AsRef<Path>>(
+    repo_path: P,
+    repo: &provider::Repo,
+    options: &Options,
+) -> Result<(), Error> {
+    let cgitrc_path = repo_path
+        .as_ref()
+        .join("cgitrc");
+
+    let cgitrc = render(&read(&repo_path)?, repo, options)?;
+
+    fs::write(&cgitrc_path, cgitrc)
+        .map_err(|e| Error::Write {
+            source: e,
+            path: cgitrc_path.display().to_string(),
+        })?;
+
+    match options.mode {
+        Some(mode) => set_mode(&cgitrc_path, mode),
+        None => Ok(()),
+    }
+}
+
+/// Read the "cgitrc" file in the repository at `repo_path`.
+///
+/// A missing file reads as empty.
+pub fn read<P: AsRef<Path>>(repo_path: P) -> Result<String, Error> {
+    let cgitrc_path = repo_path
+        .as_ref()
+        .join("cgitrc");
+
+    match fs::read_to_string(&cgitrc_path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(Error::Read {
+            source: e,
+            path: cgitrc_path.display().to_string(),
+        }),
+    }
+}
+
+/// Build the "cgitrc" file that `write` would replace `existing` with: the
+/// managed section for `repo`, followed by the custom lines below `MARKER`
+/// in `existing`.
+pub fn render(
+    existing: &str,
+    repo: &provider::Repo,
+    options: &Options,
+) -> Result<String, Error> {
+    let custom: String = existing
+        .lines()
+        .skip_while(|line| *line != MARKER)
+        .skip(1)
+        .map(|line| format!("{}\n", line))
+        .collect();
+
+    let mut cgitrc = managed(repo, options)?;
+    cgitrc.push_str(MARKER);
+    cgitrc.push('\n');
+    cgitrc.push_str(&custom);
+
+    Ok(cgitrc)
+}
+
+/// Set the permission bits of the file at `path` to `mode`, as for a cgitrc
+/// file that the web server must be able to read.
+pub fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<(), Error> {
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+        .map_err(|e| Error::SetMode {
+            source: e,
+            path: path.as_ref().display().to_string(),
+        })
+}
+
+/// Build the Reflectub-managed "cgitrc" settings for `repo`.
+pub fn managed(
+    repo: &provider::Repo,
+    options: &Options,
+) -> Result<String, Error> {
+    let mut cgitrc = match &options.base {
+        Some(Base::Copy(base_cgitrc)) =>
+            fs::read_to_string(base_cgitrc)
+                .map_err(|e| Error::ReadBase {
+                    source: e,
+                    path: base_cgitrc.display().to_string(),
+                })?,
+        Some(Base::Template(template)) => render_template(template, repo),
+        None => String::new(),
+    };
+
+    if !cgitrc.is_empty() && !cgitrc.ends_with('\n') {
+        cgitrc.push('\n');
+    }
+
+    cgitrc.push_str(&format!("defbranch={}\n", repo.default_branch));
+    cgitrc.push_str(&format!("clone-url={}\n", repo.clone_url));
+    cgitrc.push_str(&format!("owner={}\n", repo.owner));
+
+    // List oversize repositories with a note that their contents weren't
+    // mirrored.
+    if options.too_large {
+        if repo.description().is_empty() {
+            cgitrc.push_str("desc=[too large to mirror]\n");
+        } else {
+            cgitrc.push_str(
+                &format!("desc={} [too large to mirror]\n", repo.description()),
+            );
+        }
+    }
+
+    if let Some(homepage) = &repo.homepage {
+        cgitrc.push_str(&format!("homepage={}\n", homepage));
+    }
+
+    if !repo.topics.is_empty() {
+        cgitrc.push_str(
+            &format!("extra-info=topics: {}\n", repo.topics.join(", ")),
+        );
+    }
+
+    if let Some(section) = options.section {
+        cgitrc.push_str(&format!("section={}\n", section));
+    }
+
+    if options.hide {
+        cgitrc.push_str("hide=1\n");
+    }
+
+    for line in options.extra_lines {
+        cgitrc.push_str(line);
+        cgitrc.push('\n');
+    }
+
+    Ok(cgitrc)
+}
+
+/// Substitute `{{variable}}` placeholders in `template` with values from
+/// `repo`.
+///
+/// Supported variables are `name`, `description`, `default_branch`,
+/// `clone_url`, and `owner`. Unknown variables are replaced with an empty
+/// string.
+pub fn render_template(template: &str, repo: &provider::Repo) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        rendered.push_str(&rest[..start]);
+
+        let value = match rest[start + 2..end].trim() {
+            "name" => &repo.name,
+            "description" => repo.description(),
+            "default_branch" => &repo.default_branch,
+            "clone_url" => &repo.clone_url,
+            "owner" => &repo.owner,
+            _ => "",
+        };
+        rendered.push_str(value);
+
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// Make a unified diff from `old` to `new`, both labelled `path`.
+///
+/// Returns `None` if they're the same.
+pub fn diff(path: &Path, old: &str, new: &str) -> Option<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let edits = edit_script(&old_lines, &new_lines);
+
+    // Group changes into hunks, merging ones whose context would overlap.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+
+    for (i, _) in edits.iter().enumerate().filter(|(_, (op, _))| *op != ' ') {
+        let start = i.saturating_sub(DIFF_CONTEXT);
+        let end = (i + 1 + DIFF_CONTEXT).min(edits.len());
+
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    if hunks.is_empty() {
+        return None;
+    }
+
+    let mut diff = format!("--- {0}\n+++ {0}\n", path.display());
+
+    // Count the lines of `edits` on the old side, leaving out additions, or
+    // on the new side, leaving out removals.
+    let count = |edits: &[(char, &str)], skip: char| edits
+        .iter()
+        .filter(|(op, _)| *op != skip)
+        .count();
+
+    for (start, end) in hunks {
+        let old_before = count(&edits[..start], '+');
+        let new_before = count(&edits[..start], '-');
+        let old_len = count(&edits[start..end], '+');
+        let new_len = count(&edits[start..end], '-');
+
+        // Empty ranges start at the line before them.
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_before + usize::from(old_len > 0),
+            old_len,
+            new_before + usize::from(new_len > 0),
+            new_len,
+        ));
+
+        for (op, line) in &edits[start..end] {
+            diff.push_str(&format!("{}{}\n", op, line));
+        }
+    }
+
+    Some(diff)
+}
+
+/// Find the shortest list of line removals ('-') and additions ('+') that
+/// turn `old` into `new`, with unchanged lines marked ' '.
+fn edit_script<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    // Lengths of the longest common subsequences of the suffixes of `old`
+    // and `new`.
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            edits.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            edits.push(('-', old[i]));
+            i += 1;
+        } else {
+            edits.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    edits.extend(old[i..].iter().map(|line| ('-', *line)));
+    edits.extend(new[j..].iter().map(|line| ('+', *line)));
+
+    edits
+}
@@ -18,10 +18,19 @@
 
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
 use rusqlite::{self, OptionalExtension};
 use thiserror;
 
-use crate::github;
+use crate::provider;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
 
 
 /// Repository metadata mapped to the database.
@@ -31,38 +40,107 @@ pub struct Repo {
     name: Option<String>,
     description: Option<String>,
     pub default_branch: Option<String>,
-    updated_at: Option<String>,
+    updated_at: Option<DateTime<FixedOffset>>,
+    pub clone_url: Option<String>,
+
+    /// When Reflectub last successfully mirrored or fetched the repository,
+    /// as opposed to when it last changed upstream.
+    pub last_mirrored_at: Option<DateTime<FixedOffset>>,
+
+    /// The repository's topics, stored as a comma-separated list.
+    pub topics: Vec<String>,
+    pub homepage: Option<String>,
+    pub owner: Option<String>,
+    pub fork: Option<bool>,
+    pub language: Option<String>,
+
+    /// Repository size in KiB.
+    pub size: Option<u64>,
+
+    /// When the repository was created upstream. `NULL` in rows stored before
+    /// the column was added.
+    pub created_at: Option<DateTime<FixedOffset>>,
+
+    /// When the repository was last pushed to. `NULL` in rows stored before
+    /// the column was added.
+    pub pushed_at: Option<DateTime<FixedOffset>>,
+
+    /// Hash of the metadata that Reflectub mirrors, to tell real changes from
+    /// `updated_at` bumps that don't affect the mirror, like new stars.
+    pub metadata_hash: Option<String>,
+
+    /// Size of the mirror on disk in bytes, measured after it was last
+    /// mirrored or updated.
+    pub disk_size: Option<u64>,
+
+    /// Whether the repository is private. `NULL` in rows stored before the
+    /// column was added.
+    pub private: Option<bool>,
 }
 
 impl Repo {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn description(&self) -> &str {
         self.description
             .as_deref()
             .unwrap_or("")
     }
-}
 
-impl From<&github::Repo> for Repo {
-    fn from(repo: &github::Repo) -> Self {
-        use chrono::DateTime;
-
-        let repo_updated_at = DateTime::parse_from_rfc3339(&repo.updated_at).ok();
-        let repo_pushed_at = DateTime::parse_from_rfc3339(&repo.pushed_at).ok();
+    pub fn updated_at(&self) -> Option<DateTime<FixedOffset>> {
+        self.updated_at
+    }
 
-        // Set `updated_at` to the most recent of `repo_updated_at` or
-        // `repo_pushed_at`.
-        let updated_at =
-            if repo_updated_at.is_none() && repo_pushed_at.is_none() {
-                repo.updated_at.clone()
+    /// Check if this repository is newer than `stored`, a row already read
+    /// from the database.
+    ///
+    /// Makes the same decision as `Db::repo_is_updated` without a query.
+    pub fn is_newer_than(&self, stored: &Repo) -> bool {
+        is_newer(stored.updated_at, self.updated_at)
+    }
 
-            // `repo_updated_at` and `repo_pushed_at` are both Some.
-            } else if repo_pushed_at.unwrap() > repo_updated_at.unwrap() {
-                repo.pushed_at.clone()
+    /// Build a `Repo` from a row selected by `repo_get` or `repo_all`.
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        Ok(
+            Repo {
+                id: row.get(0)?,
+                name: Some(row.get(1)?),
+                description: row.get(2)?,
+                default_branch: row.get(3)?,
+                updated_at: Some(parse_timestamp(row, 4)?),
+                clone_url: row.get(5)?,
+                last_mirrored_at: parse_optional_timestamp(row, 6)?,
+                topics: parse_topics(row.get(7)?),
+                homepage: row.get(8)?,
+                owner: row.get(9)?,
+                fork: row.get(10)?,
+                language: row.get(11)?,
+                size: row.get(12)?,
+                created_at: parse_optional_timestamp(row, 13)?,
+                pushed_at: parse_optional_timestamp(row, 14)?,
+                metadata_hash: row.get(15)?,
+                disk_size: row.get(16)?,
+                private: row.get(17)?,
+            }
+        )
+    }
+}
 
-            // Default to `repo.updated_at`.
-            } else {
-                repo.updated_at.clone()
-            };
+/// Convert a fetched repository into its database representation.
+///
+/// Both timestamps are parsed when the repository is fetched, so this
+/// conversion can't fail on a malformed `updated_at` or `pushed_at`.
+impl From<&provider::Repo> for Repo {
+    fn from(repo: &provider::Repo) -> Self {
+        // Set `updated_at` to the most recent of `repo.updated_at` or
+        // `repo.pushed_at`.
+        let updated_at = repo.updated_at.max(repo.pushed_at);
 
         Self {
             id: repo.id,
@@ -70,6 +148,144 @@ impl From<&github::Repo> for Repo {
             description: repo.description.clone(),
             default_branch: Some(repo.default_branch.clone()),
             updated_at: Some(updated_at),
+            clone_url: Some(repo.clone_url.clone()),
+            last_mirrored_at: None,
+            topics: repo.topics.clone(),
+            homepage: repo.homepage.clone(),
+            owner: Some(repo.owner.clone()),
+            fork: Some(repo.fork),
+            language: repo.language.clone(),
+            size: Some(repo.size),
+            created_at: repo.created_at,
+            pushed_at: Some(repo.pushed_at),
+            metadata_hash: Some(metadata_hash(repo)),
+            disk_size: None,
+            private: Some(repo.private),
+        }
+    }
+}
+
+/// Rebuild a repository from its stored metadata, for working without
+/// fetching from the provider.
+///
+/// Rows recorded before the owner, fork, language, size, and private columns
+/// were added have defaults for those fields until the repository is next updated.
+/// In rows without a `pushed_at` time, it's set to `updated_at`, which already
+/// includes it. Wikis aren't stored, so `has_wiki` is `false`.
+impl From<&Repo> for provider::Repo {
+    fn from(repo: &Repo) -> Self {
+        // `updated_at` is never null in the database.
+        let updated_at = repo.updated_at
+            .unwrap_or_else(|| DateTime::<Utc>::from(UNIX_EPOCH).into());
+
+        provider::Repo {
+            id: repo.id,
+            name: repo.name.clone().unwrap_or_default(),
+            owner: repo.owner.clone().unwrap_or_default(),
+            description: repo.description.clone(),
+            fork: repo.fork.unwrap_or(false),
+            language: repo.language.clone(),
+            clone_url: repo.clone_url.clone().unwrap_or_default(),
+            default_branch: repo.default_branch
+                .clone()
+                .unwrap_or_else(|| "master".to_owned()),
+            homepage: repo.homepage.clone(),
+            size: repo.size.unwrap_or(0),
+            topics: repo.topics.clone(),
+            created_at: repo.created_at,
+            updated_at,
+            pushed_at: repo.pushed_at.unwrap_or(updated_at),
+            has_wiki: false,
+            private: repo.private.unwrap_or(false),
+        }
+    }
+}
+
+
+/// Schema changes applied after the initial `repositories` table. The
+/// database's `user_version` records how many have been run.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE repositories ADD COLUMN clone_url TEXT",
+    "ALTER TABLE repositories ADD COLUMN last_mirrored_at TEXT",
+    "ALTER TABLE repositories ADD COLUMN topics TEXT",
+    "ALTER TABLE repositories ADD COLUMN homepage TEXT",
+    "ALTER TABLE repositories ADD COLUMN owner TEXT",
+    "ALTER TABLE repositories ADD COLUMN fork INTEGER",
+    "ALTER TABLE repositories ADD COLUMN language TEXT",
+    "ALTER TABLE repositories ADD COLUMN size INTEGER",
+    "ALTER TABLE repositories ADD COLUMN created_at TEXT",
+    r#"
+        CREATE TABLE run_state (
+            repo_id INTEGER PRIMARY KEY,
+            status TEXT NOT NULL
+        )
+    "#,
+    "ALTER TABLE repositories ADD COLUMN pushed_at TEXT",
+    "ALTER TABLE repositories ADD COLUMN metadata_hash TEXT",
+    "ALTER TABLE repositories ADD COLUMN disk_size INTEGER",
+    "ALTER TABLE repositories ADD COLUMN private INTEGER",
+    r#"
+        CREATE TABLE releases (
+            repo_id INTEGER NOT NULL,
+            tag_name TEXT NOT NULL,
+            name TEXT,
+            published_at TEXT,
+            asset_urls TEXT,
+            PRIMARY KEY (repo_id, tag_name)
+        )
+    "#,
+];
+
+
+/// A summary of a repository's release, stored with
+/// `Db::release_replace_all`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Release {
+    pub tag_name: String,
+    pub name: Option<String>,
+    pub published_at: Option<DateTime<FixedOffset>>,
+
+    /// Download URLs of the release's assets, stored one per line.
+    pub asset_urls: Vec<String>,
+}
+
+impl From<&provider::Release> for Release {
+    fn from(release: &provider::Release) -> Self {
+        Release {
+            tag_name: release.tag_name.clone(),
+            name: release.name.clone(),
+            published_at: release.published_at,
+            asset_urls: release.assets
+                .iter()
+                .map(|asset| asset.url.clone())
+                .collect(),
+        }
+    }
+}
+
+
+/// A repository to write with `Db::repo_write_all`.
+#[derive(Debug)]
+pub enum RepoWrite {
+    Insert(Repo),
+    Update(Repo),
+}
+
+/// Whether a repository was processed successfully during the current run.
+///
+/// Run state is kept until a run finishes without errors, so that an
+/// interrupted or failed run can be resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunStatus {
+    Done,
+    Failed,
+}
+
+impl RunStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Done => "done",
+            RunStatus::Failed => "failed",
         }
     }
 }
@@ -82,6 +298,12 @@ pub enum Error {
 
     #[error("connection pool error")]
     Pool(#[from] r2d2::Error),
+
+    #[error("cannot create database directory '{path}'")]
+    CreateDir {
+        source: io::Error,
+        path: String,
+    },
 }
 
 
@@ -92,21 +314,107 @@ pub struct Db {
 
 impl Db {
     /// Open a connection to the database.
-    pub fn connect(path: &str) -> Result<Self, Error> {
+    ///
+    /// The file and any missing parent directories are created if they don't
+    /// exist. `pool_size` sets the maximum number of connections in the pool.
+    /// If `None`, the r2d2 default is used.
+    pub fn connect(path: &str, pool_size: Option<u32>) -> Result<Self, Error> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| Error::CreateDir {
+                    source: e,
+                    path: dir.display().to_string(),
+                })?;
+        }
+
         let manager = SqliteConnectionManager::file(path)
             .with_flags(
                 rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
                 | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
             );
 
+        let mut builder = r2d2::Pool::builder();
+
+        if let Some(pool_size) = pool_size {
+            builder = builder.max_size(pool_size);
+        }
+
         Ok(
             Db {
-                pool: r2d2::Pool::new(manager)?,
+                pool: builder.build(manager)?,
             }
         )
     }
 
-    /// Initialise the database with tables and indexes.
+    /// Open a connection to an existing database that can't be written to, as
+    /// for a dry run.
+    pub fn connect_read_only(
+        path: &str,
+        pool_size: Option<u32>,
+    ) -> Result<Self, Error> {
+        let manager = SqliteConnectionManager::file(path)
+            .with_flags(rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY);
+
+        let mut builder = r2d2::Pool::builder();
+
+        if let Some(pool_size) = pool_size {
+            builder = builder.max_size(pool_size);
+        }
+
+        Ok(
+            Db {
+                pool: builder.build(manager)?,
+            }
+        )
+    }
+
+    /// Open a database that's kept in memory, as for tests.
+    ///
+    /// Every connection in the pool shares the same database, which is
+    /// discarded when the `Db` is dropped. Each call gets a new database.
+    pub fn connect_in_memory() -> Result<Self, Error> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let uri = format!(
+            "file:reflectub-{}-{}?mode=memory&cache=shared",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        );
+
+        let manager = SqliteConnectionManager::file(uri)
+            .with_flags(
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+                | rusqlite::OpenFlags::SQLITE_OPEN_CREATE
+                | rusqlite::OpenFlags::SQLITE_OPEN_URI,
+            );
+
+        Ok(
+            Db {
+                // Keep a connection open so the database isn't discarded
+                // while the pool is idle.
+                pool: r2d2::Pool::builder()
+                    .min_idle(Some(1))
+                    .build(manager)?,
+            }
+        )
+    }
+
+    /// Return `true` if `create` would change the database, because it has
+    /// no tables or has pending migrations.
+    pub fn needs_create(&self) -> Result<bool, Error> {
+        let pool = self.pool.get()?;
+
+        let version: usize = pool.query_row(
+            "PRAGMA user_version",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(version < MIGRATIONS.len())
+    }
+
+    /// Initialise the database with tables and indexes, and run any pending
+    /// migrations.
     pub fn create(&self) -> Result<(), Error> {
         let mut pool = self.pool.get()?;
         let tx = pool.transaction()?;
@@ -132,6 +440,20 @@ impl Db {
             [],
         )?;
 
+        let version: usize = tx.query_row(
+            "PRAGMA user_version",
+            [],
+            |row| row.get(0),
+        )?;
+
+        if version < MIGRATIONS.len() {
+            for migration in &MIGRATIONS[version..] {
+                tx.execute(migration, [])?;
+            }
+
+            tx.pragma_update(None, "user_version", &MIGRATIONS.len())?;
+        }
+
         tx.commit()?;
 
         Ok(())
@@ -152,22 +474,25 @@ impl Db {
                 name,
                 description,
                 default_branch,
-                updated_at
+                updated_at,
+                clone_url,
+                last_mirrored_at,
+                topics,
+                homepage,
+                owner,
+                fork,
+                language,
+                size,
+                created_at,
+                pushed_at,
+                metadata_hash,
+                disk_size,
+                private
             FROM repositories
             WHERE id = ?
             "#,
             [id],
-            |row| {
-                Ok(
-                    Repo {
-                        id: row.get(0)?,
-                        name: Some(row.get(1)?),
-                        description: row.get(2)?,
-                        default_branch: row.get(3)?,
-                        updated_at: Some(row.get(4)?),
-                    }
-                )
-            },
+            Repo::from_row,
         )?;
 
         tx.commit()?;
@@ -175,26 +500,97 @@ impl Db {
         Ok(repo)
     }
 
+    /// Get all repositories, most recently updated first.
+    pub fn repo_all(&self) -> Result<Vec<Repo>, Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        let repos = {
+            let mut stmt = tx.prepare(
+                r#"
+                SELECT
+                    id,
+                    name,
+                    description,
+                    default_branch,
+                    updated_at,
+                    clone_url,
+                    last_mirrored_at,
+                    topics,
+                    homepage,
+                    owner,
+                    fork,
+                    language,
+                    size,
+                    created_at,
+                    pushed_at,
+                    metadata_hash,
+                    disk_size,
+                    private
+                FROM repositories
+                ORDER BY datetime(updated_at) DESC
+                "#,
+            )?;
+
+            let rows = stmt.query_map([], Repo::from_row)?;
+
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        tx.commit()?;
+
+        Ok(repos)
+    }
+
+    /// Get the repositories named `name`, which may belong to different
+    /// owners.
+    pub fn repo_all_named(&self, name: &str) -> Result<Vec<Repo>, Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        let repos = {
+            let mut stmt = tx.prepare(
+                r#"
+                SELECT
+                    id,
+                    name,
+                    description,
+                    default_branch,
+                    updated_at,
+                    clone_url,
+                    last_mirrored_at,
+                    topics,
+                    homepage,
+                    owner,
+                    fork,
+                    language,
+                    size,
+                    created_at,
+                    pushed_at,
+                    metadata_hash,
+                    disk_size,
+                    private
+                FROM repositories
+                WHERE name = ?
+                "#,
+            )?;
+
+            let rows = stmt.query_map([name], Repo::from_row)?;
+
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        tx.commit()?;
+
+        Ok(repos)
+    }
+
     /// Insert a new repository.
     pub fn repo_insert(&self, repo: Repo) -> Result<(), Error> {
         let mut pool = self.pool.get()?;
         let tx = pool.transaction()?;
 
-        tx.execute(
-            r#"
-            INSERT INTO repositories
-                (id, name, description, default_branch, updated_at)
-                VALUES
-                (?, ?, ?, ?, ?)
-            "#,
-            rusqlite::params![
-                repo.id,
-                &repo.name,
-                &repo.description,
-                &repo.default_branch,
-                &repo.updated_at,
-            ],
-        )?;
+        insert_repo(&tx, &repo)?;
 
         tx.commit()?;
 
@@ -204,7 +600,13 @@ impl Db {
     /// Check if the given repository is newer than the one in the repository.
     ///
     /// Compares the `updated_at` field to find out whether the repository was
-    /// updated.
+    /// updated. For fetched repositories, `updated_at` holds the later of the
+    /// provider's `updated_at` and `pushed_at` times, so both metadata
+    /// changes and pushes count as updates.
+    ///
+    /// Timestamps are compared at full precision, so a change less than a
+    /// second after the stored time is still detected. Returns `false` if the
+    /// repository isn't in the database.
     pub fn repo_is_updated(
         &self,
         repo: &Repo,
@@ -212,29 +614,20 @@ impl Db {
         let mut pool = self.pool.get()?;
         let tx = pool.transaction()?;
 
-        let is_updated = match tx.query_row(
+        let stored_updated_at = tx.query_row(
             r#"
-            SELECT 1
+            SELECT updated_at
             FROM repositories
             WHERE id = ?
-                AND datetime(updated_at) < datetime(?)
             "#,
-            rusqlite::params![
-                repo.id,
-                &repo.updated_at,
-            ],
-            |row| row.get::<usize, u8>(0),
+            [repo.id],
+            |row| parse_timestamp(row, 0),
         )
-            .optional()
-        {
-            Ok(Some(_)) => Ok(true),
-            Ok(None) => Ok(false),
-            Err(e) => Err(e.into()),
-        };
+            .optional()?;
 
         tx.commit()?;
 
-        is_updated
+        Ok(is_newer(stored_updated_at, repo.updated_at))
     }
 
     /// Update an existing repository.
@@ -242,27 +635,415 @@ impl Db {
         let mut pool = self.pool.get()?;
         let tx = pool.transaction()?;
 
+        update_repo(&tx, repo)?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Insert and update repositories, and record the run state of
+    /// repositories by ID, in a single transaction.
+    ///
+    /// If any write fails, none of them are applied, so a repository is never
+    /// recorded as done without its row.
+    pub fn repo_write_all(
+        &self,
+        writes: &[RepoWrite],
+        run_state: &[(i64, RunStatus)],
+    ) -> Result<(), Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        for write in writes {
+            match write {
+                RepoWrite::Insert(repo) => insert_repo(&tx, repo)?,
+                RepoWrite::Update(repo) => update_repo(&tx, repo)?,
+            }
+        }
+
+        for (id, status) in run_state {
+            set_run_state(&tx, *id, *status)?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Delete the repository with ID `id`.
+    pub fn repo_delete(&self, id: i64) -> Result<(), Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
         tx.execute(
             r#"
-            UPDATE repositories
-            SET
-                name = ?,
-                description = ?,
-                default_branch = ?,
-                updated_at = ?
+            DELETE FROM repositories
             WHERE id = ?
             "#,
-            rusqlite::params![
-                &repo.name,
-                &repo.description,
-                &repo.default_branch,
-                &repo.updated_at,
-                repo.id,
-            ],
+            [id],
         )?;
 
+        tx.execute("DELETE FROM releases WHERE repo_id = ?", [id])?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Get the releases stored for repository `repo_id`, newest first, with
+    /// unpublished ones last.
+    pub fn release_all(&self, repo_id: i64) -> Result<Vec<Release>, Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        let releases = {
+            let mut stmt = tx.prepare(
+                r#"
+                SELECT
+                    tag_name,
+                    name,
+                    published_at,
+                    asset_urls
+                FROM releases
+                WHERE repo_id = ?
+                ORDER BY published_at IS NULL, published_at DESC, tag_name
+                "#,
+            )?;
+
+            let rows = stmt.query_map([repo_id], |row| {
+                Ok(
+                    Release {
+                        tag_name: row.get(0)?,
+                        name: row.get(1)?,
+                        published_at: parse_optional_timestamp(row, 2)?,
+                        asset_urls: parse_asset_urls(row.get(3)?),
+                    }
+                )
+            })?;
+
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        tx.commit()?;
+
+        Ok(releases)
+    }
+
+    /// Replace the stored releases of repository `repo_id` with `releases`.
+    pub fn release_replace_all(
+        &self,
+        repo_id: i64,
+        releases: &[Release],
+    ) -> Result<(), Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        tx.execute("DELETE FROM releases WHERE repo_id = ?", [repo_id])?;
+
+        for release in releases {
+            tx.execute(
+                r#"
+                INSERT OR REPLACE INTO releases
+                    (
+                        repo_id,
+                        tag_name,
+                        name,
+                        published_at,
+                        asset_urls
+                    )
+                    VALUES
+                    (?, ?, ?, ?, ?)
+                "#,
+                rusqlite::params![
+                    repo_id,
+                    &release.tag_name,
+                    &release.name,
+                    release.published_at.map(format_timestamp),
+                    release.asset_urls.join("\n"),
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Get the status of every repository processed since the run state was
+    /// last cleared, keyed by repository ID.
+    pub fn run_state_all(&self) -> Result<HashMap<i64, RunStatus>, Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        let run_state = {
+            let mut stmt = tx.prepare(
+                r#"
+                SELECT repo_id, status
+                FROM run_state
+                "#,
+            )?;
+
+            let rows = stmt.query_map([], |row| {
+                let status = match row.get_ref(1)?.as_str()? {
+                    "failed" => RunStatus::Failed,
+                    _ => RunStatus::Done,
+                };
+
+                Ok((row.get(0)?, status))
+            })?;
+
+            rows.collect::<Result<HashMap<_, _>, _>>()?
+        };
+
+        tx.commit()?;
+
+        Ok(run_state)
+    }
+
+    /// Record the status of repository `id` in the current run.
+    pub fn run_state_set(
+        &self,
+        id: i64,
+        status: RunStatus,
+    ) -> Result<(), Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        set_run_state(&tx, id, status)?;
+
         tx.commit()?;
 
         Ok(())
     }
+
+    /// Forget the run state, starting a new run.
+    pub fn run_state_clear(&self) -> Result<(), Error> {
+        let mut pool = self.pool.get()?;
+        let tx = pool.transaction()?;
+
+        tx.execute("DELETE FROM run_state", [])?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+
+/// Record the run state of repository `id` as part of `tx`.
+fn set_run_state(
+    tx: &rusqlite::Transaction,
+    id: i64,
+    status: RunStatus,
+) -> Result<(), Error> {
+    tx.execute(
+        r#"
+        INSERT OR REPLACE INTO run_state
+            (repo_id, status)
+            VALUES
+            (?, ?)
+        "#,
+        rusqlite::params![id, status.as_str()],
+    )?;
+
+    Ok(())
+}
+
+/// Insert `repo` as part of `tx`.
+fn insert_repo(tx: &rusqlite::Transaction, repo: &Repo) -> Result<(), Error> {
+    tx.execute(
+        r#"
+        INSERT INTO repositories
+            (
+                id,
+                name,
+                description,
+                default_branch,
+                updated_at,
+                clone_url,
+                last_mirrored_at,
+                topics,
+                homepage,
+                owner,
+                fork,
+                language,
+                size,
+                created_at,
+                pushed_at,
+                metadata_hash,
+                disk_size,
+                private
+            )
+            VALUES
+            (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        rusqlite::params![
+            repo.id,
+            &repo.name,
+            &repo.description,
+            &repo.default_branch,
+            &repo.updated_at.map(format_timestamp),
+            &repo.clone_url,
+            &repo.last_mirrored_at.map(format_timestamp),
+            &repo.topics.join(","),
+            &repo.homepage,
+            &repo.owner,
+            &repo.fork,
+            &repo.language,
+            &repo.size,
+            &repo.created_at.map(format_timestamp),
+            &repo.pushed_at.map(format_timestamp),
+            &repo.metadata_hash,
+            &repo.disk_size,
+            &repo.private,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Update `repo` as part of `tx`.
+fn update_repo(tx: &rusqlite::Transaction, repo: &Repo) -> Result<(), Error> {
+    tx.execute(
+        r#"
+        UPDATE repositories
+        SET
+            name = ?,
+            description = ?,
+            default_branch = ?,
+            updated_at = ?,
+            clone_url = ?,
+            last_mirrored_at = ?,
+            topics = ?,
+            homepage = ?,
+            owner = ?,
+            fork = ?,
+            language = ?,
+            size = ?,
+            created_at = ?,
+            pushed_at = ?,
+            metadata_hash = ?,
+            disk_size = ?,
+            private = ?
+        WHERE id = ?
+        "#,
+        rusqlite::params![
+            &repo.name,
+            &repo.description,
+            &repo.default_branch,
+            &repo.updated_at.map(format_timestamp),
+            &repo.clone_url,
+            &repo.last_mirrored_at.map(format_timestamp),
+            &repo.topics.join(","),
+            &repo.homepage,
+            &repo.owner,
+            &repo.fork,
+            &repo.language,
+            &repo.size,
+            &repo.created_at.map(format_timestamp),
+            &repo.pushed_at.map(format_timestamp),
+            &repo.metadata_hash,
+            &repo.disk_size,
+            &repo.private,
+            repo.id,
+        ],
+    )?;
+
+    Ok(())
+}
+
+
+/// Return `true` if `updated` is later than `stored`, or `false` if either is
+/// missing.
+fn is_newer(
+    stored: Option<DateTime<FixedOffset>>,
+    updated: Option<DateTime<FixedOffset>>,
+) -> bool {
+    match (stored, updated) {
+        (Some(stored), Some(updated)) => stored < updated,
+        _ => false,
+    }
+}
+
+/// Hash the fields of `repo` that end up in its mirror or cgitrc.
+///
+/// Uses 64-bit FNV-1a rather than the standard library's hasher, whose output
+/// can change between Rust releases, since the hash is stored.
+fn metadata_hash(repo: &provider::Repo) -> String {
+    let fields = [
+        repo.description(),
+        &repo.default_branch,
+        &format_timestamp(repo.pushed_at),
+        &repo.clone_url,
+        repo.homepage.as_deref().unwrap_or(""),
+        &repo.topics.join(","),
+        &repo.owner,
+        if repo.fork { "fork" } else { "" },
+        repo.language.as_deref().unwrap_or(""),
+        &repo.size.to_string(),
+    ];
+
+    // Only hashed when set, so that hashes stored before the field was added
+    // stay the same for public repositories.
+    let private = if repo.private { Some("private") } else { None };
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for field in fields.iter().chain(&private) {
+        // Separate fields with a null byte so that moving text from one to
+        // the next changes the hash.
+        for byte in field.bytes().chain(std::iter::once(0)) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    format!("{:016x}", hash)
+}
+
+/// Format `timestamp` as an RFC 3339 string for storage.
+fn format_timestamp(timestamp: DateTime<FixedOffset>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// Parse the RFC 3339 timestamp in column `index` of `row`.
+fn parse_timestamp(
+    row: &rusqlite::Row,
+    index: usize,
+) -> Result<DateTime<FixedOffset>, rusqlite::Error> {
+    let timestamp: String = row.get(index)?;
+
+    DateTime::parse_from_rfc3339(&timestamp)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            Box::new(e),
+        ))
+}
+
+/// Parse the nullable RFC 3339 timestamp in column `index` of `row`.
+fn parse_optional_timestamp(
+    row: &rusqlite::Row,
+    index: usize,
+) -> Result<Option<DateTime<FixedOffset>>, rusqlite::Error> {
+    match row.get_ref(index)? {
+        rusqlite::types::ValueRef::Null => Ok(None),
+        _ => parse_timestamp(row, index).map(Some),
+    }
+}
+
+/// Split a comma-separated list of topics.
+fn parse_topics(topics: Option<String>) -> Vec<String> {
+    topics
+        .filter(|t| !t.is_empty())
+        .map(|t| t.split(',').map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Split a newline-separated list of asset URLs.
+fn parse_asset_urls(asset_urls: Option<String>) -> Vec<String> {
+    asset_urls
+        .map(|urls| urls.lines().map(str::to_owned).collect())
+        .unwrap_or_default()
 }
@@ -16,11 +16,119 @@
 // along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
 
 
+use log::{debug, warn};
 use thiserror;
 
+use std::collections::hash_map::RandomState;
 use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
+use std::ops::AddAssign;
 use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+
+/// Fetch refspec that copies all refs from a remote, as `git clone --mirror`
+/// does.
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+
+/// Fetch refspec that copies only branches, used instead of `MIRROR_REFSPEC`
+/// when tags are left out.
+const BRANCHES_REFSPEC: &str = "+refs/heads/*:refs/heads/*";
+
+/// How long to wait for a fetch to stop after it times out, before leaving
+/// it running in the background.
+const FETCH_CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// Log target of the fetch details logged with `FetchSettings::trace`.
+pub const TRACE_TARGET: &str = "git";
+
+
+/// Options for fetching from remotes.
+#[derive(Debug, Default, Clone)]
+pub struct FetchSettings {
+    /// Number of times to retry a fetch that fails with a transient network
+    /// error.
+    pub retries: u32,
+
+    /// Abort a fetch that takes longer than this.
+    pub timeout: Option<Duration>,
+
+    /// URL of an HTTP(S) proxy to fetch through.
+    pub proxy: Option<String>,
+
+    /// Wait a random time of up to this long before each fetch.
+    pub throttle: Option<Duration>,
+
+    /// Extra "Name: value" headers to send with HTTP(S) fetches.
+    pub headers: Vec<String>,
+
+    /// Only fetch branches, leaving out tags and other refs.
+    pub no_tags: bool,
+
+    /// Git config values, as "key" and "value", to set in the repository
+    /// before fetching, both in new mirrors and on update.
+    pub git_config: Vec<(String, String)>,
+
+    /// Log the remote, ref updates, and transfer statistics of each fetch
+    /// to `TRACE_TARGET`.
+    pub trace: bool,
+
+    /// Cancel fetches in progress once set, as on a forced shutdown.
+    pub cancel: Arc<AtomicBool>,
+}
+
+
+/// Amount of data received by fetches.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TransferStats {
+    pub received_bytes: u64,
+    pub received_objects: u64,
+}
+
+impl AddAssign for TransferStats {
+    fn add_assign(&mut self, other: Self) {
+        self.received_bytes += other.received_bytes;
+        self.received_objects += other.received_objects;
+    }
+}
+
+
+/// Options for creating a mirror with `mirror` or `init_mirror`.
+#[derive(Debug, Clone)]
+pub struct MirrorOptions {
+    /// URL of the repository to mirror.
+    pub url: String,
+
+    /// Name of the remote to add. Defaults to "origin".
+    pub remote_name: String,
+
+    /// Text for the mirror's `description` file.
+    pub description: String,
+
+    /// Branch to point `HEAD` at. Defaults to "master".
+    pub default_branch: String,
+
+    pub fetch_settings: FetchSettings,
+}
+
+impl MirrorOptions {
+    /// Options for mirroring `url` with the default remote name and branch,
+    /// and no description.
+    pub fn new(url: &str) -> Self {
+        MirrorOptions {
+            url: url.to_owned(),
+            remote_name: "origin".to_owned(),
+            description: String::new(),
+            default_branch: "master".to_owned(),
+            fetch_settings: FetchSettings::default(),
+        }
+    }
+}
 
 
 #[derive(Debug, thiserror::Error)]
@@ -64,12 +172,51 @@ pub enum Error {
         source: git2::Error,
         remote_name: String,
     },
+    #[error("update: cannot restore mirror config for remote '{remote_name}'")]
+    UpdateRestoreMirror {
+        source: git2::Error,
+        remote_name: String,
+    },
     #[error("update: cannot fetch from remote '{remote_name}")]
     UpdateFetch {
         source: git2::Error,
         remote_name: String,
     },
 
+    #[error("cannot set URL of remote '{remote_name}' to '{url}'")]
+    SetRemoteUrl {
+        source: git2::Error,
+        remote_name: String,
+        url: String,
+    },
+
+    #[error("verify: repo '{path}' is corrupt")]
+    VerifyCorrupt {
+        source: git2::Error,
+        path: String,
+    },
+
+    #[error("bundle: cannot open repo '{path}'")]
+    BundleOpenRepo {
+        source: git2::Error,
+        path: String,
+    },
+    #[error("bundle: cannot run git to bundle '{path}'")]
+    BundleRunGit {
+        source: std::io::Error,
+        path: String,
+    },
+    #[error("bundle: git failed to bundle '{path}': {stderr}")]
+    BundleFailed {
+        path: String,
+        stderr: String,
+    },
+    #[error("bundle: cannot write '{path}'")]
+    BundleWrite {
+        source: std::io::Error,
+        path: String,
+    },
+
     #[error("{action}: cannot switch to branch '{branch}'")]
     GitChangeBranch {
         source: git2::Error,
@@ -77,6 +224,13 @@ pub enum Error {
         branch: String,
     },
 
+    #[error("{action}: cannot set config '{key}'")]
+    GitSetConfig {
+        source: git2::Error,
+        action: String,
+        key: String,
+    },
+
     #[error("git error")]
     Git(#[from] git2::Error),
 
@@ -84,6 +238,28 @@ pub enum Error {
     Io(#[from] std::io::Error),
 }
 
+impl Error {
+    /// Return `true` if `verify` found damaged objects or references, as
+    /// opposed to being unable to read the repository at all.
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            Error::VerifyCorrupt { source, .. } => matches!(
+                source.class(),
+                git2::ErrorClass::Odb
+                | git2::ErrorClass::Object
+                | git2::ErrorClass::Reference
+                | git2::ErrorClass::Zlib
+                | git2::ErrorClass::Tree
+                | git2::ErrorClass::Tag
+                | git2::ErrorClass::Indexer
+                | git2::ErrorClass::Sha1
+            ),
+            _ => false,
+        }
+    }
+}
+
+
 
 /// Mirror a repository.
 ///
@@ -92,69 +268,107 @@ pub enum Error {
 /// ```shell
 /// git clone --mirror URL
 /// ```
-pub fn mirror<P: AsRef<Path> + Copy>(
-    url: &str,
+///
+/// The remote is added as `options.remote_name`. Objects are fetched
+/// according to `options.fetch_settings`. Returns the amount of data
+/// received.
+pub fn mirror<P: AsRef<Path>>(
     path: P,
-    description: &str,
-    default_branch: &str,
+    options: &MirrorOptions,
+) -> Result<TransferStats, Error> {
+    let repo = repo_init_mirror(&path, options)?;
+
+    let stats = fetch(
+        path.as_ref(),
+        &options.remote_name,
+        false,
+        &options.fetch_settings,
+    )
+        .map_err(|e| Error::MirrorFetch {
+            source: e,
+            remote_name: options.remote_name.clone(),
+        })?;
+
+    // Set HEAD even for "master", since `init.defaultBranch` may have made
+    // the new repository's HEAD something else.
+    repo_change_to_existing_branch(&repo, &options.default_branch)
+        .map_err(|e| Error::GitChangeBranch {
+            source: e,
+            action: "mirror".to_owned(),
+            branch: options.default_branch.clone(),
+        })?;
+
+    Ok(stats)
+}
+
+/// Set up a mirror of a repository without fetching any objects.
+///
+/// The repository can be filled in later with `update`.
+/// `options.fetch_settings` is ignored.
+pub fn init_mirror<P: AsRef<Path>>(
+    path: P,
+    options: &MirrorOptions,
 ) -> Result<(), Error> {
+    let repo = repo_init_mirror(path, options)?;
+
+    repo_change_current_branch(&repo, &options.default_branch)
+        .map_err(|e| Error::GitChangeBranch {
+            source: e,
+            action: "mirror".to_owned(),
+            branch: options.default_branch.clone(),
+        })
+}
+
+/// Create a bare repository at `path` with a mirror remote for `url`.
+fn repo_init_mirror<P: AsRef<Path>>(
+    path: P,
+    options: &MirrorOptions,
+) -> Result<git2::Repository, Error> {
     let repo = git2::Repository::init_opts(
-        path,
-        &git2::RepositoryInitOptions::new()
+        &path,
+        git2::RepositoryInitOptions::new()
             .bare(true)
 
             // On Linux, using the external template prevents the custom
             // description from being added. It doesn't make a difference on
             // Mac OS.
-            .external_template(false)
-            .description(description),
+            .external_template(false),
     )
         .map_err(|e| Error::MirrorCreateRepo {
             source: e,
             path: format!("{}", path.as_ref().display()),
         })?;
 
-    let remote_name = "origin";
+    // Write the description the same way as on update, rather than with
+    // `RepositoryInitOptions::description`, which leaves out the trailing
+    // newline.
+    update_description(&path, &options.description)?;
 
-    let mut remote = repo.remote_with_fetch(
-        remote_name,
-        url,
-        "+refs/*:refs/*",
+    repo.remote_with_fetch(
+        &options.remote_name,
+        &options.url,
+        MIRROR_REFSPEC,
     )
         .map_err(|e| Error::MirrorAddRemote {
             source: e,
-            remote_name: remote_name.to_owned(),
-            url: url.to_owned(),
+            remote_name: options.remote_name.clone(),
+            url: options.url.clone(),
         })?;
 
     let mut config = repo.config()
-        .map_err(|e| Error::MirrorConfigGet(e))?;
+        .map_err(Error::MirrorConfigGet)?;
     config.set_bool(
-        &format!("remote.{}.mirror", remote_name),
+        &format!("remote.{}.mirror", options.remote_name),
         true,
     )
         .map_err(|e| Error::MirrorRemoteEnableMirror {
             source: e,
-            remote_name: remote_name.to_owned(),
+            remote_name: options.remote_name.clone(),
         })?;
 
-    let refspecs: [&str; 0] = [];
-    remote.fetch(&refspecs, None, None)
-        .map_err(|e| Error::MirrorFetch {
-            source: e,
-            remote_name: remote_name.to_owned(),
-        })?;
-
-    if default_branch != "master" {
-        repo_change_current_branch(&repo, default_branch)
-            .map_err(|e| Error::GitChangeBranch {
-                source: e,
-                action: "mirror".to_owned(),
-                branch: default_branch.to_owned(),
-            })?;
-    }
+    repo_set_config(&repo, &options.fetch_settings.git_config, "mirror")?;
 
-    Ok(())
+    Ok(repo)
 }
 
 /// Update remotes.
@@ -164,9 +378,16 @@ pub fn mirror<P: AsRef<Path> + Copy>(
 /// ```shell
 /// git remote update
 /// ```
+///
+/// Objects are fetched according to `fetch_settings`. The mirror config of
+/// `remote_name`, the remote that was mirrored, is restored if it's missing.
+/// Other remotes, as added by hand, are fetched with their own config. Returns
+/// the amount of data received from all remotes.
 pub fn update<P: AsRef<Path> + Copy>(
     path: P,
-) -> Result<(), Error> {
+    remote_name: &str,
+    fetch_settings: &FetchSettings,
+) -> Result<TransferStats, Error> {
     let repo = git2::Repository::open_bare(path)
         .map_err(|e| Error::UpdateOpenRepo {
             source: e,
@@ -178,32 +399,224 @@ pub fn update<P: AsRef<Path> + Copy>(
             source: e,
             path: format!("{}", path.as_ref().display()),
         })?;
-    for remote_opt in remotes {
-        if let Some(remote_name) = remote_opt {
-            let mut remote = repo.find_remote(remote_name)
+
+    repo_set_config(&repo, &fetch_settings.git_config, "update")?;
+
+    let mut stats = TransferStats::default();
+
+    for name in remotes.into_iter().flatten() {
+        if name == remote_name {
+            let remote = repo.find_remote(name)
                 .map_err(|e| Error::UpdateFindRemote {
                     source: e,
-                    remote_name: remote_name.to_owned(),
+                    remote_name: name.to_owned(),
                 })?;
 
-            let mut fetch_options = git2::FetchOptions::new();
-            fetch_options
-                .prune(git2::FetchPrune::On)
-                .download_tags(git2::AutotagOption::All);
-
-            let refspecs: [&str; 0] = [];
-            remote.fetch(&refspecs, Some(&mut fetch_options), None)
-                .map_err(|e| Error::UpdateFetch {
+            repo_restore_mirror_config(&repo, &remote, name)
+                .map_err(|e| Error::UpdateRestoreMirror {
                     source: e,
-                    remote_name: remote_name.to_owned(),
+                    remote_name: name.to_owned(),
                 })?;
         }
+
+        stats += fetch(path.as_ref(), name, true, fetch_settings)
+            .map_err(|e| Error::UpdateFetch {
+                source: e,
+                remote_name: name.to_owned(),
+            })?;
+    }
+
+    Ok(stats)
+}
+
+/// Add the mirror refspec and `remote.<name>.mirror` flag to `remote` if
+/// they're missing, as in mirrors that were created by hand or edited.
+fn repo_restore_mirror_config(
+    repo: &git2::Repository,
+    remote: &git2::Remote,
+    remote_name: &str,
+) -> Result<(), git2::Error> {
+    let has_mirror_refspec = remote.fetch_refspecs()?
+        .iter()
+        .any(|refspec| refspec == Some(MIRROR_REFSPEC));
+
+    if !has_mirror_refspec {
+        warn!(
+            "{}: adding missing fetch refspec to remote '{}'",
+            repo.path().display(),
+            remote_name,
+        );
+
+        repo.remote_add_fetch(remote_name, MIRROR_REFSPEC)?;
+    }
+
+    let mut config = repo.config()?;
+    let mirror_key = format!("remote.{}.mirror", remote_name);
+
+    if !config.get_bool(&mirror_key).unwrap_or(false) {
+        warn!(
+            "{}: enabling 'mirror' flag on remote '{}'",
+            repo.path().display(),
+            remote_name,
+        );
+
+        config.set_bool(&mirror_key, true)?;
+    }
+
+    Ok(())
+}
+
+/// Set each of `values` in the local config of `repo`.
+///
+/// Values that are already set are left alone, so this can be run on every
+/// update without rewriting the config file.
+fn repo_set_config(
+    repo: &git2::Repository,
+    values: &[(String, String)],
+    action: &str,
+) -> Result<(), Error> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let mut config = repo.config()
+        .and_then(|c| c.open_level(git2::ConfigLevel::Local))?;
+
+    for (key, value) in values {
+        if config.get_string(key).ok().as_ref() == Some(value) {
+            continue;
+        }
+
+        config.set_str(key, value)
+            .map_err(|e| Error::GitSetConfig {
+                source: e,
+                action: action.to_owned(),
+                key: key.clone(),
+            })?;
     }
 
     Ok(())
 }
 
+/// Check the integrity of the repository at `path`.
+///
+/// Reads every object in the object database, which verifies its hash, and
+/// checks that every reference points to an existing object. This reads the
+/// whole repository, so it can be slow.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    repo_verify(path.as_ref())
+        .map_err(|e| Error::VerifyCorrupt {
+            source: e,
+            path: format!("{}", path.as_ref().display()),
+        })
+}
+
+fn repo_verify(path: &Path) -> Result<(), git2::Error> {
+    let repo = git2::Repository::open_bare(path)?;
+    let odb = repo.odb()?;
+
+    let mut oids = Vec::new();
+    odb.foreach(|oid| {
+        oids.push(*oid);
+        true
+    })?;
+
+    for oid in oids {
+        odb.read(oid)?;
+    }
+
+    for reference in repo.references()? {
+        reference?.peel(git2::ObjectType::Any)?;
+    }
+
+    Ok(())
+}
+
+/// Write a bundle of every ref in the bare repository at `path` to
+/// `bundle_path`.
+///
+/// Works like:
+///
+/// ```shell
+/// git bundle create BUNDLE_PATH --all
+/// ```
+///
+/// libgit2 can't create bundles, so this runs the `git` command. The bundle is
+/// written to a temporary file first, so an existing bundle is only replaced
+/// by a complete one. Returns `false` without writing anything if the
+/// repository has no refs, since Git refuses to create an empty bundle.
+pub fn bundle<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    bundle_path: Q,
+) -> Result<bool, Error> {
+    let path = path.as_ref();
+    let bundle_path = bundle_path.as_ref();
+
+    let repo = git2::Repository::open_bare(path)
+        .map_err(|e| Error::BundleOpenRepo {
+            source: e,
+            path: format!("{}", path.display()),
+        })?;
+
+    let is_empty = repo_is_empty(&repo)
+        .map_err(|e| Error::BundleOpenRepo {
+            source: e,
+            path: format!("{}", path.display()),
+        })?;
+
+    if is_empty {
+        return Ok(false);
+    }
+
+    let tmp_path = bundle_path.with_extension("bundle.tmp");
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(["bundle", "create", "--quiet"])
+        .arg(&tmp_path)
+        .arg("--all")
+        .output()
+        .map_err(|e| Error::BundleRunGit {
+            source: e,
+            path: format!("{}", path.display()),
+        })?;
+
+    if !output.status.success() {
+        // Git may leave a partial file behind.
+        let _ = fs::remove_file(&tmp_path);
+
+        return Err(Error::BundleFailed {
+            path: format!("{}", path.display()),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+
+    fs::rename(&tmp_path, bundle_path)
+        .map_err(|e| Error::BundleWrite {
+            source: e,
+            path: format!("{}", bundle_path.display()),
+        })?;
+
+    Ok(true)
+}
+
+/// Check whether the bare repository at `path` has no refs, as after
+/// mirroring a repository that doesn't have any commits yet.
+pub fn is_empty<P: AsRef<Path>>(path: P) -> Result<bool, Error> {
+    let repo = git2::Repository::open_bare(path)?;
+
+    Ok(repo_is_empty(&repo)?)
+}
+
+fn repo_is_empty(repo: &git2::Repository) -> Result<bool, git2::Error> {
+    Ok(repo.references()?.next().is_none())
+}
+
 /// Update the repository's description file.
+///
+/// A non-empty description is written with a trailing newline, as Git does.
+/// An empty description leaves the file empty.
 pub fn update_description<P: AsRef<Path>>(
     repo_path: P,
     description: &str,
@@ -212,6 +625,7 @@ pub fn update_description<P: AsRef<Path>>(
 
     let mut file = fs::OpenOptions::new()
         .write(true)
+        .create(true)
         .truncate(true)
         .open(description_path)?;
 
@@ -224,6 +638,39 @@ pub fn update_description<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Point the remote `remote_name` of the repository at `repo_path` to `url`,
+/// as when the repository moved.
+///
+/// Returns `true` if the URL changed.
+pub fn set_remote_url<P: AsRef<Path>>(
+    repo_path: P,
+    remote_name: &str,
+    url: &str,
+) -> Result<bool, Error> {
+    repo_set_remote_url(repo_path.as_ref(), remote_name, url)
+        .map_err(|e| Error::SetRemoteUrl {
+            source: e,
+            remote_name: remote_name.to_owned(),
+            url: crate::config::redact_url(url),
+        })
+}
+
+fn repo_set_remote_url(
+    repo_path: &Path,
+    remote_name: &str,
+    url: &str,
+) -> Result<bool, git2::Error> {
+    let repo = git2::Repository::open_bare(repo_path)?;
+
+    if repo.find_remote(remote_name)?.url() == Some(url) {
+        return Ok(false);
+    }
+
+    repo.remote_set_url(remote_name, url)?;
+
+    Ok(true)
+}
+
 /// Change the current branch of the repository at `repo_path` to
 /// `default_branch`.
 pub fn change_current_branch<P: AsRef<Path>>(
@@ -233,18 +680,357 @@ pub fn change_current_branch<P: AsRef<Path>>(
     let repo = git2::Repository::open_bare(repo_path)?;
 
     Ok(
-        repo_change_current_branch(&repo, default_branch)?
+        repo_change_to_existing_branch(&repo, default_branch)?
     )
 }
 
+/// Change `repo`'s current branch to `default_branch` if that branch exists.
+///
+/// A new repository's HEAD points at whatever branch `init.defaultBranch`
+/// names, so it's read rather than assumed to be "master". If HEAD is on a
+/// branch that doesn't exist, as in an empty repository, it points at
+/// `default_branch` anyway, so that the branch is current once it's pushed.
+/// Otherwise, if `default_branch` doesn't exist, log a warning and leave HEAD
+/// alone rather than pointing it at a missing branch.
+fn repo_change_to_existing_branch(
+    repo: &git2::Repository,
+    default_branch: &str,
+) -> Result<(), git2::Error> {
+    let branch_ref = format!("refs/heads/{}", default_branch);
+
+    let head = repo.find_reference("HEAD")?;
+    if head.symbolic_target() == Some(branch_ref.as_str()) {
+        return Ok(());
+    }
+
+    match repo.find_reference(&branch_ref) {
+        Ok(_) => repo_change_current_branch(repo, default_branch),
+        Err(e) if e.code() == git2::ErrorCode::NotFound
+            && repo_head_is_unborn(repo)? =>
+            repo_change_current_branch(repo, default_branch),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            warn!(
+                "{}: default branch '{}' doesn't exist, not changing HEAD",
+                repo.path().display(),
+                default_branch,
+            );
+
+            Ok(())
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Check whether `repo`'s HEAD is on a branch that doesn't exist.
+fn repo_head_is_unborn(repo: &git2::Repository) -> Result<bool, git2::Error> {
+    match repo.head() {
+        Ok(_) => Ok(false),
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch
+            || e.code() == git2::ErrorCode::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
 /// Change `repo`'s current branch to `default_branch`.
 fn repo_change_current_branch(
     repo: &git2::Repository,
     default_branch: &str,
 ) -> Result<(), git2::Error> {
+    repo.set_head(
+        &format!("refs/heads/{}", default_branch),
+    )
+}
+
+/// Fetch from `remote_name` in the bare repository at `path`, retrying
+/// transient failures as configured in `settings`.
+///
+/// When `prune` is set, deleted remote refs are removed. All tags are
+/// downloaded unless `settings.no_tags` is set, in which case only branches
+/// are fetched instead of the remote's configured refspecs.
+fn fetch(
+    path: &Path,
+    remote_name: &str,
+    prune: bool,
+    settings: &FetchSettings,
+) -> Result<TransferStats, git2::Error> {
+    if let Some(throttle) = settings.throttle {
+        thread::sleep(jitter(throttle));
+    }
+
+    with_retries(
+        settings.retries,
+        || fetch_with_timeout(path, remote_name, prune, settings),
+    )
+}
+
+/// Fetch from `remote_name` once.
+///
+/// If `settings.timeout` elapses before the fetch finishes, the transfer is
+/// cancelled and an error is returned. Timeout errors aren't retried.
+///
+/// The fetch is only cancelled from its progress callbacks, which don't run
+/// while a connection is stalled. After a timeout, this waits up to
+/// `FETCH_CANCEL_GRACE` for the fetch thread to stop, so that usually nothing
+/// is still writing to the repository when the caller removes a partial
+/// mirror. A fetch that doesn't stop by then is left running on its own
+/// thread rather than blocking the caller.
+fn fetch_with_timeout(
+    path: &Path,
+    remote_name: &str,
+    prune: bool,
+    settings: &FetchSettings,
+) -> Result<TransferStats, git2::Error> {
+    let timeout = match settings.timeout {
+        Some(t) => t,
+        None => return fetch_remote(
+            path,
+            remote_name,
+            prune,
+            settings,
+            &AtomicBool::new(false),
+        ),
+    };
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+
+    let fetch_thread = {
+        let path = path.to_owned();
+        let remote_name = remote_name.to_owned();
+        let settings = settings.clone();
+        let cancelled = Arc::clone(&cancelled);
+
+        // `git2::Remote` isn't `Send`, so the repository is reopened on the
+        // fetch thread.
+        thread::spawn(move || {
+            let _ = tx.send(
+                fetch_remote(
+                    &path,
+                    &remote_name,
+                    prune,
+                    &settings,
+                    &cancelled,
+                ),
+            );
+        })
+    };
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => {
+            let _ = fetch_thread.join();
+
+            result
+        },
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            cancelled.store(true, Ordering::SeqCst);
+
+            // Give the cancelled fetch a chance to stop writing to the
+            // repository.
+            match rx.recv_timeout(FETCH_CANCEL_GRACE) {
+                Err(mpsc::RecvTimeoutError::Timeout) => warn!(
+                    "{}: fetch from '{}' didn't stop after timing out, \
+                    leaving it running",
+                    path.display(),
+                    remote_name,
+                ),
+                _ => {
+                    let _ = fetch_thread.join();
+                },
+            }
+
+            Err(
+                git2::Error::new(
+                    git2::ErrorCode::GenericError,
+                    git2::ErrorClass::None,
+                    format!("fetch timed out after {:?}", timeout),
+                )
+            )
+        },
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            let _ = fetch_thread.join();
+
+            Err(git2::Error::from_str("fetch thread exited unexpectedly"))
+        },
+    }
+}
+
+/// Fetch from `remote_name` with the proxy and headers in `settings`,
+/// aborting the transfer once `cancelled` or `settings.cancel` is set.
+fn fetch_remote(
+    path: &Path,
+    remote_name: &str,
+    prune: bool,
+    settings: &FetchSettings,
+    cancelled: &AtomicBool,
+) -> Result<TransferStats, git2::Error> {
+    let repo = git2::Repository::open_bare(path)?;
+    let mut remote = repo.find_remote(remote_name)?;
+
+    if settings.trace {
+        debug!(
+            target: TRACE_TARGET,
+            "{}: fetching '{}' from {}",
+            path.display(),
+            remote_name,
+            remote.url().map(crate::config::redact_url).unwrap_or_default(),
+        );
+    }
+
+    let is_cancelled = || {
+        cancelled.load(Ordering::SeqCst)
+            || settings.cancel.load(Ordering::SeqCst)
+    };
+
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.transfer_progress(|_| !is_cancelled());
+    callbacks.sideband_progress(|data| {
+        if settings.trace {
+            let message = String::from_utf8_lossy(data);
+
+            // Progress messages are overwritten with carriage returns.
+            for line in message.split(&['\r', '\n'][..]) {
+                if !line.trim().is_empty() {
+                    debug!(
+                        target: TRACE_TARGET,
+                        "{}: remote: {}",
+                        path.display(),
+                        line,
+                    );
+                }
+            }
+        }
+
+        !is_cancelled()
+    });
+
+    if settings.trace {
+        callbacks.update_tips(|refname, old, new| {
+            debug!(
+                target: TRACE_TARGET,
+                "{}: {} {}..{}",
+                path.display(),
+                refname,
+                old,
+                new,
+            );
+
+            true
+        });
+    }
+
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+
+    if let Some(proxy) = &settings.proxy {
+        let mut proxy_options = git2::ProxyOptions::new();
+        proxy_options.url(proxy);
+
+        fetch_options.proxy_options(proxy_options);
+    }
+
+    if !settings.headers.is_empty() {
+        let headers: Vec<&str> = settings.headers
+            .iter()
+            .map(String::as_str)
+            .collect();
+
+        fetch_options.custom_headers(&headers);
+    }
+
+    if prune {
+        fetch_options.prune(git2::FetchPrune::On);
+    }
+
+    let refspecs: &[&str] = if settings.no_tags {
+        fetch_options.download_tags(git2::AutotagOption::None);
+
+        &[BRANCHES_REFSPEC]
+    } else {
+        fetch_options.download_tags(git2::AutotagOption::All);
+
+        &[]
+    };
+
+    remote.fetch(refspecs, Some(&mut fetch_options), None)?;
+
+    let progress = remote.stats();
+
+    if settings.trace {
+        debug!(
+            target: TRACE_TARGET,
+            "{}: received {} of {} objects ({} bytes), \
+                {} local objects used",
+            path.display(),
+            progress.received_objects(),
+            progress.total_objects(),
+            progress.received_bytes(),
+            progress.local_objects(),
+        );
+    }
+
     Ok(
-        repo.set_head(
-            &format!("refs/heads/{}", default_branch),
-        )?
+        TransferStats {
+            received_bytes: progress.received_bytes() as u64,
+            received_objects: progress.received_objects() as u64,
+        }
+    )
+}
+
+/// Get a random duration between half of `max` and `max`.
+fn jitter(max: Duration) -> Duration {
+    // `RandomState` is seeded randomly, so hashing nothing gives a random
+    // number without needing an RNG.
+    let random = RandomState::new().build_hasher().finish();
+
+    max / 2 + max.mul_f64((random as f64 / u64::MAX as f64) / 2.0)
+}
+
+/// Call `f`, retrying up to `retries` times with exponential backoff if it
+/// fails with a transient error.
+fn with_retries<T, F>(retries: u32, mut f: F) -> Result<T, git2::Error>
+where
+    F: FnMut() -> Result<T, git2::Error>,
+{
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+
+    loop {
+        match f() {
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                attempt += 1;
+
+                warn!(
+                    "{}; retrying in {:?} (attempt {} of {})",
+                    e.message(),
+                    delay,
+                    attempt,
+                    retries,
+                );
+
+                thread::sleep(delay);
+                delay *= 2;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Return `true` if `error` is a network error that might succeed if
+/// retried.
+///
+/// Authentication and not-found errors are never retried.
+fn is_retryable(error: &git2::Error) -> bool {
+    match error.code() {
+        git2::ErrorCode::Auth
+        | git2::ErrorCode::Certificate
+        | git2::ErrorCode::NotFound => return false,
+        _ => (),
+    }
+
+    matches!(
+        error.class(),
+        git2::ErrorClass::Net
+        | git2::ErrorClass::Http
+        | git2::ErrorClass::Ssl
     )
 }
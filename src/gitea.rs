@@ -0,0 +1,179 @@
+// Copyright (c) 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use thiserror;
+
+use crate::provider::{self, Provider};
+
+use std::ops::ControlFlow;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Gitea request error")]
+    Http(#[from] Box<ureq::Error>),
+
+    #[error("Gitea I/O error")]
+    Io(#[from] std::io::Error),
+}
+
+
+/// A Gitea or Forgejo repository, as returned by the repositories API.
+#[derive(Debug, Deserialize)]
+pub struct Repo {
+    pub id: i64,
+    pub name: String,
+    pub owner: Owner,
+    pub description: String,
+    pub fork: bool,
+
+    #[serde(default)]
+    pub language: Option<String>,
+    pub clone_url: String,
+    pub default_branch: String,
+
+    /// The repository's homepage URL.
+    #[serde(default)]
+    pub website: String,
+    pub size: u64,
+
+    /// `null` when the repository has no topics.
+    #[serde(default)]
+    pub topics: Option<Vec<String>>,
+    pub created_at: DateTime<FixedOffset>,
+    pub updated_at: DateTime<FixedOffset>,
+
+    #[serde(default)]
+    pub private: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Owner {
+    pub login: String,
+}
+
+impl From<Repo> for provider::Repo {
+    fn from(repo: Repo) -> Self {
+        provider::Repo {
+            id: repo.id,
+            name: repo.name,
+            owner: repo.owner.login,
+            description: Some(repo.description)
+                .filter(|d| !d.is_empty()),
+            fork: repo.fork,
+            language: repo.language
+                .filter(|l| !l.is_empty()),
+            clone_url: repo.clone_url,
+            default_branch: repo.default_branch,
+            homepage: Some(repo.website)
+                .filter(|w| !w.is_empty()),
+            size: repo.size,
+            topics: repo.topics.unwrap_or_default(),
+            created_at: Some(repo.created_at),
+            updated_at: repo.updated_at,
+
+            // Gitea doesn't report a separate push time. `updated_at` changes
+            // on push.
+            pushed_at: repo.updated_at,
+            has_wiki: false,
+            private: repo.private,
+        }
+    }
+}
+
+
+/// Fetches repositories from a Gitea or Forgejo instance.
+#[derive(Debug)]
+pub struct Gitea {
+    /// The root URL of the instance, for example "https://codeberg.org".
+    pub base_url: String,
+    pub agent: ureq::Agent,
+}
+
+impl Provider for Gitea {
+    fn name(&self) -> &'static str {
+        "Gitea"
+    }
+
+    fn fetch_repos_each(
+        &self,
+        username: &str,
+        f: &mut dyn FnMut(Vec<provider::Repo>) -> ControlFlow<()>,
+    ) -> Result<(), provider::Error> {
+        Ok(fetch_repos_each_from(&self.agent, &self.base_url, username, f)?)
+    }
+}
+
+
+/// Fetch all repositories for the given user from a Gitea or Forgejo
+/// instance.
+///
+/// `base_url` is the root URL of the instance, for example
+/// "https://codeberg.org".
+pub fn fetch_repos(
+    base_url: &str,
+    gitea_username: &str,
+) -> Result<Vec<provider::Repo>, Error> {
+    let agent = provider::default_agent();
+
+    let mut repos = Vec::new();
+
+    fetch_repos_each_from(&agent, base_url, gitea_username, &mut |page| {
+        repos.extend(page);
+
+        ControlFlow::Continue(())
+    })?;
+
+    Ok(repos)
+}
+
+/// Fetch the given user's repos using `agent`, calling `f` with each page.
+fn fetch_repos_each_from(
+    agent: &ureq::Agent,
+    base_url: &str,
+    gitea_username: &str,
+    f: &mut dyn FnMut(Vec<provider::Repo>) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    for i in 1.. {
+        let repo_page: Vec<Repo> = agent.get(
+            &format!(
+                "{}/api/v1/users/{}/repos?page={}&limit=50",
+                base_url.trim_end_matches('/'),
+                gitea_username,
+                i,
+            ),
+        )
+            .call()
+            .map_err(Box::new)?
+            .into_json()?;
+
+        if repo_page.is_empty() {
+            break;
+        }
+
+        let page = repo_page.into_iter().map(provider::Repo::from).collect();
+
+        if f(page).is_break() {
+            break;
+        }
+    }
+
+    Ok(())
+}
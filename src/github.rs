@@ -16,76 +16,940 @@
 // along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
 
 
-use serde::Deserialize;
+use chrono::{DateTime, FixedOffset};
+use log::warn;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Deserializer};
 use thiserror;
 
+use crate::provider::{self, Provider};
 
-const USER_AGENT: &'static str = concat!(
-    env!("CARGO_PKG_NAME"),
-    "/",
-    env!("CARGO_PKG_VERSION"),
-);
+use std::fmt;
+use std::ops::ControlFlow;
+use std::str;
 
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("GitHub request error")]
-    Http(#[from] ureq::Error),
+    Http(#[from] Box<ureq::Error>),
 
     #[error("GitHub I/O error")]
     Io(#[from] std::io::Error),
+
+    #[error("GitHub thread pool error")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+
+    #[error("GitHub user '{0}' not found")]
+    UserNotFound(String),
+
+    #[error("GitHub repository '{0}' not found")]
+    RepoNotFound(String),
+
+    #[error(
+        "GitHub refused the request (HTTP {status}): {message}; check the \
+        credentials and permissions for '{url}'"
+    )]
+    Unauthorized {
+        status: u16,
+        message: String,
+        url: String,
+    },
+
+    #[error(
+        "GitHub rate limit exceeded (HTTP {status}): {message}; try again \
+        after the limit resets"
+    )]
+    RateLimited {
+        status: u16,
+        message: String,
+    },
+
+    #[error(
+        "GitHub server error (HTTP {status}): {message}; try again later"
+    )]
+    Server {
+        status: u16,
+        message: String,
+    },
+
+    #[error("unexpected GitHub response (HTTP {status}): {message}")]
+    Status {
+        status: u16,
+        message: String,
+    },
+
+    #[error("GitHub GraphQL error: {0}")]
+    Graphql(String),
+
+    #[error(
+        "invalid sort '{0}', expected 'created', 'updated', 'pushed', or \
+        'full_name'"
+    )]
+    InvalidSort(String),
+
+    #[error("invalid direction '{0}', expected 'asc' or 'desc'")]
+    InvalidDirection(String),
+}
+
+
+/// Root URL of the public GitHub API.
+pub const API_BASE_URL: &str = "https://api.github.com";
+
+/// Number of releases to request per page, the most the API allows.
+const RELEASES_PER_PAGE: usize = 100;
+
+/// Maximum number of repository pages to request at the same time.
+const MAX_CONCURRENT_PAGE_REQUESTS: usize = 4;
+
+/// GraphQL query for a page of an owner's public repositories, with the
+/// fields of the REST API's repositories that Reflectub uses.
+const GRAPHQL_REPOS_QUERY: &str = "
+query($login: String!, $cursor: String, $orderBy: RepositoryOrder) {
+  repositoryOwner(login: $login) {
+    repositories(
+      first: 100
+      after: $cursor
+      ownerAffiliations: OWNER
+      privacy: PUBLIC
+      orderBy: $orderBy
+    ) {
+      pageInfo { hasNextPage endCursor }
+      nodes {
+        databaseId
+        name
+        owner { login }
+        description
+        isFork
+        isPrivate
+        url
+        homepageUrl
+        diskUsage
+        createdAt
+        updatedAt
+        pushedAt
+        hasWikiEnabled
+        primaryLanguage { name }
+        defaultBranchRef { name }
+        repositoryTopics(first: 100) { nodes { topic { name } } }
+      }
+    }
+  }
+}
+";
+
+
+/// The field to list repositories by.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Sort {
+    Created,
+    #[default]
+    Updated,
+    Pushed,
+    FullName,
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sort::Created => write!(f, "created"),
+            Sort::Updated => write!(f, "updated"),
+            Sort::Pushed => write!(f, "pushed"),
+            Sort::FullName => write!(f, "full_name"),
+        }
+    }
+}
+
+impl str::FromStr for Sort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(Sort::Created),
+            "updated" => Ok(Sort::Updated),
+            "pushed" => Ok(Sort::Pushed),
+            "full_name" => Ok(Sort::FullName),
+            _ => Err(Error::InvalidSort(s.to_owned())),
+        }
+    }
+}
+
+/// The direction to list repositories in. GitHub defaults to ascending for
+/// `Sort::FullName` and descending otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Asc => write!(f, "asc"),
+            Direction::Desc => write!(f, "desc"),
+        }
+    }
+}
+
+impl str::FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(Direction::Asc),
+            "desc" => Ok(Direction::Desc),
+            _ => Err(Error::InvalidDirection(s.to_owned())),
+        }
+    }
+}
+
+/// The order to list repositories in.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Order {
+    pub sort: Sort,
+
+    /// `None` uses GitHub's default for `sort`.
+    pub direction: Option<Direction>,
 }
 
+impl Order {
+    /// Get the GraphQL `RepositoryOrder` field and direction. The GraphQL
+    /// API has no default direction, so the REST API's is used.
+    fn graphql(&self) -> (&'static str, &'static str) {
+        let field = match self.sort {
+            Sort::Created => "CREATED_AT",
+            Sort::Updated => "UPDATED_AT",
+            Sort::Pushed => "PUSHED_AT",
+            Sort::FullName => "NAME",
+        };
 
+        let direction = match (self.direction, self.sort) {
+            (Some(Direction::Asc), _) | (None, Sort::FullName) => "ASC",
+            _ => "DESC",
+        };
+
+        (field, direction)
+    }
+}
+
+
+/// A GitHub repository, as returned by the repositories API.
 #[derive(Debug, Deserialize)]
 pub struct Repo {
     pub id: i64,
     pub name: String,
+    pub owner: Owner,
     pub description: Option<String>,
     pub fork: bool,
+    pub language: Option<String>,
     pub clone_url: String,
     pub default_branch: String,
+    pub homepage: Option<String>,
     pub size: u64,
-    pub updated_at: String,
-    pub pushed_at: String,
+
+    #[serde(default)]
+    pub topics: Vec<String>,
+    pub created_at: DateTime<FixedOffset>,
+    pub updated_at: DateTime<FixedOffset>,
+
+    /// `None` for a repository that was never pushed to, or whose time
+    /// couldn't be parsed.
+    #[serde(default, deserialize_with = "deserialize_lenient_timestamp")]
+    pub pushed_at: Option<DateTime<FixedOffset>>,
+
+    #[serde(default)]
+    pub has_wiki: bool,
+
+    #[serde(default)]
+    pub private: bool,
 }
 
-impl Repo {
-    /// Get the repository description or an empty string if `None`.
-    pub fn description(&self) -> &str {
-        self.description
-            .as_deref()
-            .unwrap_or("")
+#[derive(Debug, Deserialize)]
+pub struct Owner {
+    pub login: String,
+}
+
+/// A GitHub repository, as returned by the GraphQL API.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlRepo {
+    database_id: i64,
+    name: String,
+    owner: Owner,
+    description: Option<String>,
+    is_fork: bool,
+    is_private: bool,
+    url: String,
+    homepage_url: Option<String>,
+    disk_usage: Option<u64>,
+    created_at: DateTime<FixedOffset>,
+    updated_at: DateTime<FixedOffset>,
+
+    /// `None` for a repository that was never pushed to.
+    pushed_at: Option<DateTime<FixedOffset>>,
+    has_wiki_enabled: bool,
+    primary_language: Option<Named>,
+
+    /// `None` for a repository without any commits.
+    default_branch_ref: Option<Named>,
+    repository_topics: Nodes<TopicNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Named {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopicNode {
+    topic: Named,
+}
+
+#[derive(Debug, Deserialize)]
+struct Nodes<T> {
+    nodes: Vec<T>,
+}
+
+impl From<GraphqlRepo> for Repo {
+    fn from(repo: GraphqlRepo) -> Self {
+        Repo {
+            id: repo.database_id,
+            name: repo.name,
+            owner: repo.owner,
+            description: repo.description,
+            fork: repo.is_fork,
+            language: repo.primary_language.map(|language| language.name),
+            clone_url: format!("{}.git", repo.url),
+            default_branch: repo.default_branch_ref
+                .map_or_else(|| "master".to_owned(), |branch| branch.name),
+            homepage: repo.homepage_url,
+            size: repo.disk_usage.unwrap_or(0),
+            topics: repo.repository_topics.nodes
+                .into_iter()
+                .map(|node| node.topic.name)
+                .collect(),
+            created_at: repo.created_at,
+            updated_at: repo.updated_at,
+
+            // Left to `From<Repo> for provider::Repo` to fill in, as for
+            // the REST API.
+            pushed_at: repo.pushed_at,
+            has_wiki: repo.has_wiki_enabled,
+            private: repo.is_private,
+        }
     }
 }
 
+/// The body of a GraphQL API response.
+#[derive(Debug, Deserialize)]
+struct GraphqlResponse {
+    data: Option<GraphqlData>,
 
-/// Fetch all GitHub repositories for the given user.
-pub fn fetch_repos(github_username: &str) -> Result<Vec<Repo>, Error> {
-    let agent = ureq::AgentBuilder::new()
-        .user_agent(USER_AGENT)
-        .build();
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
 
-    let mut repos = Vec::new();
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlData {
+    repository_owner: Option<GraphqlOwner>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlOwner {
+    repositories: GraphqlConnection,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlConnection {
+    page_info: PageInfo,
+    nodes: Vec<GraphqlRepo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlError {
+    message: String,
+
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+impl From<Repo> for provider::Repo {
+    fn from(repo: Repo) -> Self {
+        provider::Repo {
+            id: repo.id,
+            name: repo.name,
+            owner: repo.owner.login,
+            description: repo.description
+                .filter(|d| !d.is_empty()),
+            fork: repo.fork,
+            language: repo.language,
+            clone_url: repo.clone_url,
+            default_branch: repo.default_branch,
+            homepage: repo.homepage
+                .filter(|h| !h.is_empty()),
+            size: repo.size,
+            topics: repo.topics,
+            created_at: Some(repo.created_at),
+            updated_at: repo.updated_at,
+
+            // `updated_at` already includes pushes.
+            pushed_at: repo.pushed_at.unwrap_or(repo.updated_at),
+            has_wiki: repo.has_wiki,
+            private: repo.private,
+        }
+    }
+}
+
+/// Deserialize an optional RFC 3339 timestamp, treating a malformed one as
+/// missing, so that one bad repository doesn't fail a whole page.
+fn deserialize_lenient_timestamp<'de, D>(
+    deserializer: D,
+) -> Result<Option<DateTime<FixedOffset>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp: Option<String> = Option::deserialize(deserializer)?;
 
-    for i in 1.. {
-        let repo_page: Vec<Repo> = agent.get(
-            &format!(
-                "https://api.github.com/users/{}/repos?page={}&per_page=100&sort=updated",
-                github_username,
-                i,
-            ),
+    Ok(
+        timestamp.and_then(|timestamp|
+            match DateTime::parse_from_rfc3339(&timestamp) {
+                Ok(time) => Some(time),
+                Err(e) => {
+                    warn!("ignoring malformed time '{}': {}", timestamp, e);
+
+                    None
+                },
+            }
         )
+    )
+}
+
+/// A GitHub release, as returned by the releases API.
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub name: Option<String>,
+    pub published_at: Option<DateTime<FixedOffset>>,
+    pub assets: Vec<Asset>,
+}
+
+/// A file attached to a GitHub release.
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub size: u64,
+    pub browser_download_url: String,
+}
+
+impl From<Release> for provider::Release {
+    fn from(release: Release) -> Self {
+        provider::Release {
+            tag_name: release.tag_name,
+            name: release.name
+                .filter(|n| !n.is_empty()),
+            published_at: release.published_at,
+            assets: release.assets
+                .into_iter()
+                .map(|asset| provider::Asset {
+                    name: asset.name,
+                    size: asset.size,
+                    url: asset.browser_download_url,
+                })
+                .collect(),
+        }
+    }
+}
+
+
+/// Fetches repositories from GitHub.
+#[derive(Debug)]
+pub struct Github {
+    /// The root URL of the API, `API_BASE_URL` for github.com, or for
+    /// example "https://github.example.com/api/v3" for GitHub Enterprise.
+    pub base_url: String,
+    pub agent: ureq::Agent,
+    pub order: Order,
+
+    /// A token to list repositories with the GraphQL API, which takes fewer
+    /// requests. Without one, the REST API is used.
+    pub token: Option<String>,
+}
+
+impl Provider for Github {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn fetch_repos_each(
+        &self,
+        username: &str,
+        f: &mut dyn FnMut(Vec<provider::Repo>) -> ControlFlow<()>,
+    ) -> Result<(), provider::Error> {
+        let f = &mut |page: Vec<Repo>| f(
+            page
+                .into_iter()
+                .map(provider::Repo::from)
+                .collect()
+        );
+
+        match &self.token {
+            Some(token) => fetch_repos_graphql_each_from(
+                &self.agent,
+                &self.base_url,
+                token,
+                username,
+                self.order,
+                f,
+            )?,
+            None => fetch_repos_each_from(
+                &self.agent,
+                &self.base_url,
+                username,
+                self.order,
+                f,
+            )?,
+        }
+
+        Ok(())
+    }
+
+    fn supports_releases(&self) -> bool {
+        true
+    }
+
+    fn fetch_releases(
+        &self,
+        owner: &str,
+        name: &str,
+    ) -> Result<Vec<provider::Release>, provider::Error> {
+        Ok(
+            fetch_releases_from(&self.agent, &self.base_url, owner, name)?
+                .into_iter()
+                .map(provider::Release::from)
+                .collect()
+        )
+    }
+}
+
+
+/// Fetch all GitHub repositories for the given user, listed in `order`.
+///
+/// The first page's `Link` header gives the number of pages, so the rest are
+/// fetched in parallel batches. Without a `Link` header, pages are fetched one
+/// after another until an empty page is returned.
+///
+/// Returns `Error::UserNotFound` if the user doesn't exist. A user without any
+/// public repositories gets an empty list.
+pub fn fetch_repos(
+    github_username: &str,
+    order: Order,
+) -> Result<Vec<Repo>, Error> {
+    fetch_repos_with_agent(
+        &provider::default_agent(),
+        github_username,
+        order,
+    )
+}
+
+/// Like `fetch_repos`, but make requests with `agent`, as one built by
+/// `provider::new_agent`, instead of an agent with default settings.
+///
+/// Reusing an agent keeps its connection pool between calls.
+pub fn fetch_repos_with_agent(
+    agent: &ureq::Agent,
+    github_username: &str,
+    order: Order,
+) -> Result<Vec<Repo>, Error> {
+    let mut repos = Vec::new();
+
+    fetch_repos_each_from(
+        agent,
+        API_BASE_URL,
+        github_username,
+        order,
+        &mut |page| {
+            repos.extend(page);
+
+            ControlFlow::Continue(())
+        },
+    )?;
+
+    Ok(repos)
+}
+
+/// Fetch all GitHub repositories for the given user with the GraphQL API,
+/// listed in `order`.
+///
+/// Requires a `token`. Each request gets 100 repositories with all the
+/// metadata Reflectub uses. Only public repositories are listed, as with
+/// `fetch_repos`.
+pub fn fetch_repos_graphql(
+    token: &str,
+    github_username: &str,
+    order: Order,
+) -> Result<Vec<Repo>, Error> {
+    let mut repos = Vec::new();
+
+    fetch_repos_graphql_each_from(
+        &provider::default_agent(),
+        API_BASE_URL,
+        token,
+        github_username,
+        order,
+        &mut |page| {
+            repos.extend(page);
+
+            ControlFlow::Continue(())
+        },
+    )?;
+
+    Ok(repos)
+}
+
+/// Fetch the given user's GitHub repositories from the GraphQL API of the
+/// REST API at `base_url` using `agent`, calling `f` with each page in
+/// order.
+fn fetch_repos_graphql_each_from(
+    agent: &ureq::Agent,
+    base_url: &str,
+    token: &str,
+    github_username: &str,
+    order: Order,
+    f: &mut dyn FnMut(Vec<Repo>) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    let url = graphql_url(base_url);
+    let (field, direction) = order.graphql();
+
+    let mut cursor = None;
+
+    loop {
+        let response = check_status(
+            agent.post(&url)
+                .set("Authorization", &format!("bearer {}", token))
+                .send_json(ureq::json!({
+                    "query": GRAPHQL_REPOS_QUERY,
+                    "variables": {
+                        "login": github_username,
+                        "cursor": cursor,
+                        "orderBy": {
+                            "field": field,
+                            "direction": direction,
+                        },
+                    },
+                })),
+            github_username,
+        )?;
+
+        let response: GraphqlResponse = response.into_json()?;
+
+        let not_found = response.errors
+            .iter()
+            .any(|e| e.kind.as_deref() == Some("NOT_FOUND"));
+
+        if not_found {
+            return Err(Error::UserNotFound(github_username.to_owned()));
+        }
+
+        if !response.errors.is_empty() {
+            return Err(Error::Graphql(
+                response.errors
+                    .into_iter()
+                    .map(|e| e.message)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ));
+        }
+
+        let repositories = response.data
+            .and_then(|data| data.repository_owner)
+            .ok_or_else(|| Error::UserNotFound(github_username.to_owned()))?
+            .repositories;
+
+        if repositories.nodes.is_empty() {
+            return Ok(());
+        }
+
+        let page = repositories.nodes
+            .into_iter()
+            .map(Repo::from)
+            .collect();
+
+        if f(page).is_break() || !repositories.page_info.has_next_page {
+            return Ok(());
+        }
+
+        cursor = repositories.page_info.end_cursor;
+    }
+}
+
+/// Get the GraphQL API URL for the REST API at `base_url`.
+///
+/// The public API has both at its root, while GitHub Enterprise serves them
+/// at "/api/graphql" and "/api/v3".
+fn graphql_url(base_url: &str) -> String {
+    let base_url = base_url.trim_end_matches('/');
+
+    format!(
+        "{}/graphql",
+        base_url.strip_suffix("/v3").unwrap_or(base_url),
+    )
+}
+
+/// Fetch the given user's GitHub repositories from the API at `base_url`
+/// using `agent`, calling `f` with each page in order.
+fn fetch_repos_each_from(
+    agent: &ureq::Agent,
+    base_url: &str,
+    github_username: &str,
+    order: Order,
+    f: &mut dyn FnMut(Vec<Repo>) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    let response = request_page(
+        agent,
+        base_url,
+        github_username,
+        order,
+        1,
+    )?;
+    let last_page = response.header("Link")
+        .and_then(parse_last_page);
+    let first_page: Vec<Repo> = response.into_json()?;
+
+    if first_page.is_empty() {
+        return Ok(());
+    }
+
+    if f(first_page).is_break() {
+        return Ok(());
+    }
+
+    match last_page {
+        Some(last_page) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(MAX_CONCURRENT_PAGE_REQUESTS)
+                .build()?;
+
+            let page_numbers: Vec<u32> = (2..=last_page).collect();
+
+            // Hand over each batch of pages before fetching the next, so
+            // they can be processed while the rest download.
+            for batch in page_numbers.chunks(MAX_CONCURRENT_PAGE_REQUESTS) {
+                let pages = pool.install(|| {
+                    batch
+                        .into_par_iter()
+                        .map(|&i|
+                            fetch_page(
+                                agent,
+                                base_url,
+                                github_username,
+                                order,
+                                i,
+                            )
+                        )
+                        .collect::<Result<Vec<_>, _>>()
+                })?;
+
+                for page in pages {
+                    if f(page).is_break() {
+                        return Ok(());
+                    }
+                }
+            }
+        },
+        None => {
+            for i in 2.. {
+                let repo_page = fetch_page(
+                    agent,
+                    base_url,
+                    github_username,
+                    order,
+                    i,
+                )?;
+
+                if repo_page.is_empty() {
+                    break;
+                }
+
+                if f(repo_page).is_break() {
+                    break;
+                }
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Fetch all releases of `owner`'s repository `name`, newest first.
+///
+/// Pages are fetched one after another until one isn't full. Returns
+/// `Error::RepoNotFound` if the repository doesn't exist.
+fn fetch_releases_from(
+    agent: &ureq::Agent,
+    base_url: &str,
+    owner: &str,
+    name: &str,
+) -> Result<Vec<Release>, Error> {
+    let mut releases = Vec::new();
+
+    for page in 1.. {
+        let url = format!(
+            "{}/repos/{}/{}/releases?page={}&per_page={}",
+            base_url.trim_end_matches('/'),
+            owner,
+            name,
+            page,
+            RELEASES_PER_PAGE,
+        );
+
+        let result = agent.get(&url)
             .set("Accept", "application/vnd.github.v3+json")
-            .call()?
-            .into_json()?;
+            .call();
+
+        let response = match result {
+            Err(ureq::Error::Status(404, _)) =>
+                return Err(
+                    Error::RepoNotFound(format!("{}/{}", owner, name))
+                ),
+            result => check_status(result, owner)?,
+        };
 
-        if repo_page.is_empty() {
+        let page: Vec<Release> = response.into_json()?;
+        let full = page.len() == RELEASES_PER_PAGE;
+
+        releases.extend(page);
+
+        if !full {
             break;
         }
+    }
+
+    Ok(releases)
+}
+
+/// Fetch page `page` of the user's repositories.
+fn fetch_page(
+    agent: &ureq::Agent,
+    base_url: &str,
+    github_username: &str,
+    order: Order,
+    page: u32,
+) -> Result<Vec<Repo>, Error> {
+    Ok(
+        request_page(agent, base_url, github_username, order, page)?
+            .into_json()?
+    )
+}
 
-        repos.extend(repo_page);
+/// Request page `page` of the user's repositories.
+fn request_page(
+    agent: &ureq::Agent,
+    base_url: &str,
+    github_username: &str,
+    order: Order,
+    page: u32,
+) -> Result<ureq::Response, Error> {
+    let mut url = format!(
+        "{}/users/{}/repos?page={}&per_page=100&sort={}",
+        base_url.trim_end_matches('/'),
+        github_username,
+        page,
+        order.sort,
+    );
+
+    if let Some(direction) = order.direction {
+        url.push_str(&format!("&direction={}", direction));
     }
 
-    Ok(repos)
+    check_status(
+        agent.get(&url)
+            .set("Accept", "application/vnd.github.v3+json")
+            .call(),
+        github_username,
+    )
+}
+
+/// Turn a non-2xx response to a request for `github_username`'s
+/// repositories into the matching error.
+fn check_status(
+    result: Result<ureq::Response, ureq::Error>,
+    github_username: &str,
+) -> Result<ureq::Response, Error> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(_, response)) =>
+            Err(status_error(github_username, response)),
+        Err(e) => Err(Error::Http(Box::new(e))),
+    }
+}
+
+/// The body of a GitHub API error response.
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+/// Get the error for a non-2xx `response` to a request for
+/// `github_username`'s repositories.
+///
+/// The message comes from GitHub's JSON error body, or the status text if
+/// there isn't one. A 403 is only taken as a rate limit if GitHub says it is,
+/// since it's also used for requests that aren't allowed.
+fn status_error(github_username: &str, response: ureq::Response) -> Error {
+    let status = response.status();
+    let url = response.get_url().to_owned();
+    let rate_limit_remaining = response.header("X-RateLimit-Remaining")
+        .map(str::to_owned);
+    let status_text = response.status_text().to_owned();
+
+    let message = response.into_json::<ErrorBody>()
+        .map(|body| body.message)
+        .unwrap_or(status_text);
+
+    match status {
+        404 => Error::UserNotFound(github_username.to_owned()),
+        403 if rate_limit_remaining.as_deref() == Some("0")
+            || message.contains("rate limit") =>
+            Error::RateLimited { status, message },
+        429 => Error::RateLimited { status, message },
+        401 | 403 => Error::Unauthorized { status, message, url },
+        500..=599 => Error::Server { status, message },
+        _ => Error::Status { status, message },
+    }
+}
+
+/// Get the page number of the `rel="last"` link in a `Link` header.
+///
+/// The header looks like:
+///
+/// ```text
+/// <https://api.github.com/user/1/repos?page=2>; rel="next", <https://api.github.com/user/1/repos?page=5>; rel="last"
+/// ```
+fn parse_last_page(link: &str) -> Option<u32> {
+    let last = link
+        .split(',')
+        .find(|part| part.contains(r#"rel="last""#))?;
+
+    let url = last
+        .trim()
+        .strip_prefix('<')?
+        .split('>')
+        .next()?;
+
+    url
+        .split('?')
+        .nth(1)?
+        .split('&')
+        .find_map(|param| param.strip_prefix("page="))?
+        .parse()
+        .ok()
 }
@@ -0,0 +1,172 @@
+// Copyright (c) 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+use thiserror;
+
+use crate::provider::{self, Provider};
+
+use std::ops::ControlFlow;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("GitLab request error")]
+    Http(#[from] Box<ureq::Error>),
+
+    #[error("GitLab I/O error")]
+    Io(#[from] std::io::Error),
+}
+
+
+/// A GitLab project, as returned by the projects API.
+#[derive(Debug, Deserialize)]
+pub struct Project {
+    pub id: i64,
+    pub name: String,
+    pub path: String,
+    pub namespace: Namespace,
+    pub description: Option<String>,
+    pub forked_from_project: Option<IgnoredAny>,
+    pub http_url_to_repo: String,
+    pub default_branch: Option<String>,
+
+    #[serde(default)]
+    pub topics: Vec<String>,
+    pub created_at: DateTime<FixedOffset>,
+    pub last_activity_at: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Namespace {
+    pub path: String,
+}
+
+impl From<Project> for provider::Repo {
+    fn from(project: Project) -> Self {
+        provider::Repo {
+            id: project.id,
+
+            // Use the path rather than the display name, as it's safe to use
+            // as a directory name.
+            name: project.path,
+            owner: project.namespace.path,
+            description: project.description
+                .filter(|d| !d.is_empty()),
+            fork: project.forked_from_project.is_some(),
+            language: None,
+            clone_url: project.http_url_to_repo,
+
+            // Empty projects don't have a default branch.
+            default_branch: project.default_branch
+                .unwrap_or_else(|| "master".to_owned()),
+            homepage: None,
+
+            // The projects API doesn't include the repository size without
+            // authentication.
+            size: 0,
+            topics: project.topics,
+            created_at: Some(project.created_at),
+            updated_at: project.last_activity_at,
+            pushed_at: project.last_activity_at,
+            has_wiki: false,
+            private: false,
+        }
+    }
+}
+
+
+/// Fetches projects from a GitLab instance.
+#[derive(Debug)]
+pub struct Gitlab {
+    /// The root URL of the GitLab instance, for example "https://gitlab.com".
+    pub base_url: String,
+    pub agent: ureq::Agent,
+}
+
+impl Provider for Gitlab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn fetch_repos_each(
+        &self,
+        username: &str,
+        f: &mut dyn FnMut(Vec<provider::Repo>) -> ControlFlow<()>,
+    ) -> Result<(), provider::Error> {
+        Ok(fetch_repos_each_from(&self.agent, &self.base_url, username, f)?)
+    }
+}
+
+
+/// Fetch all GitLab projects for the given user.
+///
+/// `base_url` is the root URL of the GitLab instance, for example
+/// "https://gitlab.com".
+pub fn fetch_repos(
+    base_url: &str,
+    gitlab_username: &str,
+) -> Result<Vec<provider::Repo>, Error> {
+    let agent = provider::default_agent();
+
+    let mut repos = Vec::new();
+
+    fetch_repos_each_from(&agent, base_url, gitlab_username, &mut |page| {
+        repos.extend(page);
+
+        ControlFlow::Continue(())
+    })?;
+
+    Ok(repos)
+}
+
+/// Fetch the given user's projects using `agent`, calling `f` with each page.
+fn fetch_repos_each_from(
+    agent: &ureq::Agent,
+    base_url: &str,
+    gitlab_username: &str,
+    f: &mut dyn FnMut(Vec<provider::Repo>) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    for i in 1.. {
+        let project_page: Vec<Project> = agent.get(
+            &format!(
+                "{}/api/v4/users/{}/projects?page={}&per_page=100&order_by=last_activity_at",
+                base_url.trim_end_matches('/'),
+                gitlab_username,
+                i,
+            ),
+        )
+            .call()
+            .map_err(Box::new)?
+            .into_json()?;
+
+        if project_page.is_empty() {
+            break;
+        }
+
+        let page = project_page.into_iter().map(provider::Repo::from).collect();
+
+        if f(page).is_break() {
+            break;
+        }
+    }
+
+    Ok(())
+}
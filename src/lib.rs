@@ -16,6 +16,28 @@
 // along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
 
 
+pub mod cgitrc;
+pub mod config;
 pub mod database;
 pub mod git;
+pub mod gitea;
 pub mod github;
+pub mod gitlab;
+mod mirror;
+pub mod multi_error;
+pub mod provider;
+
+pub use mirror::{
+    cgitrc_diff,
+    check,
+    disk_sizes,
+    fix,
+    mirror_user,
+    mirror_user_with_shutdown,
+    regenerate_cgitrc,
+    Inconsistency,
+    Outcome,
+    Shutdown,
+    Summary,
+    Warning,
+};
@@ -17,35 +17,49 @@
 
 
 use anyhow::{self, Context};
-use chrono::DateTime;
-use exitcode;
-use filetime;
 use getopts::Options;
-use parse_size::parse_size;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use rusqlite;
+use log::{info, warn};
 
-use reflectub::{database, git, github};
-
-mod multi_error;
-use multi_error::MultiError;
+use reflectub::config;
+use reflectub::config::Config;
+use reflectub::Inconsistency;
+use reflectub::multi_error::MultiError;
 
 use std::env;
-use std::fs;
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::io;
+use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// Exit code used when some repositories failed but others succeeded.
+const EXIT_PARTIAL_FAILURE: exitcode::ExitCode = 1;
+
+/// Exit code used with `--fail-if-unchanged` when no repositories were
+/// mirrored or updated.
+const EXIT_UNCHANGED: exitcode::ExitCode = 2;
+
+/// Set by `--quiet` to suppress error messages.
+static QUIET: AtomicBool = AtomicBool::new(false);
 
 
 fn main() {
     match run() {
         Ok(_) => (),
         Err(e) => {
-            e
-                .into_iter()
-                .for_each(|e| eprintln!("error: {:#}", e));
+            let exit_code = if e.is_partial() {
+                EXIT_PARTIAL_FAILURE
+            } else {
+                exitcode::SOFTWARE
+            };
+
+            if !QUIET.load(Ordering::SeqCst) {
+                e
+                    .into_iter()
+                    .for_each(|e| eprintln!("error: {:#}", e));
+            }
 
-            process::exit(exitcode::SOFTWARE);
+            process::exit(exit_code);
         },
     };
 }
@@ -53,7 +67,7 @@ fn main() {
 fn print_usage(opts: &Options) {
     print!(
         "{}",
-        opts.usage("usage: reflectub [options] -d DATABASE <github_username> <repository_path>"),
+        opts.usage("usage: reflectub [options] -d DATABASE [<github_username>... <repository_path>]"),
     );
 }
 
@@ -62,9 +76,67 @@ fn run() -> Result<(), MultiError> {
 
     let mut opts = Options::new();
 
+    opts.optopt("c", "config", "configuration file path", "CONFIG_FILE");
+    opts.optflag("", "print-config", "print the configuration from all sources and exit");
+    opts.optopt("", "provider", "fetch repositories from PROVIDER: github (default), gitlab, or gitea", "PROVIDER");
+    opts.optopt("", "proxy", "send requests through the proxy at URL (default: $HTTPS_PROXY)", "URL");
+    opts.optopt("", "api-base-url", "root URL of a GitLab or Gitea instance, or of a GitHub API", "URL");
+    opts.optopt("", "sort", "list GitHub repositories by FIELD: updated (default), pushed, created, or full_name", "FIELD");
+    opts.optopt("", "direction", "list GitHub repositories in DIRECTION: asc or desc", "DIRECTION");
     opts.optopt("d", "database", "SQLite database file path (required)", "DATABASE_FILE");
     opts.optopt("", "cgitrc", "base cgitrc file to copy to mirrored repositories", "CGITRC_FILE");
+    opts.optopt("", "cgitrc-template", "cgitrc template to render into mirrored repositories", "TEMPLATE_FILE");
+    opts.optmulti("", "cgitrc-for", "copy CGITRC_FILE into repositories owned by OWNER instead of the base cgitrc", "OWNER=CGITRC_FILE");
+    opts.optmulti("", "cgitrc-line", "add LINE to every repository's cgitrc", "LINE");
+    opts.optopt("", "cgitrc-mode", "set the permissions of written cgitrc files to MODE, as in 0644", "MODE");
+    opts.optflag("", "update-cgitrc", "regenerate cgitrc files of existing mirrors");
+    opts.optflag("", "cgitrc-diff", "show the changes --cgitrc-only would make to cgitrc files without writing them");
+    opts.optflag("", "cgitrc-only", "regenerate cgitrc files from the database without fetching");
+    opts.optflag("", "check", "report repositories missing from the database or the repository path");
+    opts.optflag("", "fix", "with --check, remove database rows of missing mirrors");
+    opts.optopt("", "section-by", "group repositories into CGit sections by owner, language, or fork", "GROUPING");
+    opts.optopt("", "section", "put all repositories in CGit section NAME", "NAME");
+    opts.optopt("", "generate-index", "write a CGit include file listing all mirrors to FILE", "FILE");
     opts.optopt("", "skip-larger-than", "skip repositories larger than SIZE", "SIZE");
+    opts.optflag("", "mirror-unknown-size", "with --skip-larger-than, mirror repositories whose size is reported as 0");
+    opts.optopt("", "skip-larger-than-mode", "skip oversize repositories entirely, or mirror only their metadata", "skip|metadata");
+    opts.optopt("", "private-mode", "skip private repositories, hide them from the CGit index, or show them", "skip|hide|show");
+    opts.optopt("", "pushed-since", "only mirror repositories pushed to since DATE or within DURATION", "DATE|DURATION");
+    opts.optmulti("", "include", "only mirror repositories matching PATTERN", "PATTERN");
+    opts.optmulti("", "exclude", "don't mirror repositories matching PATTERN", "PATTERN");
+    opts.optflag("", "include-forks", "also mirror forks");
+    opts.optopt("", "exclude-file", "don't mirror repositories named in FILE", "FILE");
+    opts.optopt("", "max-repos", "refuse to mirror more than N repositories", "N");
+    opts.optflag("", "force", "mirror repositories even if there are more than --max-repos");
+    opts.optflag("", "force-remirror", "delete existing mirrors and mirror them again from scratch");
+    opts.optmulti("", "repo", "with --force-remirror, only re-mirror repository NAME", "NAME");
+    opts.optopt("", "retries", "retry failed fetches N times", "N");
+    opts.optopt("", "fork-subdir", "mirror forks into NAME under the repository path (default: fork)", "NAME");
+    opts.optopt("", "clone-url-template", "fetch new mirrors from URLs built from TEMPLATE", "TEMPLATE");
+    opts.optopt("", "remote-name", "name the remote of new mirrors NAME (default: origin)", "NAME");
+    opts.optopt("", "http-timeout", "give up on API requests that stall for DURATION (default: 30s)", "DURATION");
+    opts.optopt("", "fetch-timeout", "abort fetches that take longer than DURATION", "DURATION");
+    opts.optopt("", "throttle", "wait a random time of up to DURATION before each fetch", "DURATION");
+    opts.optmulti("", "git-header", "send HEADER with every HTTP(S) fetch", "HEADER");
+    opts.optmulti("", "git-config", "set the Git config KEY to VALUE in every mirror", "KEY=VALUE");
+    opts.optflag("", "git-trace", "log details of each fetch to standard error");
+    opts.optflag("", "resume", "continue an interrupted run, retrying failed repositories first");
+    opts.optflag("", "heal", "verify mirrors after fetching and re-mirror corrupt ones");
+    opts.optopt("", "bundle-dir", "write a Git bundle of each changed mirror to DIR", "DIR");
+    opts.optflag("", "mirror-wikis", "also mirror the wikis of GitHub repositories");
+    opts.optflag("", "mirror-releases", "store the releases of GitHub repositories in the database");
+    opts.optflag("", "mirror-release-assets", "with --mirror-releases, download release assets into each mirror");
+    opts.optopt("", "skip-assets-larger-than", "don't download release assets larger than SIZE", "SIZE");
+    opts.optflag("", "write-metadata-json", "write each mirror's metadata to reflectub.json inside it");
+    opts.optflag("", "always-agefile", "write a CGit agefile in every mirror");
+    opts.optflag("", "no-tags", "only fetch branches, not tags");
+    opts.optopt("", "db-pool-size", "maximum number of database connections", "SIZE");
+    opts.optopt("", "db-batch-size", "save N repositories to the database per transaction (default: 1)", "N");
+    opts.optflag("", "report-sizes", "after mirroring, print the disk usage of each mirror");
+    opts.optflag("", "fail-if-unchanged", "exit with status 2 if no repositories were mirrored or updated");
+    opts.optflag("n", "dry-run", "show what would be done without doing it");
+    opts.optflagmulti("v", "verbose", "print progress messages; repeat for more detail");
+    opts.optflag("q", "quiet", "don't print warnings or errors");
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("V", "version", "show the program version");
 
@@ -81,357 +153,535 @@ fn run() -> Result<(), MultiError> {
         process::exit(exitcode::OK);
     }
 
-    let database_file = opt_matches.opt_str("database")
-        .ok_or(anyhow::anyhow!("missing required argument '--database'"))?;
+    if opt_matches.opt_present("quiet") {
+        QUIET.store(true, Ordering::SeqCst);
 
-    if opt_matches.free.len() != 2 {
-        print_usage(&opts);
-        process::exit(exitcode::USAGE);
+        init_logger(None);
+    } else {
+        init_logger(Some(opt_matches.opt_count("verbose")));
     }
 
-    let username = &opt_matches.free[0];
-    let mirror_root = &opt_matches.free[1];
-
-    // Parse the maximum repo size and return an error if it fails. If the size
-    // is `None`, set the value to `None`.
-    let max_repo_size_bytes = opt_matches.opt_str("skip-larger-than")
-        .map_or(
-            Ok(None),
-            |s|
-                parse_size(&s)
-                    .map(|s| Some(s))
-                    .with_context(|| format!(
-                        "unable to parse max file size '{}'",
-                        s
-                    ))
-        )?;
-
-    let base_cgitrc = opt_matches.opt_str("cgitrc")
-        .map(|s| PathBuf::from(s));
-
-    let repos = github::fetch_repos(username)
-        .context("unable to fetch GitHub repositories")?;
-
-    let db = database::Db::connect(&database_file)
-        .context("unable to connect to database")?;
-
-    db.create()
-        .context("unable to create database")?;
-
-    let errors: Vec<_> = repos
-        .par_iter()
-        .map(|repo| {
-            (
-                &repo.name,
-                process_repo(
-                    &repo,
-                    &db,
-                    &mirror_root,
-                    base_cgitrc.as_ref(),
-                    max_repo_size_bytes,
-                ),
-            )
-        })
-        .filter(|(_, r)| r.is_err())
-
-        // `error` should always be an error.
-        .map(|(name, error)| {
-            error
-                .err()
-                .unwrap()
-                .context(name.clone())
-        })
-        .collect();
+    // Read the config file given on the command line, or the default config
+    // file if it exists.
+    let mut config = match opt_matches.opt_str("config") {
+        Some(path) => Config::load(path)
+            .map_err(anyhow::Error::new)?,
+        None => match Config::default_path() {
+            Some(path) if path.exists() => Config::load(path)
+                .map_err(anyhow::Error::new)?,
+            _ => Config::default(),
+        },
+    };
 
-    if errors.len() > 0 {
-        return Err(MultiError::from(errors))
+    // Command line options override config file values.
+    if let Some(provider) = opt_matches.opt_str("provider") {
+        config.provider = Some(provider);
     }
 
-    Ok(())
-}
+    if let Some(proxy) = opt_matches.opt_str("proxy") {
+        config.proxy = Some(proxy);
+    }
 
-/// Mirror or update `repo`.
-fn process_repo<P: AsRef<Path>>(
-    repo: &github::Repo,
-    db: &database::Db,
-    mirror_root: &str,
-    base_cgitrc: Option<P>,
-    max_repo_size_bytes: Option<u64>,
-) -> anyhow::Result<()> {
-    if let Some(max_repo_size_bytes) = max_repo_size_bytes {
-        if is_repo_oversize(repo.size, max_repo_size_bytes) {
-            return Ok(());
-        }
+    if config.proxy.is_none() {
+        config.proxy = env::var("HTTPS_PROXY")
+            .or_else(|_| env::var("https_proxy"))
+            .ok()
+            .filter(|proxy| !proxy.is_empty());
     }
 
-    let id = repo.id;
-    let path = clone_path(&mirror_root, &repo);
-    let db_repo = database::Repo::from(repo);
+    if config.github_token.is_none() {
+        config.github_token = env::var("GITHUB_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+    }
 
-    match db.repo_get(id) {
-        // If we've already seen the repo and it's been updated, fetch the
-        // latest.
-        Ok(current_repo) => {
-            if db.repo_is_updated(&db_repo)? {
-                update(&path, &current_repo, &repo)?;
+    if let Some(api_base_url) = opt_matches.opt_str("api-base-url") {
+        config.api_base_url = Some(api_base_url);
+    }
 
-                db.repo_update(&db_repo)?;
-            }
-        },
+    if let Some(sort) = opt_matches.opt_str("sort") {
+        config.sort = Some(sort);
+    }
 
-        // If the repo doesn't exist, mirror it and store it in the
-        // database.
-        Err(database::Error::Db(rusqlite::Error::QueryReturnedNoRows)) => {
-            mirror(
-                path,
-                &repo,
-                base_cgitrc,
-            )?;
+    if let Some(direction) = opt_matches.opt_str("direction") {
+        config.direction = Some(direction);
+    }
 
-            db.repo_insert(db_repo)?;
-        },
+    if let Some(database) = opt_matches.opt_str("database") {
+        config.database = Some(database);
+    }
 
-        Err(e) => anyhow::bail!(e),
+    if let Some(cgitrc) = opt_matches.opt_str("cgitrc") {
+        config.cgitrc = Some(PathBuf::from(cgitrc));
     }
 
-    Ok(())
-}
+    if let Some(template) = opt_matches.opt_str("cgitrc-template") {
+        config.cgitrc_template = Some(PathBuf::from(template));
+    }
 
+    for owner_cgitrc in opt_matches.opt_strs("cgitrc-for") {
+        let (owner, path) = owner_cgitrc.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!(
+                "expected OWNER=CGITRC_FILE in '--cgitrc-for {}'",
+                owner_cgitrc,
+            ))?;
 
-/// Return `true` if `size_kilobytes` is larger than `max_repo_size_bytes`.
-fn is_repo_oversize(
-    size_kilobytes: u64,
-    max_repo_size_bytes: u64,
-) -> bool {
-    let size_bytes = size_kilobytes * 1000;
+        config.cgitrc_for.insert(owner.to_owned(), PathBuf::from(path));
+    }
 
-    if size_bytes > max_repo_size_bytes {
-        return true;
+    let cgitrc_lines = opt_matches.opt_strs("cgitrc-line");
+    if !cgitrc_lines.is_empty() {
+        config.cgitrc_lines = cgitrc_lines;
     }
 
-    false
-}
+    if let Some(mode) = opt_matches.opt_str("cgitrc-mode") {
+        config.cgitrc_mode = Some(mode);
+    }
 
-/// Get the clone path for a repository.
-///
-/// If `repo` is a fork, add `/fork/` to `base_path`.
-fn clone_path<P: AsRef<Path>>(base_path: P, repo: &github::Repo) -> PathBuf {
-    let git_dir = format!("{}.git", repo.name);
-
-    if repo.fork {
-        base_path
-            .as_ref()
-            .join("fork")
-            .join(git_dir)
-    } else {
-        base_path
-            .as_ref()
-            .join(git_dir)
+    if opt_matches.opt_present("update-cgitrc") {
+        config.update_cgitrc = true;
     }
-}
 
-/// Mirror a repository.
-fn mirror<P1, P2>(
-    clone_path: P1,
-    repo: &github::Repo,
-    base_cgitrc: Option<P2>,
-) -> anyhow::Result<()>
-where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
-{
-    git::mirror(
-        &repo.clone_url,
-        &clone_path,
-        repo.description(),
-        &repo.default_branch,
-    )?;
-
-    // Copy the base cgitrc file into the newly-cloned repository.
-    if let Some(base_cgitrc) = base_cgitrc {
-        let cgitrc_path = clone_path.as_ref().join("cgitrc");
-
-        fs::copy(&base_cgitrc, &cgitrc_path)
-            .with_context(|| format!(
-                "unable to copy '{}' to '{}'",
-                "./cgitrc",
-                &cgitrc_path.display(),
-            ))?;
+    if let Some(section_by) = opt_matches.opt_str("section-by") {
+        config.section_by = Some(section_by);
     }
 
-    if repo.default_branch != "master" {
-        repo_cgitrc_set_defbranch(&clone_path, &repo.default_branch)?;
+    if let Some(section) = opt_matches.opt_str("section") {
+        config.section = Some(section);
     }
 
-    update_mtime(&clone_path, &repo)?;
+    if let Some(size) = opt_matches.opt_str("skip-larger-than") {
+        config.skip_larger_than = Some(size);
+    }
 
-    Ok(())
-}
+    if let Some(mode) = opt_matches.opt_str("skip-larger-than-mode") {
+        config.skip_larger_than_mode = Some(mode);
+    }
 
-/// Update a previously-mirrored repository.
-fn update<P: AsRef<Path>>(
-    repo_path: P,
-    current_repo: &database::Repo,
-    updated_repo: &github::Repo,
-) -> anyhow::Result<()> {
-    git::update(&repo_path)?;
+    if let Some(mode) = opt_matches.opt_str("private-mode") {
+        config.private_mode = Some(mode);
+    }
 
-    let remote_description = updated_repo.description();
+    if opt_matches.opt_present("mirror-unknown-size") {
+        config.mirror_unknown_size = true;
+    }
 
-    if current_repo.description() != remote_description {
-        git::update_description(&repo_path, remote_description)?;
+    if let Some(pool_size) = opt_matches.opt_str("db-pool-size") {
+        config.db_pool_size = Some(
+            pool_size.parse()
+                .with_context(|| format!(
+                    "unable to parse database pool size '{}'",
+                    pool_size,
+                ))?
+        );
     }
 
-    if let Some(default_branch) = &current_repo.default_branch {
-        if default_branch != &updated_repo.default_branch {
-            git::change_current_branch(
-                &repo_path,
-                &updated_repo.default_branch,
-            )?;
+    if let Some(batch_size) = opt_matches.opt_str("db-batch-size") {
+        config.db_batch_size = Some(
+            batch_size.parse()
+                .with_context(|| format!(
+                    "unable to parse database batch size '{}'",
+                    batch_size,
+                ))?
+        );
+    }
 
-            repo_cgitrc_set_defbranch(&repo_path, &updated_repo.default_branch)?;
-        }
+    if let Some(pushed_since) = opt_matches.opt_str("pushed-since") {
+        config.pushed_since = Some(pushed_since);
     }
 
-    update_mtime(&repo_path, &updated_repo)?;
+    let include = opt_matches.opt_strs("include");
+    if !include.is_empty() {
+        config.include = include;
+    }
 
-    Ok(())
-}
+    let exclude = opt_matches.opt_strs("exclude");
+    if !exclude.is_empty() {
+        config.exclude = exclude;
+    }
 
-/// Set the mtime of the repository to GitHub's `pushed_at` time.
-///
-/// Used for CGit "age" sorting.
-fn update_mtime<P: AsRef<Path>>(
-    repo_path: P,
-    repo: &github::Repo,
-) -> anyhow::Result<()> {
-    let update_time = filetime::FileTime::from_system_time(
-        DateTime::parse_from_rfc3339(&repo.pushed_at)
-            .with_context(|| format!(
-                "unable to parse update time from '{}'",
-                &repo.pushed_at,
-            ))?
-            .into()
-    );
+    if let Some(exclude_file) = opt_matches.opt_str("exclude-file") {
+        config.exclude_file = Some(PathBuf::from(exclude_file));
+    }
 
-    let default_branch_ref = repo_path
-        .as_ref()
-        .join("refs/heads")
-        .join(&repo.default_branch);
-
-    // Try updating times on the default ref.
-    match filetime::set_file_times(
-        &default_branch_ref,
-        update_time,
-        update_time,
-    ) {
-        Ok(_) => Ok(()),
-        Err(e) if e.kind() == io::ErrorKind::NotFound => {
-            // If the default ref file doesn't exist, update times on the
-            // 'packed-refs' file.
-            let packed_refs_path = repo_path
-                .as_ref()
-                .join("packed-refs");
-
-            match filetime::set_file_times(
-                &packed_refs_path,
-                update_time,
-                update_time,
-            ) {
-                Ok(_) => Ok(()),
-                Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                    // In the absence of a 'packed-refs' file, create a CGit
-                    // agefile and add the update time to it.
-                    Ok(set_agefile_time(&repo_path, &repo.pushed_at)?)
-                },
-                Err(e) => Err(e),
-            }
+    if let Some(max_repos) = opt_matches.opt_str("max-repos") {
+        config.max_repos = Some(
+            max_repos.parse()
                 .with_context(|| format!(
-                    "unable to set mtime on '{}'",
-                    &packed_refs_path.display(),
-                ))?;
+                    "unable to parse maximum repositories '{}'",
+                    max_repos,
+                ))?
+        );
+    }
+
+    if opt_matches.opt_present("force") {
+        config.force = true;
+    }
+
+    if opt_matches.opt_present("force-remirror") {
+        config.force_remirror = true;
+    }
+
+    config.remirror_repos = opt_matches.opt_strs("repo");
+
+    if let Some(retries) = opt_matches.opt_str("retries") {
+        config.retries = Some(
+            retries.parse()
+                .with_context(|| format!(
+                    "unable to parse retries '{}'",
+                    retries,
+                ))?
+        );
+    }
+
+    if opt_matches.opt_present("heal") {
+        config.heal = true;
+    }
+
+    if let Some(bundle_dir) = opt_matches.opt_str("bundle-dir") {
+        config.bundle_dir = Some(PathBuf::from(bundle_dir));
+    }
+
+    if opt_matches.opt_present("include-forks") {
+        config.include_forks = true;
+    }
 
-            Ok(())
+    if opt_matches.opt_present("mirror-wikis") {
+        config.mirror_wikis = true;
+    }
+
+    if opt_matches.opt_present("mirror-releases") {
+        config.mirror_releases = true;
+    }
+
+    if opt_matches.opt_present("mirror-release-assets") {
+        config.mirror_release_assets = true;
+    }
+
+    if let Some(size) = opt_matches.opt_str("skip-assets-larger-than") {
+        config.skip_assets_larger_than = Some(size);
+    }
+
+    if opt_matches.opt_present("write-metadata-json") {
+        config.write_metadata_json = true;
+    }
+
+    if opt_matches.opt_present("always-agefile") {
+        config.always_agefile = true;
+    }
+
+    if opt_matches.opt_present("no-tags") {
+        config.no_tags = true;
+    }
+
+    if let Some(fork_subdir) = opt_matches.opt_str("fork-subdir") {
+        config.fork_subdir = Some(fork_subdir);
+    }
+
+    if let Some(remote_name) = opt_matches.opt_str("remote-name") {
+        config.remote_name = Some(remote_name);
+    }
+
+    if let Some(template) = opt_matches.opt_str("clone-url-template") {
+        config.clone_url_template = Some(template);
+    }
+
+    if let Some(timeout) = opt_matches.opt_str("http-timeout") {
+        config.http_timeout = Some(timeout);
+    }
+
+    if let Some(timeout) = opt_matches.opt_str("fetch-timeout") {
+        config.fetch_timeout = Some(timeout);
+    }
+
+    if let Some(throttle) = opt_matches.opt_str("throttle") {
+        config.throttle = Some(throttle);
+    }
+
+    let git_headers = opt_matches.opt_strs("git-header");
+    if !git_headers.is_empty() {
+        config.git_headers = git_headers;
+    }
+
+    let git_config = opt_matches.opt_strs("git-config");
+    if !git_config.is_empty() {
+        config.git_config = git_config;
+    }
+
+    if opt_matches.opt_present("git-trace") {
+        config.git_trace = true;
+    }
+
+    if let Some(index_path) = opt_matches.opt_str("generate-index") {
+        config.generate_index = Some(PathBuf::from(index_path));
+    }
+
+    let cgitrc_only = opt_matches.opt_present("cgitrc-only");
+    let cgitrc_diff = opt_matches.opt_present("cgitrc-diff");
+    let check = opt_matches.opt_present("check");
+
+    // The last free argument is the mirror root, and the ones before it are
+    // usernames. Usernames aren't needed to regenerate cgitrc files or check
+    // mirrors.
+    match opt_matches.free.split_last() {
+        None => (),
+        Some((mirror_root, [])) if cgitrc_only || cgitrc_diff || check => {
+            config.mirror_root = Some(mirror_root.clone());
+        },
+        Some((_, [])) => {
+            print_usage(&opts);
+            process::exit(exitcode::USAGE);
         },
-        Err(e) => Err(e),
+        Some((mirror_root, usernames)) => {
+            config.users = usernames
+                .iter()
+                .map(|name| config::User::new(name))
+                .collect();
+            config.mirror_root = Some(mirror_root.clone());
+        },
+    }
+
+    if opt_matches.opt_present("resume") {
+        config.resume = true;
+    }
+
+    if opt_matches.opt_present("dry-run") {
+        config.dry_run = true;
+    }
+
+    if opt_matches.opt_present("print-config") {
+        print!(
+            "{}",
+            config.to_redacted_toml()
+                .map_err(anyhow::Error::new)?,
+        );
+
+        return Ok(());
+    }
+
+    if config.database.is_none() {
+        return Err(
+            anyhow::anyhow!("missing required argument '--database'").into()
+        );
+    }
+
+    if config.mirror_root.is_none() {
+        return Err(
+            anyhow::anyhow!("missing required argument <repository_path>")
+                .into()
+        );
+    }
+
+    if cgitrc_diff {
+        return reflectub::cgitrc_diff(&config, io::stdout().lock());
+    }
+
+    if cgitrc_only {
+        let names = reflectub::regenerate_cgitrc(&config)?;
+
+        if config.dry_run {
+            for name in names {
+                println!("{}: regenerate cgitrc", name);
+            }
+        }
+
+        return Ok(());
+    }
+
+    if check {
+        return check_mirrors(&config, opt_matches.opt_present("fix"));
+    }
+
+    if config.users.is_empty() {
+        return Err(
+            anyhow::anyhow!("missing required argument <github_username>")
+                .into()
+        );
+    }
+
+    let shutdown = reflectub::Shutdown::new();
+
+    {
+        let shutdown = shutdown.clone();
+
+        ctrlc::set_handler(move || {
+            // Cancel in-progress fetches on a second signal. The run still
+            // returns normally, so everything processed so far is saved.
+            if shutdown.request() {
+                warn!("interrupted again, cancelling in-progress fetches");
+            } else {
+                warn!(
+                    "interrupted, waiting for in-progress repositories to \
+                    finish"
+                );
+            }
+        })
+            .context("unable to set signal handler")?;
+    }
+
+    let summary = reflectub::mirror_user_with_shutdown(&config, &shutdown)?;
+
+    if config.dry_run {
+        for line in &summary.plan {
+            println!("{}", line);
+        }
+
+        println!(
+            "{} to mirror, {} to update, {} up to date, {} skipped (too large), \
+            {} skipped (fork)",
+            summary.mirrored,
+            summary.updated,
+            summary.unchanged,
+            summary.skipped_size,
+            summary.skipped_forks,
+        );
+    }
+
+    if summary.skipped_forks > 0 {
+        info!(
+            "skipped {} forks; use '--include-forks' to mirror them",
+            summary.skipped_forks,
+        );
+    }
+
+    if summary.received_repos > 0 {
+        info!(
+            "fetched {} in {} objects across {} repositories",
+            format_size(summary.received.received_bytes),
+            summary.received.received_objects,
+            summary.received_repos,
+        );
+    }
+
+    if summary.empty > 0 {
+        info!(
+            "{} repositories have no commits yet and will be fetched again \
+            once they're pushed to",
+            summary.empty,
+        );
+    }
+
+    // Warnings don't affect the exit code.
+    if !QUIET.load(Ordering::SeqCst) {
+        for warning in &summary.warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
+
+    let changed = summary.mirrored + summary.updated;
+    let result = summary.into_result();
+
+    if opt_matches.opt_present("report-sizes") {
+        report_sizes(&config)?;
+    }
+
+    // Errors take precedence over the unchanged status.
+    result?;
+
+    if changed == 0 && opt_matches.opt_present("fail-if-unchanged") {
+        process::exit(EXIT_UNCHANGED);
     }
-        .with_context(|| format!(
-            "unable to set mtime on '{}'",
-            &default_branch_ref.display(),
-        ))?;
 
     Ok(())
 }
 
-/// Write `update_time` into the repo's `info/web/last-modified` file.
-fn set_agefile_time<P: AsRef<Path>>(
-    repo_path: P,
-    update_time: &str,
-) -> anyhow::Result<()> {
-    let agefile_dir = repo_path.as_ref().join("info/web");
-    fs::DirBuilder::new()
-        .create(&agefile_dir)
-        .with_context(|| format!(
-            "unable to create directory '{}'",
-            &agefile_dir.display(),
-        ))?;
-
-    let agefile_path = agefile_dir.join("last-modified");
-    let mut agefile = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(&agefile_path)
-        .with_context(|| format!(
-            "unable to open '{}'",
-            &agefile_path.display(),
-        ))?;
-
-    writeln!(agefile, "{}", &update_time)
-        .with_context(|| format!(
-            "unable to write to '{}'",
-            &agefile_path.display(),
-        ))?;
+/// Print the disk usage of each mirror and the total, like du(1).
+fn report_sizes(config: &Config) -> Result<(), MultiError> {
+    let sizes = reflectub::disk_sizes(config)?;
+
+    for (name, size) in &sizes {
+        println!("{}\t{}", format_size(*size), name);
+    }
+
+    println!(
+        "{}\ttotal",
+        format_size(sizes.iter().map(|(_, size)| size).sum()),
+    );
 
     Ok(())
 }
 
-/// Set the default CGit branch in the repository's "cgitrc" file.
-fn repo_cgitrc_set_defbranch<P: AsRef<Path>>(
-    repo_path: P,
-    default_branch: &str,
-) -> anyhow::Result<()> {
-    repo_cgitrc_append(
-        &repo_path,
-        &format!("defbranch={}", default_branch),
-    )?;
+/// Format `bytes` with a binary unit, as in "1.5 MiB".
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
 
-    Ok(())
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
 }
 
-/// Append `config` to the repo-local "cgitrc" file.
-fn repo_cgitrc_append<P: AsRef<Path>>(
-    repo_path: P,
-    config: &str,
-) -> anyhow::Result<()> {
-    let cgitrc_path = repo_path
-        .as_ref()
-        .join("cgitrc");
-
-    let mut cgitrc_file = fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(&cgitrc_path)
-        .with_context(|| format!(
-            "unable to open '{}'",
-            &cgitrc_path.display(),
-        ))?;
-
-    writeln!(cgitrc_file, "{}", config)
-        .with_context(|| format!(
-            "unable to write to '{}'",
-            &cgitrc_path.display(),
-        ))?;
+/// Print differences between the database and the mirrors on disk, and fix
+/// them if `fix` is true.
+///
+/// Returns a partial error if any inconsistencies remain.
+fn check_mirrors(config: &Config, fix: bool) -> Result<(), MultiError> {
+    let inconsistencies = reflectub::check(config)?;
+
+    for inconsistency in &inconsistencies {
+        println!("{}", inconsistency);
+    }
+
+    if fix && config.dry_run {
+        for inconsistency in &inconsistencies {
+            if let Inconsistency::MissingMirror { name, .. } = inconsistency {
+                println!("{}: remove from database", name);
+            }
+        }
+    }
+
+    let fixed = if fix {
+        reflectub::fix(config, &inconsistencies)?
+    } else {
+        0
+    };
+
+    let remaining = inconsistencies.len() - fixed;
+
+    if remaining > 0 {
+        return Err(MultiError::partial(vec![
+            anyhow::anyhow!("{} inconsistencies found", remaining),
+        ]));
+    }
 
     Ok(())
 }
+
+/// Initialise the logger.
+///
+/// By default, only warnings and errors are logged. A `verbosity` of 1 enables
+/// info messages, and 2 or more enables debug messages. A `verbosity` of
+/// `None` disables logging. Fetch details, only sent with `--git-trace`, are
+/// logged unless logging is disabled. The `RUST_LOG` environment variable
+/// takes precedence.
+fn init_logger(verbosity: Option<usize>) {
+    let level = match verbosity {
+        None => log::LevelFilter::Off,
+        Some(0) => log::LevelFilter::Warn,
+        Some(1) => log::LevelFilter::Info,
+        Some(_) => log::LevelFilter::Debug,
+    };
+
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+
+    if verbosity.is_some() {
+        builder.filter_module(
+            reflectub::git::TRACE_TARGET,
+            log::LevelFilter::Debug,
+        );
+    }
+
+    builder
+        .parse_default_env()
+        .init();
+}
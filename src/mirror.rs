@@ -0,0 +1,3445 @@
+// Copyright (c) 2021, 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use anyhow::{self, Context};
+use chrono::{DateTime, FixedOffset, NaiveDate, SecondsFormat, Utc};
+use log::{debug, info, warn};
+use parse_size::parse_size;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+
+use crate::{cgitrc, config, database, git, gitea, github, gitlab, provider};
+use crate::config::Config;
+use crate::multi_error::MultiError;
+use crate::provider::Provider;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Name of the optional file in the mirror root listing glob patterns of
+/// repositories not to mirror.
+const IGNORE_FILE: &str = ".reflectubignore";
+
+/// Name of the optional file in the mirror root with settings for individual
+/// repositories.
+const OVERRIDES_FILE: &str = "reflectub.overrides.toml";
+
+/// Name of the directory in each mirror that release assets are downloaded
+/// into.
+const RELEASES_DIR: &str = "releases";
+
+/// Name of the file in each mirror that `write_metadata_json` writes.
+const METADATA_JSON_FILE: &str = "reflectub.json";
+
+/// How often `mirror_user` logs how many repositories it has processed.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+
+
+/// Asks a `mirror_user_with_shutdown` run to stop, as from a signal handler.
+///
+/// Clones share the same requests.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+
+    /// Set on the second request to cancel fetches in progress.
+    aborted: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Ask the run to stop.
+    ///
+    /// On the first request, repositories already being processed are
+    /// finished, and the rest are counted as interrupted. Later requests also
+    /// cancel fetches in progress, failing their repositories. Either way,
+    /// the run still saves what it processed before returning.
+    ///
+    /// Returns `true` if a shutdown was already requested.
+    pub fn request(&self) -> bool {
+        let already_requested = self.requested.swap(true, Ordering::SeqCst);
+
+        if already_requested {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+
+        already_requested
+    }
+
+    /// Return `true` once a shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+
+/// Mirror the repositories of every user in `config` into
+/// `config.mirror_root`, and record them in `config.database`.
+///
+/// Like `mirror_user_with_shutdown`, but the run can't be stopped early.
+pub fn mirror_user(config: &Config) -> Result<Summary, MultiError> {
+    mirror_user_with_shutdown(config, &Shutdown::new())
+}
+
+/// Mirror the repositories of every user in `config` into
+/// `config.mirror_root`, and record them in `config.database`, stopping early
+/// once `shutdown` is requested.
+///
+/// New repositories are mirrored and existing ones are updated, in parallel.
+/// Each page of repositories starts processing as soon as it's fetched. A
+/// repository listed more than once is processed with the settings of the
+/// first listing, and again afterwards if a later listing was pushed to more
+/// recently.
+/// If `config.dry_run` is set, nothing is changed, and the actions that would
+/// be taken are listed in `Summary::plan` instead.
+///
+/// Failures to fetch a user's repositories or to process a repository are
+/// recorded in the returned `Summary` rather than stopping the run. An error
+/// is returned only if the run can't start, as when `config` is invalid.
+pub fn mirror_user_with_shutdown(
+    config: &Config,
+    shutdown: &Shutdown,
+) -> Result<Summary, MultiError> {
+    if config.users.is_empty() {
+        return Err(anyhow::anyhow!("no users to mirror").into());
+    }
+
+    let options = Options::parse(config)?;
+    let mut default_settings = Settings {
+        shutdown: shutdown.clone(),
+        ..options.settings(config)?
+    };
+    default_settings.fetch_settings.cancel = Arc::clone(&shutdown.aborted);
+
+    let pushed_since = config.pushed_since
+        .as_deref()
+        .map(|s| parse_since(s, Utc::now()))
+        .transpose()?;
+
+    let provider = new_provider(
+        config.provider.as_deref().unwrap_or("github"),
+        config.api_base_url.as_deref(),
+        config.proxy.as_deref(),
+        options.http_timeout,
+        options.order,
+        config.github_token.as_deref(),
+    )?;
+
+    if config.mirror_releases && !provider.supports_releases() {
+        return Err(
+            anyhow::anyhow!(
+                "'--mirror-releases' isn't supported for {}",
+                provider.name(),
+            )
+                .into()
+        );
+    }
+
+    let include_patterns = parse_patterns(&config.include)?;
+    let mut exclude_patterns = parse_patterns(&config.exclude)?;
+
+    if let Some(path) = &config.exclude_file {
+        exclude_patterns.extend(read_exclude_file(path)?);
+    }
+
+    let ignore_file = Path::new(default_settings.mirror_root).join(IGNORE_FILE);
+    exclude_patterns.extend(read_ignore_file(&ignore_file)?);
+
+    let db = open_database(config)?;
+
+    let mut summary = Summary::default();
+
+    let run_state = if config.resume {
+        Some(
+            db.run_state_all()
+                .context("unable to read run state")?
+        )
+    } else {
+        if !config.dry_run {
+            db.run_state_clear()
+                .context("unable to clear run state")?;
+        }
+
+        None
+    };
+
+    let write_batch = WriteBatch::new(
+        &db,
+        config.db_batch_size.unwrap_or(1),
+    );
+
+    // Results of each page of repositories, tagged with the page's position
+    // so they can be reported in order.
+    let page_results = Mutex::new(Vec::new());
+
+    let progress = Arc::new(Progress::default());
+
+    // Mirror a page of repositories in parallel, and record the results
+    // under the page's position.
+    let process_page = |
+        page_index: usize,
+        settings: Settings,
+        repos: Vec<provider::Repo>,
+    | {
+        let results: Vec<_> = repos
+            .par_iter()
+            .map(|repo| {
+                let result = run_repo(
+                    repo,
+                    &db,
+                    &*provider,
+                    &settings,
+                    &write_batch,
+                );
+                progress.processed.fetch_add(1, Ordering::SeqCst);
+
+                (repo.id, repo.name.clone(), result)
+            })
+            .collect();
+
+        page_results
+            .lock()
+            .unwrap()
+            .push((page_index, results));
+    };
+
+    // The same repository can be returned for more than one user, as when
+    // mirroring both an organisation and one of its members. The `pushed_at`
+    // time of each repository queued so far is kept, so that a more recently
+    // pushed duplicate can be processed after the rest.
+    let mut seen = HashMap::new();
+    let mut newer_duplicates = HashMap::new();
+    let mut page_count = 0;
+
+    // With a maximum, pages are held back until every user's repositories
+    // have been counted.
+    let max_repos = config.max_repos.filter(|_| !config.force);
+    let mut held_pages = Vec::new();
+    let mut total_repos = 0;
+
+    let mut skipped_forks = 0;
+    let mut hidden_name_warnings = Vec::new();
+
+    // Report progress from outside the thread pool, which may be busy with
+    // long fetches.
+    let (stop_progress, progress_stopped) = mpsc::channel::<()>();
+    let progress_reporter = {
+        let progress = Arc::clone(&progress);
+
+        thread::spawn(move ||
+            progress.report_every(PROGRESS_INTERVAL, &progress_stopped)
+        )
+    };
+
+    // Start processing each page of repositories while the next one is
+    // fetched.
+    rayon::scope(|scope| {
+        for user in &config.users {
+            if shutdown.is_requested() {
+                break;
+            }
+
+            let settings = default_settings.for_user(user);
+            let fetch_start = Instant::now();
+            let mut repo_count = 0;
+
+            let result = provider.fetch_repos_each(&user.name, &mut |page| {
+                repo_count += page.len();
+
+                let mut repos = filter_repos(
+                    page,
+                    &include_patterns,
+                    &exclude_patterns,
+                );
+
+                if !config.include_forks {
+                    let count = repos.len();
+                    repos.retain(|repo| !repo.fork);
+                    skipped_forks += count - repos.len();
+                }
+
+                // Leave out dormant repositories.
+                if let Some(since) = pushed_since {
+                    repos.retain(|repo| repo.pushed_at >= since);
+                }
+
+                repos.retain(|repo| {
+                    let skip = options.overrides
+                        .get(&repo.name)
+                        .is_some_and(|o| o.skip);
+
+                    if skip {
+                        debug!("{}: skipping, as set in overrides", repo.name);
+                    }
+
+                    !skip
+                });
+
+                if options.private_mode == PrivateMode::Skip {
+                    repos.retain(|repo| {
+                        if repo.private {
+                            debug!(
+                                "{}: skipping private repository",
+                                repo.name,
+                            );
+                        }
+
+                        !repo.private
+                    });
+                }
+
+                // Mirrors of names starting with a dot would be hidden, so
+                // they're skipped. GitHub allows such names, as in ".github",
+                // so this isn't an error. Names with path separators are
+                // still refused by `process_repo`.
+                repos.retain(|repo| {
+                    if !repo.name.starts_with('.')
+                        || repo.name.contains(['/', '\\'])
+                    {
+                        return true;
+                    }
+
+                    hidden_name_warnings.push(Warning::new(
+                        &repo.name,
+                        "skipping, name starts with a dot".to_owned(),
+                    ));
+
+                    false
+                });
+
+                let (mut repos, newer) = dedup_repos(repos, &mut seen);
+
+                for repo in newer {
+                    newer_duplicates.insert(repo.id, (settings.clone(), repo));
+                }
+
+                if let Some(run_state) = &run_state {
+                    // Skip repositories already refreshed in this run, and
+                    // retry failed ones before those that weren't attempted.
+                    repos.retain(|repo|
+                        run_state.get(&repo.id)
+                            != Some(&database::RunStatus::Done)
+                    );
+                    repos.sort_by_key(|repo|
+                        run_state.get(&repo.id)
+                            != Some(&database::RunStatus::Failed)
+                    );
+                }
+
+                let page_index = page_count;
+                page_count += 1;
+                total_repos += repos.len();
+                progress.total.fetch_add(repos.len(), Ordering::SeqCst);
+
+                if max_repos.is_some() {
+                    held_pages.push((page_index, settings.clone(), repos));
+                } else {
+                    let settings = settings.clone();
+                    let process_page = &process_page;
+
+                    scope.spawn(move |_|
+                        process_page(page_index, settings, repos)
+                    );
+                }
+
+                // Stop fetching after a signal.
+                if shutdown.is_requested() {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            });
+
+            match result {
+                Ok(()) => info!(
+                    "fetched {} repositories for '{}' in {:.2?}",
+                    repo_count,
+                    user.name,
+                    fetch_start.elapsed(),
+                ),
+                Err(e) => {
+                    let timed_out = e.is_timeout();
+                    let mut error = anyhow::Error::new(e);
+
+                    if timed_out {
+                        error = error.context(format!(
+                            "no response within {}; use '--http-timeout' \
+                            to wait longer",
+                            humantime::format_duration(options.http_timeout),
+                        ));
+                    }
+
+                    summary.errors.push((
+                        user.name.clone(),
+                        error.context(format!(
+                            "unable to fetch {} repositories for '{}'",
+                            provider.name(),
+                            user.name,
+                        )),
+                    ));
+                },
+            }
+        }
+
+        if max_repos.is_some_and(|max| total_repos > max) {
+            return;
+        }
+
+        for (page_index, settings, repos) in held_pages {
+            let process_page = &process_page;
+
+            scope.spawn(move |_| process_page(page_index, settings, repos));
+        }
+    });
+
+    if max_repos.is_none_or(|max| total_repos <= max)
+        && !newer_duplicates.is_empty()
+    {
+        // Save the first entries, so the newer ones update their mirrors
+        // instead of being inserted again.
+        write_batch.flush();
+
+        progress.total.fetch_add(newer_duplicates.len(), Ordering::SeqCst);
+
+        let repos: Vec<_> = newer_duplicates.into_values().collect();
+
+        rayon::scope(|scope| {
+            for (settings, repo) in repos {
+                let page_index = page_count;
+                page_count += 1;
+
+                let process_page = &process_page;
+
+                scope.spawn(move |_|
+                    process_page(page_index, settings, vec![repo])
+                );
+            }
+        });
+    }
+
+    drop(stop_progress);
+    let _ = progress_reporter.join();
+
+    if let Some(max) = max_repos {
+        if total_repos > max {
+            return Err(
+                anyhow::anyhow!(
+                    "found {} repositories to mirror, more than the maximum \
+                    of {}; use '--force' to mirror them anyway",
+                    total_repos,
+                    max,
+                )
+                    .into()
+            );
+        }
+    }
+
+    summary.skipped_forks = skipped_forks;
+    summary.warnings.extend(hidden_name_warnings);
+
+    let mut page_results = page_results.into_inner().unwrap();
+    page_results.sort_by_key(|(page_index, _)| *page_index);
+
+    let results = page_results
+        .into_iter()
+        .flat_map(|(_, results)| results);
+
+    // Apply the last writes before reporting, so that repositories in a
+    // failed batch are reported as failed.
+    write_batch.flush();
+
+    for (id, name, (result, notes)) in results {
+        summary.warnings.extend(notes.warnings);
+
+        if notes.transfer.received_bytes > 0 {
+            summary.received += notes.transfer;
+            summary.received_repos += 1;
+        }
+
+        if notes.empty {
+            summary.empty += 1;
+        }
+
+        let result = result.and_then(|outcome|
+            match write_batch.failure(id) {
+                Some(e) => Err(e),
+                None => Ok(outcome),
+            }
+        );
+
+        match result {
+            Ok(outcome) => {
+                if config.dry_run {
+                    summary.plan.push(format!("{}: {}", name, outcome));
+                }
+
+                summary.add(outcome);
+            },
+            Err(e) => summary.errors.push((
+                name.clone(),
+                e.context(name),
+            )),
+        }
+    }
+
+    if let Some(index_path) = &config.generate_index {
+        if config.dry_run {
+            summary.plan.push(
+                format!("write index '{}'", index_path.display()),
+            );
+        } else if let Err(e) = write_index(index_path, &db, &default_settings) {
+            summary.errors.push((index_path.display().to_string(), e));
+        }
+    }
+
+    summary.stopped = shutdown.is_requested();
+
+    // Start the next run from scratch once everything has been processed.
+    if !config.dry_run && !summary.stopped && summary.errors.is_empty() {
+        if let Err(e) = db.run_state_clear() {
+            warn!("unable to clear run state: {}", e);
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Rewrite the cgitrc file of every repository in `config.database` from its
+/// stored metadata, without fetching anything.
+///
+/// Repositories owned by one of `config.users` get that user's base cgitrc.
+/// Returns the names of the repositories whose cgitrc was regenerated, or
+/// would be in a dry run.
+pub fn regenerate_cgitrc(config: &Config) -> Result<Vec<String>, MultiError> {
+    let options = Options::parse(config)?;
+    let settings = options.settings(config)?;
+
+    let db = open_database(config)?;
+
+    regenerate_db_cgitrc(&db, &config.users, &settings)
+}
+
+/// Write a unified diff of the changes `regenerate_cgitrc` would make to the
+/// cgitrc file of every repository in `config.database` to `out`, without
+/// changing anything or fetching.
+///
+/// Repositories whose cgitrc wouldn't change are left out.
+pub fn cgitrc_diff<W: Write>(
+    config: &Config,
+    mut out: W,
+) -> Result<(), MultiError> {
+    let options = Options::parse(config)?;
+    let settings = options.settings(config)?;
+
+    let db = open_database(config)?;
+
+    let db_repos = db.repo_all()
+        .context("unable to read repositories from database")?;
+
+    let mut errors = Vec::new();
+
+    for db_repo in &db_repos {
+        let repo = provider::Repo::from(db_repo);
+        let settings = settings.for_owner(&config.users, &repo);
+
+        let path = settings.repo_path(&repo);
+
+        if !path.exists() {
+            warn!(
+                "{}: skipping, '{}' doesn't exist",
+                repo.name,
+                path.display(),
+            );
+
+            continue;
+        }
+
+        match diff_repo_cgitrc(&path, &repo, &settings) {
+            Ok(Some(diff)) => out.write_all(diff.as_bytes())
+                .context("unable to write diff")?,
+            Ok(None) => (),
+            Err(e) => errors.push(e.context(repo.name.clone())),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else if errors.len() < db_repos.len() {
+        Err(MultiError::partial(errors))
+    } else {
+        Err(MultiError::from(errors))
+    }
+}
+
+/// Find differences between the repositories in `config.database` and the
+/// mirrors in `config.mirror_root` and its fork subdirectory.
+pub fn check(config: &Config) -> Result<Vec<Inconsistency>, MultiError> {
+    let options = Options::parse(config)?;
+    let settings = options.settings(config)?;
+
+    let db = open_database(config)?;
+
+    let db_repos = db.repo_all()
+        .context("unable to read repositories from database")?;
+
+    let mut inconsistencies = Vec::new();
+    let mut tracked = HashSet::new();
+
+    for db_repo in &db_repos {
+        let mut repo = provider::Repo::from(db_repo);
+        let settings = settings.for_owner(&config.users, &repo);
+
+        let mut paths = vec![settings.repo_path(&repo)];
+
+        // Rows stored before forks were recorded don't say whether the
+        // repository is one, so its mirror could be in either directory.
+        if db_repo.fork.is_none() {
+            repo.fork = true;
+            paths.push(settings.repo_path(&repo));
+        }
+
+        let (existing, missing): (Vec<_>, Vec<_>) = paths
+            .into_iter()
+            .partition(|path| path.exists());
+
+        let paths = if existing.is_empty() {
+            inconsistencies.push(Inconsistency::MissingMirror {
+                id: repo.id,
+                name: repo.name,
+                path: missing[0].clone(),
+            });
+
+            missing
+        } else {
+            existing
+        };
+
+        for path in paths {
+            // Wikis are mirrored next to their repositories but not stored.
+            tracked.insert(wiki_path(&path));
+            tracked.insert(path);
+        }
+    }
+
+    let mut mirror_dirs = vec![PathBuf::from(settings.mirror_root)];
+
+    if !settings.fork_subdir.is_empty() {
+        mirror_dirs.push(
+            Path::new(settings.mirror_root).join(settings.fork_subdir)
+        );
+    }
+
+    for dir in mirror_dirs {
+        if !dir.exists() {
+            continue;
+        }
+
+        let entries = fs::read_dir(&dir)
+            .with_context(|| format!(
+                "unable to read directory '{}'",
+                dir.display(),
+            ))?;
+
+        let mut untracked = Vec::new();
+
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!(
+                    "unable to read directory '{}'",
+                    dir.display(),
+                ))?
+                .path();
+
+            if path.is_dir()
+                && path.extension().is_some_and(|ext| ext == "git")
+                && !tracked.contains(&path)
+            {
+                untracked.push(path);
+            }
+        }
+
+        untracked.sort();
+
+        inconsistencies.extend(
+            untracked.into_iter().map(Inconsistency::UntrackedMirror)
+        );
+    }
+
+    Ok(inconsistencies)
+}
+
+/// Get the size on disk in bytes of each mirror in `config.database`, largest
+/// first.
+///
+/// Sizes are measured when repositories are mirrored or updated, so
+/// repositories that haven't been since the size was first recorded are left
+/// out.
+pub fn disk_sizes(config: &Config) -> Result<Vec<(String, u64)>, MultiError> {
+    let db = open_database(config)?;
+
+    let mut sizes: Vec<_> = db.repo_all()
+        .context("unable to read repositories from database")?
+        .into_iter()
+        .filter_map(|repo|
+            repo.disk_size.map(|size|
+                (repo.name().unwrap_or_default().to_owned(), size)
+            )
+        )
+        .collect();
+
+    sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    Ok(sizes)
+}
+
+/// Remove database rows for repositories whose mirrors are missing, so they're
+/// mirrored again on the next run.
+///
+/// Untracked mirrors are left alone, since they might not have been created by
+/// Reflectub. Returns the number of inconsistencies fixed, which is zero in a
+/// dry run.
+pub fn fix(
+    config: &Config,
+    inconsistencies: &[Inconsistency],
+) -> Result<usize, MultiError> {
+    if config.dry_run {
+        return Ok(0);
+    }
+
+    let db = open_database(config)?;
+
+    let mut fixed = 0;
+
+    for inconsistency in inconsistencies {
+        if let Inconsistency::MissingMirror { id, name, .. } = inconsistency {
+            db.repo_delete(*id)
+                .with_context(|| format!(
+                    "{}: unable to remove from database",
+                    name,
+                ))?;
+
+            fixed += 1;
+        }
+    }
+
+    Ok(fixed)
+}
+
+/// A difference between the database and the mirrors on disk.
+#[derive(Debug)]
+pub enum Inconsistency {
+    /// A repository in the database has no mirror.
+    MissingMirror {
+        id: i64,
+        name: String,
+        path: PathBuf,
+    },
+
+    /// A mirror has no repository in the database.
+    UntrackedMirror(PathBuf),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::MissingMirror { name, path, .. } => write!(
+                f,
+                "{}: mirror '{}' doesn't exist",
+                name,
+                path.display(),
+            ),
+            Inconsistency::UntrackedMirror(path) => write!(
+                f,
+                "'{}' isn't in the database",
+                path.display(),
+            ),
+        }
+    }
+}
+
+/// Connect to `config.database` and create or migrate its tables.
+///
+/// In a dry run, the database is opened read-only instead, or kept in memory
+/// if it doesn't exist yet, so that nothing is written to disk.
+fn open_database(config: &Config) -> anyhow::Result<database::Db> {
+    let database_file = config.database
+        .as_ref()
+        .ok_or(anyhow::anyhow!("no database file given"))?;
+
+    if config.db_pool_size == Some(0) {
+        anyhow::bail!("database pool size must be greater than 0");
+    }
+
+    if config.dry_run {
+        return open_database_read_only(database_file, config.db_pool_size);
+    }
+
+    let db = database::Db::connect(database_file, config.db_pool_size)
+        .context("unable to connect to database")?;
+
+    db.create()
+        .context("unable to create database")?;
+
+    Ok(db)
+}
+
+/// Open `database_file` read-only, or an empty database in memory if it
+/// doesn't exist.
+fn open_database_read_only(
+    database_file: &str,
+    pool_size: Option<u32>,
+) -> anyhow::Result<database::Db> {
+    if !Path::new(database_file).exists() {
+        let db = database::Db::connect_in_memory()
+            .context("unable to create in-memory database")?;
+
+        db.create()
+            .context("unable to create in-memory database")?;
+
+        return Ok(db);
+    }
+
+    let db = database::Db::connect_read_only(database_file, pool_size)
+        .context("unable to connect to database")?;
+
+    let needs_create = db.needs_create()
+        .context("unable to read database version")?;
+
+    if needs_create {
+        anyhow::bail!(
+            "database '{}' needs to be upgraded; run without '--dry-run' \
+            first",
+            database_file,
+        );
+    }
+
+    Ok(db)
+}
+
+/// Values parsed from a `Config` that `Settings` borrow.
+struct Options {
+    cgitrc_template: Option<String>,
+    section: Option<Section>,
+    max_repo_size_bytes: Option<u64>,
+    oversize_mode: OversizeMode,
+    private_mode: PrivateMode,
+    overrides: HashMap<String, config::RepoOverride>,
+    fetch_timeout: Option<Duration>,
+    throttle: Option<Duration>,
+    http_timeout: Duration,
+    asset_agent: Option<ureq::Agent>,
+    max_asset_size_bytes: Option<u64>,
+    cgitrc_mode: Option<u32>,
+    git_config: Vec<(String, String)>,
+    order: github::Order,
+}
+
+impl Options {
+    /// Parse and check the options in `config`.
+    fn parse(config: &Config) -> anyhow::Result<Self> {
+        // Parse the maximum repo size and return an error if it fails. If the
+        // size is `None`, set the value to `None`.
+        let max_repo_size_bytes = config.skip_larger_than
+            .as_ref()
+            .map_or(
+                Ok(None),
+                |s|
+                    parse_size(s)
+                        .map(Some)
+                        .with_context(|| format!(
+                            "unable to parse max file size '{}'",
+                            s
+                        ))
+            )?;
+
+        let fetch_timeout = config.fetch_timeout
+            .as_ref()
+            .map(|s|
+                humantime::parse_duration(s)
+                    .with_context(|| format!(
+                        "unable to parse fetch timeout '{}'",
+                        s
+                    ))
+            )
+            .transpose()?;
+
+        let throttle = config.throttle
+            .as_ref()
+            .map(|s|
+                humantime::parse_duration(s)
+                    .with_context(|| format!(
+                        "unable to parse throttle '{}'",
+                        s
+                    ))
+            )
+            .transpose()?;
+
+        let http_timeout = config.http_timeout
+            .as_ref()
+            .map(|s|
+                humantime::parse_duration(s)
+                    .with_context(|| format!(
+                        "unable to parse HTTP timeout '{}'",
+                        s
+                    ))
+            )
+            .transpose()?
+            .unwrap_or(provider::DEFAULT_HTTP_TIMEOUT);
+
+        if config.mirror_release_assets && !config.mirror_releases {
+            anyhow::bail!(
+                "'--mirror-release-assets' requires '--mirror-releases'"
+            );
+        }
+
+        let asset_agent = if config.mirror_release_assets {
+            Some(
+                provider::new_agent(config.proxy.as_deref(), http_timeout)
+                    .map_err(anyhow::Error::new)?
+            )
+        } else {
+            None
+        };
+
+        let max_asset_size_bytes = config.skip_assets_larger_than
+            .as_ref()
+            .map(|s|
+                parse_size(s)
+                    .with_context(|| format!(
+                        "unable to parse max asset size '{}'",
+                        s
+                    ))
+            )
+            .transpose()?;
+
+        let cgitrc_mode = config.cgitrc_mode
+            .as_deref()
+            .map(parse_mode)
+            .transpose()?;
+
+        let order = github::Order {
+            sort: config.sort
+                .as_deref()
+                .map_or(Ok(github::Sort::default()), str::parse)?,
+            direction: config.direction
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+        };
+
+        let is_github = config.provider.as_deref().unwrap_or("github")
+            == "github";
+
+        if !is_github && (config.sort.is_some() || config.direction.is_some()) {
+            anyhow::bail!("'--sort' and '--direction' only apply to GitHub");
+        }
+
+        if !config.remirror_repos.is_empty() && !config.force_remirror {
+            anyhow::bail!("'--repo' only applies with '--force-remirror'");
+        }
+
+        if config.force_remirror
+            && config.remirror_repos.is_empty()
+            && !config.force
+        {
+            anyhow::bail!(
+                "'--force-remirror' without '--repo' deletes and mirrors \
+                every repository again; add '--force' to confirm"
+            );
+        }
+
+        if config.db_batch_size == Some(0) {
+            anyhow::bail!("database batch size must be greater than 0");
+        }
+
+        let oversize_mode = config.skip_larger_than_mode
+            .as_deref()
+            .map_or(Ok(OversizeMode::Skip), str::parse)?;
+
+        let private_mode = config.private_mode
+            .as_deref()
+            .map_or(Ok(PrivateMode::Show), str::parse)?;
+
+        let section = match (&config.section_by, &config.section) {
+            (Some(_), Some(_)) => anyhow::bail!(
+                "'--section-by' and '--section' can't be combined"
+            ),
+            (Some(section_by), None) => Some(section_by.parse::<Section>()?),
+            (None, Some(section)) => Some(Section::Fixed(section.clone())),
+            (None, None) => None,
+        };
+
+        if config.cgitrc.is_some() && config.cgitrc_template.is_some() {
+            anyhow::bail!(
+                "'--cgitrc' and '--cgitrc-template' can't be combined"
+            );
+        }
+
+        let overrides = match &config.mirror_root {
+            Some(mirror_root) => config::load_overrides(
+                Path::new(mirror_root).join(OVERRIDES_FILE),
+            )?,
+            None => HashMap::new(),
+        };
+
+        let override_lines = overrides
+            .values()
+            .flat_map(|o| &o.cgitrc_lines);
+
+        for line in config.cgitrc_lines.iter().chain(override_lines) {
+            if !line.contains('=') || line.contains('\n') {
+                anyhow::bail!(
+                    "invalid cgitrc line '{}', expected 'key=value'",
+                    line,
+                );
+            }
+        }
+
+        for header in &config.git_headers {
+            let valid = match header.split_once(':') {
+                Some((name, _)) => !name.trim().is_empty(),
+                None => false,
+            };
+
+            if !valid || header.contains(['\r', '\n']) {
+                anyhow::bail!(
+                    "invalid Git header '{}', expected 'Name: value'",
+                    header,
+                );
+            }
+        }
+
+        let git_config = config.git_config
+            .iter()
+            .map(|value| parse_git_config(value))
+            .collect::<anyhow::Result<_>>()?;
+
+        let cgitrc_template = config.cgitrc_template
+            .as_ref()
+            .map(|path|
+                fs::read_to_string(path)
+                    .with_context(|| format!(
+                        "unable to read cgitrc template '{}'",
+                        path.display(),
+                    ))
+            )
+            .transpose()?;
+
+        Ok(
+            Options {
+                cgitrc_template,
+                section,
+                max_repo_size_bytes,
+                oversize_mode,
+                private_mode,
+                overrides,
+                fetch_timeout,
+                throttle,
+                http_timeout,
+                asset_agent,
+                max_asset_size_bytes,
+                cgitrc_mode,
+                git_config,
+                order,
+            }
+        )
+    }
+
+    /// Get the settings for repositories that aren't owned by a user with
+    /// their own base cgitrc.
+    fn settings<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> anyhow::Result<Settings<'a>> {
+        let mirror_root = config.mirror_root
+            .as_deref()
+            .ok_or(anyhow::anyhow!("no repository path given"))?;
+
+        Ok(
+            Settings {
+                mirror_root,
+                fork_subdir: config.fork_subdir.as_deref().unwrap_or("fork"),
+                base_cgitrc: match &self.cgitrc_template {
+                    Some(template) => Some(cgitrc::Base::Template(template)),
+                    None => config.cgitrc
+                        .as_deref()
+                        .map(cgitrc::Base::Copy),
+                },
+                owner_cgitrcs: &config.cgitrc_for,
+                cgitrc_lines: &config.cgitrc_lines,
+                cgitrc_mode: self.cgitrc_mode,
+                max_repo_size_bytes: self.max_repo_size_bytes,
+                oversize_mode: self.oversize_mode,
+                private_mode: self.private_mode,
+                overrides: &self.overrides,
+                mirror_unknown_size: config.mirror_unknown_size,
+                section: self.section.as_ref(),
+                update_cgitrc: config.update_cgitrc,
+                remote_name: config.remote_name.as_deref().unwrap_or("origin"),
+                clone_url_template: config.clone_url_template.as_deref(),
+                fetch_settings: git::FetchSettings {
+                    retries: config.retries.unwrap_or(0),
+                    timeout: self.fetch_timeout,
+                    proxy: config.proxy.clone(),
+                    throttle: self.throttle,
+                    headers: config.git_headers.clone(),
+                    git_config: self.git_config.clone(),
+                    trace: config.git_trace,
+                    no_tags: config.no_tags,
+                    cancel: Arc::new(AtomicBool::new(false)),
+                },
+                heal: config.heal,
+                mirror_wikis: config.mirror_wikis,
+                mirror_releases: config.mirror_releases,
+                asset_agent: self.asset_agent.as_ref(),
+                max_asset_size_bytes: self.max_asset_size_bytes,
+                bundle_dir: config.bundle_dir.as_deref(),
+                force_remirror: config.force_remirror,
+                remirror_repos: &config.remirror_repos,
+                write_metadata_json: config.write_metadata_json,
+                always_agefile: config.always_agefile,
+                dry_run: config.dry_run,
+                shutdown: Shutdown::new(),
+            }
+        )
+    }
+}
+
+/// Compile a list of glob patterns.
+fn parse_patterns(patterns: &[String]) -> anyhow::Result<Vec<glob::Pattern>> {
+    patterns
+        .iter()
+        .map(|p|
+            glob::Pattern::new(p)
+                .with_context(|| format!("invalid pattern '{}'", p))
+        )
+        .collect()
+}
+
+/// Read the repository names in the exclude list at `path`, and get exact
+/// patterns for them.
+///
+/// The file has one name per line. Blank lines and lines starting with "#" are
+/// ignored.
+fn read_exclude_file(path: &Path) -> anyhow::Result<Vec<glob::Pattern>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!(
+            "unable to read exclude file '{}'",
+            path.display(),
+        ))?;
+
+    pattern_lines(&contents)
+        .map(|name|
+            glob::Pattern::new(&glob::Pattern::escape(name))
+                .with_context(|| format!("invalid repository name '{}'", name))
+        )
+        .collect()
+}
+
+/// Read the glob patterns in the ignore file at `path`, like
+/// `mirror_root/.reflectubignore`.
+///
+/// The file has the same format as an exclude file, but its lines are
+/// patterns rather than exact names. A missing file has no patterns.
+fn read_ignore_file(path: &Path) -> anyhow::Result<Vec<glob::Pattern>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!(
+            "unable to read ignore file '{}'",
+            path.display(),
+        )),
+    };
+
+    pattern_lines(&contents)
+        .map(|pattern|
+            glob::Pattern::new(pattern)
+                .with_context(|| format!(
+                    "invalid pattern '{}' in '{}'",
+                    pattern,
+                    path.display(),
+                ))
+        )
+        .collect()
+}
+
+/// Get the non-blank lines of `contents` that don't start with "#", trimmed.
+fn pattern_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Remove repositories whose IDs are in `seen` or earlier in `repos` from
+/// `repos`, keeping the entry with the most recent `pushed_at` time, and add
+/// the rest to `seen`.
+///
+/// Repositories in `seen` may already be processing, so those whose entry in
+/// `repos` was pushed to more recently are returned separately, to be
+/// processed once the rest are finished.
+fn dedup_repos(
+    repos: Vec<provider::Repo>,
+    seen: &mut HashMap<i64, DateTime<FixedOffset>>,
+) -> (Vec<provider::Repo>, Vec<provider::Repo>) {
+    let mut deduped: Vec<provider::Repo> = Vec::with_capacity(repos.len());
+    let mut indices: HashMap<i64, usize> = HashMap::new();
+    let mut newer = Vec::new();
+
+    for repo in repos {
+        if let Some(&i) = indices.get(&repo.id) {
+            debug!("{}: skipping duplicate repository", repo.name);
+
+            if repo.pushed_at > deduped[i].pushed_at {
+                seen.insert(repo.id, repo.pushed_at);
+                deduped[i] = repo;
+            }
+
+            continue;
+        }
+
+        match seen.get(&repo.id) {
+            Some(&pushed_at) if repo.pushed_at > pushed_at => {
+                debug!(
+                    "{}: duplicate repository was pushed to more recently, \
+                    processing it again",
+                    repo.name,
+                );
+
+                seen.insert(repo.id, repo.pushed_at);
+                newer.push(repo);
+            },
+            Some(_) => {
+                debug!("{}: skipping duplicate repository", repo.name);
+            },
+            None => {
+                seen.insert(repo.id, repo.pushed_at);
+                indices.insert(repo.id, deduped.len());
+                deduped.push(repo);
+            },
+        }
+    }
+
+    (deduped, newer)
+}
+
+/// Filter `repos` by name.
+///
+/// If `include` is non-empty, only repositories matching one of its patterns
+/// are kept. Repositories matching any pattern in `exclude` are then removed.
+fn filter_repos(
+    repos: Vec<provider::Repo>,
+    include: &[glob::Pattern],
+    exclude: &[glob::Pattern],
+) -> Vec<provider::Repo> {
+    repos
+        .into_iter()
+        .filter(|repo| {
+            include.is_empty()
+                || include.iter().any(|p| p.matches(&repo.name))
+        })
+        .filter(|repo| !exclude.iter().any(|p| p.matches(&repo.name)))
+        .collect()
+}
+
+/// Parse a time given as either a duration before `now`, like "30days", or a
+/// date or RFC 3339 timestamp, like "2021-06-01" or "2021-06-01T12:00:00Z".
+///
+/// Dates are taken as midnight UTC.
+fn parse_since(
+    s: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<DateTime<FixedOffset>> {
+    if let Ok(duration) = humantime::parse_duration(s) {
+        let duration = chrono::Duration::from_std(duration)
+            .with_context(|| format!("duration '{}' is too long", s))?;
+
+        return Ok((now - duration).into());
+    }
+
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(s) {
+        return Ok(timestamp);
+    }
+
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .with_context(|| format!(
+            "unable to parse '{}' as a duration or date",
+            s,
+        ))?;
+
+    Ok(DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc).into())
+}
+
+/// Parse octal permission bits, like "0644" or "644".
+fn parse_mode(s: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .with_context(|| format!("unable to parse mode '{}' as octal", s))?;
+
+    if mode > 0o7777 {
+        anyhow::bail!("mode '{}' is out of range", s);
+    }
+
+    Ok(mode)
+}
+
+/// Parse a Git config value given as "key=value", like "gc.auto=0".
+///
+/// The key needs a section and a name separated by a dot.
+fn parse_git_config(s: &str) -> anyhow::Result<(String, String)> {
+    let valid = |key: &str| match key.split_once('.') {
+        Some((section, name)) =>
+            !section.is_empty()
+                && !name.is_empty()
+                && !key.contains(char::is_whitespace),
+        None => false,
+    };
+
+    match s.split_once('=') {
+        Some((key, value)) if valid(key) && !value.contains('\n') =>
+            Ok((key.to_owned(), value.to_owned())),
+        _ => anyhow::bail!(
+            "invalid Git config '{}', expected 'section.key=value'",
+            s,
+        ),
+    }
+}
+
+/// Get the provider called `name`.
+///
+/// GitHub defaults to the public API and GitLab to "https://gitlab.com" if
+/// `base_url` is `None`. Gitea requires a `base_url`. API requests give up
+/// after `http_timeout`. Only GitHub uses `order`, and `github_token`, with
+/// which it lists repositories through the GraphQL API.
+fn new_provider(
+    name: &str,
+    base_url: Option<&str>,
+    proxy: Option<&str>,
+    http_timeout: Duration,
+    order: github::Order,
+    github_token: Option<&str>,
+) -> anyhow::Result<Box<dyn Provider>> {
+    let agent = provider::new_agent(proxy, http_timeout)
+        .map_err(anyhow::Error::new)?;
+
+    match (name, base_url) {
+        ("github", base_url) => Ok(Box::new(github::Github {
+            base_url: base_url
+                .unwrap_or(github::API_BASE_URL)
+                .to_owned(),
+            agent,
+            order,
+            token: github_token.map(str::to_owned),
+        })),
+        ("gitlab", base_url) => Ok(Box::new(gitlab::Gitlab {
+            base_url: base_url
+                .unwrap_or("https://gitlab.com")
+                .to_owned(),
+            agent,
+        })),
+        ("gitea", Some(base_url)) => Ok(Box::new(gitea::Gitea {
+            base_url: base_url.to_owned(),
+            agent,
+        })),
+        ("gitea", None) => Err(anyhow::anyhow!(
+            "missing required argument '--api-base-url' for Gitea",
+        )),
+        _ => Err(anyhow::anyhow!(
+            "invalid provider '{}', expected 'github', 'gitlab', or 'gitea'",
+            name,
+        )),
+    }
+}
+
+/// Options applied to each repository when mirroring a user's repositories.
+#[derive(Debug, Clone)]
+struct Settings<'a> {
+    mirror_root: &'a str,
+
+    /// Subdirectory of `mirror_root` for forks, or empty to not nest them.
+    fork_subdir: &'a str,
+    base_cgitrc: Option<cgitrc::Base<'a>>,
+
+    /// Base cgitrc files for repositories owned by particular users, which
+    /// take precedence over `base_cgitrc`.
+    owner_cgitrcs: &'a HashMap<String, PathBuf>,
+
+    /// Lines added to every repository's cgitrc.
+    cgitrc_lines: &'a [String],
+
+    /// Permission bits for cgitrc files and the index.
+    cgitrc_mode: Option<u32>,
+    max_repo_size_bytes: Option<u64>,
+    oversize_mode: OversizeMode,
+    private_mode: PrivateMode,
+
+    /// Settings for individual repositories, by name.
+    overrides: &'a HashMap<String, config::RepoOverride>,
+
+    /// Mirror repositories whose size is unknown despite a maximum size.
+    mirror_unknown_size: bool,
+    section: Option<&'a Section>,
+
+    /// Regenerate the cgitrc files of existing mirrors.
+    update_cgitrc: bool,
+
+    /// Name of the remote added to new mirrors.
+    remote_name: &'a str,
+
+    /// Template for the URL new mirrors fetch from, with `{owner}`, `{name}`,
+    /// and `{url}` placeholders.
+    clone_url_template: Option<&'a str>,
+
+    /// Retries, timeout, and proxy for fetches.
+    fetch_settings: git::FetchSettings,
+
+    /// Verify mirrors after fetching, and re-mirror corrupt ones.
+    heal: bool,
+
+    /// Also mirror the wikis of repositories that have them.
+    mirror_wikis: bool,
+
+    /// Store the releases of each repository in the database.
+    mirror_releases: bool,
+
+    /// Download release assets with this agent.
+    asset_agent: Option<&'a ureq::Agent>,
+
+    /// Skip release assets larger than this.
+    max_asset_size_bytes: Option<u64>,
+
+    /// Directory to write a bundle of each mirror into.
+    bundle_dir: Option<&'a Path>,
+
+    /// Delete existing mirrors and mirror them again.
+    force_remirror: bool,
+
+    /// Repositories `force_remirror` applies to, or all if empty.
+    remirror_repos: &'a [String],
+
+    /// Write each mirror's metadata to a JSON file inside it.
+    write_metadata_json: bool,
+
+    /// Write a CGit agefile in every mirror, not only ones without ref files.
+    always_agefile: bool,
+
+    /// Don't modify mirrors or the database.
+    dry_run: bool,
+
+    /// Stops starting new repositories once requested.
+    shutdown: Shutdown,
+}
+
+impl<'a> Settings<'a> {
+    /// Get the settings for `user`'s repositories, which use the user's base
+    /// cgitrc if they have one.
+    fn for_user(&self, user: &'a config::User) -> Self {
+        Settings {
+            base_cgitrc: user.cgitrc
+                .as_deref()
+                .map(cgitrc::Base::Copy)
+                .or(self.base_cgitrc),
+            ..self.clone()
+        }
+    }
+
+    /// Get the settings for `repo`, which are `for_user` if it's owned by one
+    /// of `users`.
+    fn for_owner(
+        &self,
+        users: &'a [config::User],
+        repo: &provider::Repo,
+    ) -> Self {
+        match users
+            .iter()
+            .find(|user| user.name.eq_ignore_ascii_case(&repo.owner))
+        {
+            Some(user) => self.for_user(user),
+            None => self.clone(),
+        }
+    }
+
+    /// Get the base cgitrc for `repo`, the one for its owner if there is one.
+    fn base_cgitrc(&self, repo: &provider::Repo) -> Option<cgitrc::Base<'a>> {
+        match self.owner_cgitrcs.get(&repo.owner) {
+            Some(path) => Some(cgitrc::Base::Copy(path)),
+            None => self.base_cgitrc,
+        }
+    }
+
+    /// Get `repo`'s CGit section, from its override or the configured
+    /// grouping.
+    fn section_name<'b>(&'b self, repo: &'b provider::Repo) -> Option<&'b str> {
+        match self.overrides.get(&repo.name) {
+            Some(config::RepoOverride { section: Some(section), .. }) =>
+                Some(section),
+            _ => self.section.and_then(|section| section.name(repo)),
+        }
+    }
+
+    /// Check whether `repo` should be deleted and mirrored again.
+    fn is_remirrored(&self, repo: &provider::Repo) -> bool {
+        self.force_remirror
+            && (
+                self.remirror_repos.is_empty()
+                    || self.remirror_repos.contains(&repo.name)
+            )
+    }
+
+    /// Get the path of `repo`'s mirror.
+    fn repo_path(&self, repo: &provider::Repo) -> PathBuf {
+        clone_path(self.mirror_root, self.fork_subdir, repo)
+    }
+
+    /// Get the options for creating a mirror of `repo`.
+    fn mirror_options(&self, repo: &provider::Repo) -> git::MirrorOptions {
+        let url = match self.clone_url_template {
+            Some(template) => template
+                .replace("{owner}", &repo.owner)
+                .replace("{name}", &repo.name)
+                .replace("{url}", &repo.clone_url),
+            None => repo.clone_url.clone(),
+        };
+
+        git::MirrorOptions {
+            url,
+            remote_name: self.remote_name.to_owned(),
+            description: repo.description().to_owned(),
+            default_branch: repo.default_branch.clone(),
+            fetch_settings: self.fetch_settings.clone(),
+        }
+    }
+
+    /// Return `true` if `repo` is larger than the maximum repository size.
+    fn is_oversize(&self, repo: &provider::Repo) -> bool {
+        self.max_repo_size_bytes
+            .is_some_and(|max| is_repo_oversize(repo.size, max))
+    }
+}
+
+/// What to do with repositories larger than the maximum size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OversizeMode {
+    /// Don't mirror the repository at all.
+    Skip,
+
+    /// Create the repository with its description and cgitrc so it's listed
+    /// in CGit, but don't fetch any objects.
+    Metadata,
+}
+
+impl str::FromStr for OversizeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OversizeMode::Skip),
+            "metadata" => Ok(OversizeMode::Metadata),
+            _ => Err(anyhow::anyhow!(
+                "invalid oversize mode '{}', expected 'skip' or 'metadata'",
+                s,
+            )),
+        }
+    }
+}
+
+/// What to do with private repositories.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PrivateMode {
+    /// Don't mirror them.
+    Skip,
+
+    /// Mirror them, but leave them out of CGit's index page.
+    Hide,
+
+    /// Mirror them like public repositories.
+    Show,
+}
+
+impl str::FromStr for PrivateMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(PrivateMode::Skip),
+            "hide" => Ok(PrivateMode::Hide),
+            "show" => Ok(PrivateMode::Show),
+            _ => Err(anyhow::anyhow!(
+                "invalid private mode '{}', expected 'skip', 'hide', or \
+                'show'",
+                s,
+            )),
+        }
+    }
+}
+
+/// How to choose a repository's CGit section.
+#[derive(Debug)]
+enum Section {
+    Owner,
+    Language,
+    Fork,
+    Fixed(String),
+}
+
+impl str::FromStr for Section {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Section::Owner),
+            "language" => Ok(Section::Language),
+            "fork" => Ok(Section::Fork),
+            _ => Err(anyhow::anyhow!(
+                "invalid section grouping '{}', expected 'owner', 'language', or 'fork'",
+                s,
+            )),
+        }
+    }
+}
+
+impl Section {
+    /// Get the section name for `repo`, or `None` if it doesn't have one.
+    fn name<'a>(&'a self, repo: &'a provider::Repo) -> Option<&'a str> {
+        match self {
+            Section::Owner => Some(&repo.owner),
+            Section::Language => repo.language.as_deref(),
+            Section::Fork =>
+                if repo.fork {
+                    Some("forks")
+                } else {
+                    Some("sources")
+                },
+            Section::Fixed(name) => Some(name),
+        }
+    }
+}
+
+/// The action taken on a repository.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Mirrored,
+    Updated,
+    Unchanged,
+    SkippedSize,
+    Interrupted,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Mirrored => write!(f, "mirror"),
+            Outcome::Updated => write!(f, "update"),
+            Outcome::Unchanged => write!(f, "up to date"),
+            Outcome::SkippedSize => write!(f, "skip (too large)"),
+            Outcome::Interrupted => write!(f, "not processed (interrupted)"),
+        }
+    }
+}
+
+/// What happened while processing a repository, apart from its outcome.
+#[derive(Debug, Default)]
+struct Notes {
+    warnings: Vec<Warning>,
+
+    /// Data received by fetches.
+    transfer: git::TransferStats,
+
+    /// The repository's mirror has no commits yet.
+    empty: bool,
+}
+
+/// A problem with a repository that doesn't fail it, like a bundle that
+/// couldn't be written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub repo: String,
+    pub message: String,
+}
+
+impl Warning {
+    fn new(repo: &str, message: String) -> Self {
+        Warning {
+            repo: repo.to_owned(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.repo, self.message)
+    }
+}
+
+/// Counts of repository outcomes over a run, and the errors that occurred.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub mirrored: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub skipped_size: usize,
+    pub interrupted: usize,
+
+    /// Forks that weren't mirrored because `include_forks` wasn't set.
+    pub skipped_forks: usize,
+
+    /// Failures paired with the name of the repository, user, or file they
+    /// concern.
+    pub errors: Vec<(String, anyhow::Error)>,
+
+    /// Problems that didn't fail their repositories. They don't affect
+    /// `into_result`.
+    pub warnings: Vec<Warning>,
+
+    /// Data received by fetches, including those of wikis.
+    pub received: git::TransferStats,
+
+    /// The number of repositories that received any data.
+    pub received_repos: usize,
+
+    /// Mirrored or updated repositories that don't have any commits yet.
+    pub empty: usize,
+
+    /// The run was stopped by a `Shutdown` request before it finished.
+    pub stopped: bool,
+
+    /// In a dry run, the actions that would have been taken, one per line.
+    pub plan: Vec<String>,
+}
+
+impl Summary {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Mirrored => self.mirrored += 1,
+            Outcome::Updated => self.updated += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::SkippedSize => self.skipped_size += 1,
+            Outcome::Interrupted => self.interrupted += 1,
+        }
+    }
+
+    /// The number of repositories that were processed without error.
+    pub fn succeeded(&self) -> usize {
+        self.mirrored + self.updated + self.unchanged + self.skipped_size
+    }
+
+    /// Collect the run's errors into a `MultiError`, marked partial if any
+    /// repositories succeeded. A stopped run counts as an error.
+    pub fn into_result(self) -> Result<(), MultiError> {
+        let succeeded = self.succeeded();
+
+        let mut errors: Vec<_> = self.errors
+            .into_iter()
+            .map(|(_, e)| e)
+            .collect();
+
+        if self.stopped {
+            errors.push(anyhow::anyhow!(
+                "interrupted, {} repositories were not processed",
+                self.interrupted,
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if succeeded > 0 {
+            Err(MultiError::partial(errors))
+        } else {
+            Err(MultiError::from(errors))
+        }
+    }
+}
+
+/// Counts of the repositories queued and processed in a run, shared between
+/// the threads mirroring them.
+#[derive(Debug, Default)]
+struct Progress {
+    total: AtomicUsize,
+    processed: AtomicUsize,
+}
+
+impl Progress {
+    /// Log the number of processed repositories every `interval` until
+    /// `stop` is disconnected. Nothing is logged while the counts stay the
+    /// same.
+    fn report_every(&self, interval: Duration, stop: &mpsc::Receiver<()>) {
+        let mut last = (0, 0);
+
+        while let Err(mpsc::RecvTimeoutError::Timeout) =
+            stop.recv_timeout(interval)
+        {
+            let counts = (
+                self.processed.load(Ordering::SeqCst),
+                self.total.load(Ordering::SeqCst),
+            );
+
+            if counts != last {
+                info!("processed {}/{} repositories", counts.0, counts.1);
+
+                last = counts;
+            }
+        }
+    }
+}
+
+/// A database write for a processed repository, applied by `WriteBatch`.
+#[derive(Debug)]
+struct PendingWrite {
+    name: String,
+    write: database::RepoWrite,
+
+    /// A mirror created for an inserted repository. It's removed if the insert
+    /// fails so the next run can start from scratch.
+    new_mirror: Option<PathBuf>,
+}
+
+/// Collects database writes and run state from repositories processed in
+/// parallel, and applies them `size` repositories at a time in a single
+/// transaction.
+///
+/// A repository is only recorded as done in the same transaction as its row,
+/// so a resumed run never skips a repository whose row wasn't saved.
+#[derive(Debug)]
+struct WriteBatch<'a> {
+    db: &'a database::Db,
+    size: usize,
+    pending: Mutex<Vec<(i64, database::RunStatus, Option<PendingWrite>)>>,
+
+    /// Messages of failed batches, by the ID of each repository in them.
+    failed: Mutex<HashMap<i64, String>>,
+}
+
+impl<'a> WriteBatch<'a> {
+    fn new(db: &'a database::Db, size: usize) -> Self {
+        WriteBatch {
+            db,
+            size,
+            pending: Mutex::new(Vec::with_capacity(size)),
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Queue the run state of repository `id` with its `write`, and apply the
+    /// queue if it's full.
+    fn add(
+        &self,
+        id: i64,
+        status: database::RunStatus,
+        write: Option<PendingWrite>,
+    ) {
+        let mut pending = self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        pending.push((id, status, write));
+
+        if pending.len() < self.size {
+            return;
+        }
+
+        self.apply(mem::take(&mut *pending));
+    }
+
+    /// Apply any queued writes.
+    fn flush(&self) {
+        let pending = mem::take(
+            &mut *self.pending
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+        );
+
+        if !pending.is_empty() {
+            self.apply(pending);
+        }
+    }
+
+    /// Get the error of the batch that repository `id` was in, if it failed.
+    fn failure(&self, id: i64) -> Option<anyhow::Error> {
+        self.failed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&id)
+            .map(|message|
+                anyhow::anyhow!("{}", message)
+                    .context("unable to save to database")
+            )
+    }
+
+    fn apply(
+        &self,
+        pending: Vec<(i64, database::RunStatus, Option<PendingWrite>)>,
+    ) {
+        let mut ids = Vec::with_capacity(pending.len());
+        let mut run_state = Vec::with_capacity(pending.len());
+        let mut writes = Vec::with_capacity(pending.len());
+        let mut new_mirrors = Vec::new();
+
+        for (id, status, pending_write) in pending {
+            ids.push(id);
+            run_state.push((id, status));
+
+            if let Some(pending_write) = pending_write {
+                if let Some(path) = pending_write.new_mirror {
+                    new_mirrors.push((pending_write.name, path));
+                }
+
+                writes.push(pending_write.write);
+            }
+        }
+
+        if let Err(e) = self.db.repo_write_all(&writes, &run_state) {
+            for (name, path) in new_mirrors {
+                remove_partial_mirror(&name, &path);
+            }
+
+            // Every repository in the batch is reported, since none of them
+            // were saved.
+            let message = format!("{:#}", anyhow::Error::new(e));
+
+            self.failed
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend(ids.into_iter().map(|id| (id, message.clone())));
+        }
+    }
+}
+
+/// Process `repo` as part of a `mirror_user` run.
+///
+/// Writes are added to `write_batch`, along with the repository's status for
+/// the run state. Once `settings.shutdown` is requested, the repository is
+/// skipped.
+///
+/// Notes are returned whether or not the repository failed.
+fn run_repo(
+    repo: &provider::Repo,
+    db: &database::Db,
+    provider: &dyn Provider,
+    settings: &Settings,
+    write_batch: &WriteBatch,
+) -> (anyhow::Result<Outcome>, Notes) {
+    let mut notes = Notes::default();
+
+    // Don't start any new repositories after a signal.
+    if settings.shutdown.is_requested() {
+        return (Ok(Outcome::Interrupted), notes);
+    }
+
+    debug!("{}: start", repo.name);
+    let start = Instant::now();
+
+    let (result, write) = match process_repo(repo, db, settings, &mut notes) {
+        Ok((outcome, write)) => (Ok(outcome), write),
+        Err(e) => (Err(e), None),
+    };
+
+    // Wikis change independently of their repositories, so they're fetched
+    // even when the repository is up to date.
+    if settings.mirror_wikis && repo.has_wiki && !settings.dry_run {
+        if let Ok(Outcome::Mirrored | Outcome::Updated | Outcome::Unchanged) =
+            result
+        {
+            mirror_wiki(repo, db, settings, &mut notes);
+        }
+    }
+
+    // Releases can be published without a push, so they're also fetched for
+    // repositories that are up to date.
+    if settings.mirror_releases && !settings.dry_run {
+        if let Ok(Outcome::Mirrored | Outcome::Updated | Outcome::Unchanged) =
+            result
+        {
+            mirror_releases(repo, db, provider, settings, &mut notes);
+        }
+    }
+
+    debug!("{}: finished in {:.2?}", repo.name, start.elapsed());
+
+    if !settings.dry_run {
+        let status = match result {
+            Ok(_) => database::RunStatus::Done,
+            Err(_) => database::RunStatus::Failed,
+        };
+
+        write_batch.add(repo.id, status, write);
+    }
+
+    (result, notes)
+}
+
+/// Mirror or update `repo`.
+///
+/// If `settings.dry_run` is true, decide what to do with `repo` but don't
+/// modify the mirror or the database.
+///
+/// The database isn't written to directly. Instead, the row to insert or
+/// update is returned for a `WriteBatch`. Problems that don't fail the
+/// repository and the amount of data fetched are added to `notes`.
+fn process_repo(
+    repo: &provider::Repo,
+    db: &database::Db,
+    settings: &Settings,
+    notes: &mut Notes,
+) -> anyhow::Result<(Outcome, Option<PendingWrite>)> {
+    check_repo_name(&repo.name)?;
+
+    if settings.is_oversize(repo) {
+        info!(
+            "{}: skipping, size {} KiB is larger than the maximum",
+            repo.name,
+            repo.size,
+        );
+
+        let write = if settings.oversize_mode == OversizeMode::Metadata
+            && !settings.dry_run
+        {
+            mirror_metadata(repo, db, settings, &mut notes.warnings)?
+        } else {
+            None
+        };
+
+        return Ok((Outcome::SkippedSize, write));
+    }
+
+    let id = repo.id;
+    let path = settings.repo_path(repo);
+    let mut db_repo = database::Repo::from(repo);
+
+    // Start over as though the repository had never been mirrored.
+    if settings.is_remirrored(repo) {
+        if settings.dry_run {
+            return Ok((Outcome::Mirrored, None));
+        }
+
+        remove_for_remirror(repo, &path, db)?;
+    }
+
+    match db.repo_get(id) {
+        // If we've already seen the repo and it's been updated, fetch the
+        // latest.
+        Ok(current_repo) => {
+            // Compare with the row already read rather than querying again.
+            let is_updated = db_repo.is_newer_than(&current_repo);
+
+            // The provider bumps `updated_at` for changes that don't affect
+            // the mirror, like new stars.
+            let metadata_changed = current_repo.metadata_hash.is_none()
+                || current_repo.metadata_hash != db_repo.metadata_hash;
+
+            if !is_updated || !metadata_changed {
+                debug!("{}: up to date", repo.name);
+
+                if settings.dry_run {
+                    return Ok((Outcome::Unchanged, None));
+                }
+
+                // Propagate base cgitrc changes even when the repository
+                // itself hasn't changed.
+                if settings.update_cgitrc {
+                    write_repo_cgitrc(&path, repo, settings)?;
+                }
+
+                // Store the new `updated_at` so the repository isn't
+                // compared again on the next run.
+                let write = if is_updated {
+                    db_repo.last_mirrored_at = current_repo.last_mirrored_at;
+                    db_repo.disk_size = current_repo.disk_size;
+
+                    Some(PendingWrite {
+                        name: repo.name.clone(),
+                        write: database::RepoWrite::Update(db_repo),
+                        new_mirror: None,
+                    })
+                } else {
+                    None
+                };
+
+                // Create a missing bundle.
+                write_bundle(repo, &path, settings, false, &mut notes.warnings);
+                write_metadata_json(
+                    repo,
+                    &path,
+                    settings,
+                    false,
+                    &mut notes.warnings,
+                );
+
+                return Ok((Outcome::Unchanged, write));
+            }
+
+            if settings.dry_run {
+                return Ok((Outcome::Updated, None));
+            }
+
+            // A rename or a change of owner or fork status moves the
+            // mirror's path, possibly onto another repository's mirror.
+            let stored_path = settings.repo_path(
+                &provider::Repo::from(&current_repo),
+            );
+
+            if stored_path != path {
+                check_path_unclaimed(repo, &path, db, settings)?;
+            }
+
+            info!("{}: updating '{}'", repo.name, path.display());
+
+            let mut fetched = false;
+            let mut healed = false;
+
+            let result = update(&path, &current_repo, repo, settings)
+                .map(|transfer| {
+                    if let Some(transfer) = transfer {
+                        fetched = true;
+                        notes.transfer += transfer;
+                    }
+                });
+
+            // Start over from a fresh mirror if the existing one is corrupt.
+            // Other update failures, like network errors, are reported as
+            // usual, leaving the mirror in place.
+            if settings.heal {
+                match git::verify(&path) {
+                    Err(e) if e.is_corrupt() => {
+                        let e = match result {
+                            Ok(()) => anyhow::Error::new(e),
+                            Err(update_error) => update_error.context(e),
+                        };
+
+                        notes.warnings.push(Warning::new(
+                            &repo.name,
+                            format!(
+                                "{:#}; mirrored '{}' again",
+                                e,
+                                path.display(),
+                            ),
+                        ));
+
+                        // The database row is removed first so that a failed
+                        // mirror is retried from scratch on the next run.
+                        remove_for_remirror(repo, &path, db)?;
+
+                        match mirror(&path, repo, settings) {
+                            Ok(transfer) => notes.transfer += transfer,
+                            Err(e) => {
+                                remove_partial_mirror(&repo.name, &path);
+
+                                return Err(e);
+                            },
+                        }
+
+                        fetched = true;
+                        healed = true;
+                    },
+                    Err(e) => {
+                        result?;
+
+                        return Err(e.into());
+                    },
+                    Ok(()) => result?,
+                }
+            } else {
+                result?;
+            }
+
+            let empty = finish_fetch(repo, &path, settings, notes)?;
+
+            db_repo.last_mirrored_at = if empty {
+                None
+            } else if fetched {
+                Some(Utc::now().into())
+            } else {
+                current_repo.last_mirrored_at
+            };
+            db_repo.disk_size = measure_disk_size(
+                &repo.name,
+                &path,
+                &mut notes.warnings,
+            );
+
+            write_bundle(repo, &path, settings, fetched, &mut notes.warnings);
+            write_metadata_json(repo, &path, settings, true, &mut notes.warnings);
+
+            // A healed mirror's row was deleted along with the old mirror.
+            let write = if healed {
+                PendingWrite {
+                    name: repo.name.clone(),
+                    write: database::RepoWrite::Insert(db_repo),
+                    new_mirror: Some(path),
+                }
+            } else {
+                PendingWrite {
+                    name: repo.name.clone(),
+                    write: database::RepoWrite::Update(db_repo),
+                    new_mirror: None,
+                }
+            };
+
+            Ok((Outcome::Updated, Some(write)))
+        },
+
+        // If the repo doesn't exist, mirror it and store it in the
+        // database.
+        Err(database::Error::Db(rusqlite::Error::QueryReturnedNoRows)) => {
+            // Providers report a size of 0 for empty repositories, but also
+            // for ones whose size hasn't been computed yet, which could be
+            // over the maximum. Some never report a size, so existing mirrors
+            // are still updated.
+            if settings.max_repo_size_bytes.is_some() && repo.size == 0 {
+                if !settings.mirror_unknown_size {
+                    anyhow::bail!(
+                        "size is unknown, so it can't be compared with the \
+                        maximum; use '--mirror-unknown-size' to mirror it \
+                        anyway"
+                    );
+                }
+
+                info!("{}: size is unknown, mirroring anyway", repo.name);
+            }
+
+            if settings.dry_run {
+                return Ok((Outcome::Mirrored, None));
+            }
+
+            info!("{}: mirroring to '{}'", repo.name, path.display());
+
+            let path_existed = path.exists();
+
+            if path_existed {
+                check_path_unclaimed(repo, &path, db, settings)?;
+            }
+
+            // Remove a partial mirror so the next run can start from
+            // scratch.
+            let result = mirror(&path, repo, settings)
+                .and_then(|transfer| {
+                    notes.transfer += transfer;
+
+                    finish_fetch(repo, &path, settings, notes)
+                });
+
+            let empty = match result {
+                Ok(empty) => empty,
+                Err(e) => {
+                    if !path_existed {
+                        remove_partial_mirror(&repo.name, &path);
+                    }
+
+                    return Err(e);
+                },
+            };
+
+            if !empty {
+                db_repo.last_mirrored_at = Some(Utc::now().into());
+            }
+            db_repo.disk_size = measure_disk_size(
+                &repo.name,
+                &path,
+                &mut notes.warnings,
+            );
+
+            write_bundle(repo, &path, settings, true, &mut notes.warnings);
+            write_metadata_json(repo, &path, settings, true, &mut notes.warnings);
+
+            Ok((
+                Outcome::Mirrored,
+                Some(PendingWrite {
+                    name: repo.name.clone(),
+                    write: database::RepoWrite::Insert(db_repo),
+                    new_mirror: if path_existed {
+                        None
+                    } else {
+                        Some(path)
+                    },
+                }),
+            ))
+        },
+
+        Err(e) => anyhow::bail!(e),
+    }
+}
+
+/// Check that the mirror at `path` doesn't belong to a repository other than
+/// `repo`, as when two forks have the same name, or one repository was
+/// renamed to the former name of another. With `--mirror-wikis`, it also
+/// mustn't be the wiki mirror of another repository.
+///
+/// Each stored repository's path is computed from its stored metadata, so
+/// this reads the whole database, and is only done when a collision is
+/// possible.
+fn check_path_unclaimed(
+    repo: &provider::Repo,
+    path: &Path,
+    db: &database::Db,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let db_repos = db.repo_all()
+        .context("unable to read repositories from database")?;
+
+    let db_repos: Vec<_> = db_repos
+        .iter()
+        .map(provider::Repo::from)
+        .collect();
+
+    let owner = db_repos
+        .iter()
+        .find(|other| {
+            other.id != repo.id && settings.repo_path(other) == path
+        });
+
+    if let Some(owner) = owner {
+        anyhow::bail!(
+            "'{}' is already the mirror of '{}/{}' (ID {}), not mirroring \
+            ID {} into it",
+            path.display(),
+            owner.owner,
+            owner.name,
+            owner.id,
+            repo.id,
+        );
+    }
+
+    // A repository named like "name.wiki" has the same path as the wiki of
+    // "name".
+    if settings.mirror_wikis {
+        let wiki_owner = db_repos
+            .iter()
+            .find(|other| wiki_path(&settings.repo_path(other)) == path);
+
+        if let Some(owner) = wiki_owner {
+            anyhow::bail!(
+                "'{}' is already the wiki mirror of '{}/{}' (ID {}), not \
+                mirroring ID {} into it",
+                path.display(),
+                owner.owner,
+                owner.name,
+                owner.id,
+                repo.id,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the mirror of `repo` at `path` and its database row, so it can be
+/// mirrored again from scratch.
+fn remove_for_remirror(
+    repo: &provider::Repo,
+    path: &Path,
+    db: &database::Db,
+) -> anyhow::Result<()> {
+    info!("{}: removing '{}' to mirror it again", repo.name, path.display());
+
+    // Delete the row first, so it never refers to a removed mirror.
+    db.repo_delete(repo.id)
+        .context("unable to delete repository from database")?;
+
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e)
+            .with_context(|| format!(
+                "unable to remove '{}'",
+                path.display(),
+            )),
+    }
+}
+
+/// Check that `name` is safe to use as a directory name in the mirror root.
+///
+/// Names with path separators or a leading dot, like "../evil" or ".hidden",
+/// are refused, so that a provider can't have a repository mirrored outside
+/// the mirror root or into a hidden directory.
+fn check_repo_name(name: &str) -> anyhow::Result<()> {
+    check_file_name("repository", name)
+}
+
+/// Check that `name`, of a `kind` of thing like "repository", can be used as
+/// a single path component.
+fn check_file_name(kind: &str, name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        anyhow::bail!("{} name is empty", kind);
+    }
+
+    if name.starts_with('.') {
+        anyhow::bail!("unsafe {} name '{}' starts with a dot", kind, name);
+    }
+
+    if name.contains(['/', '\\']) {
+        anyhow::bail!(
+            "unsafe {} name '{}' contains a path separator",
+            kind,
+            name,
+        );
+    }
+
+    if name.contains('\0') {
+        anyhow::bail!("unsafe {} name '{}' contains a null byte", kind, name);
+    }
+
+    Ok(())
+}
+
+/// Mirror or update the wiki of `repo` next to the repository's mirror.
+///
+/// Update failures are added to `notes` as warnings rather than returned, so
+/// that they don't fail the repository itself. GitHub reports `has_wiki` for
+/// repositories whose wiki has no pages yet, so a wiki that can't be mirrored
+/// for the first time is only mentioned at the info level.
+///
+/// A repository named like "name.wiki" is mirrored to the same path as the
+/// wiki of "name", so the wiki is skipped with a warning if that repository
+/// is stored.
+fn mirror_wiki(
+    repo: &provider::Repo,
+    db: &database::Db,
+    settings: &Settings,
+    notes: &mut Notes,
+) {
+    let path = wiki_path(&settings.repo_path(repo));
+
+    let wiki_name = format!("{}.wiki", repo.name);
+    let owner = db.repo_all_named(&wiki_name)
+        .map(|db_repos| {
+            db_repos
+                .iter()
+                .map(provider::Repo::from)
+                .find(|other| settings.repo_path(other) == path)
+        });
+
+    match owner {
+        Ok(None) => (),
+        Ok(Some(owner)) => {
+            notes.warnings.push(Warning::new(
+                &repo.name,
+                format!(
+                    "not mirroring wiki: '{}' is already the mirror of \
+                    '{}/{}' (ID {})",
+                    path.display(),
+                    owner.owner,
+                    owner.name,
+                    owner.id,
+                ),
+            ));
+
+            return;
+        },
+        Err(e) => {
+            notes.warnings.push(Warning::new(
+                &repo.name,
+                format!(
+                    "not mirroring wiki: unable to read repositories from \
+                    database: {:#}",
+                    anyhow::Error::new(e),
+                ),
+            ));
+
+            return;
+        },
+    }
+
+    if path.exists() {
+        debug!("{}: updating wiki '{}'", repo.name, path.display());
+
+        let result = git::update(
+            &path,
+            settings.remote_name,
+            &settings.fetch_settings,
+        );
+
+        match result {
+            Ok(transfer) => notes.transfer += transfer,
+            Err(e) => notes.warnings.push(Warning::new(
+                &repo.name,
+                format!("unable to update wiki: {:#}", anyhow::Error::new(e)),
+            )),
+        }
+
+        return;
+    }
+
+    let mut options = settings.mirror_options(repo);
+    options.url = wiki_url(&options.url);
+    options.description = format!("Wiki for {}", repo.name);
+
+    // Wikis don't follow their repository's default branch.
+    options.default_branch = "master".to_owned();
+
+    info!("{}: mirroring wiki to '{}'", repo.name, path.display());
+
+    match git::mirror(&path, &options) {
+        Ok(transfer) => notes.transfer += transfer,
+        Err(e) => {
+            info!(
+                "{}: unable to mirror wiki: {:#}",
+                repo.name,
+                anyhow::Error::new(e),
+            );
+
+            remove_partial_mirror(&repo.name, &path);
+        },
+    }
+}
+
+/// Fetch the releases of `repo` and store them in the database, downloading
+/// their assets into the mirror if `settings.asset_agent` is set.
+///
+/// Failures are added to `notes` as warnings rather than returned, so that
+/// they don't fail the repository.
+fn mirror_releases(
+    repo: &provider::Repo,
+    db: &database::Db,
+    provider: &dyn Provider,
+    settings: &Settings,
+    notes: &mut Notes,
+) {
+    let releases = match provider.fetch_releases(&repo.owner, &repo.name) {
+        Ok(releases) => releases,
+        Err(e) => {
+            notes.warnings.push(Warning::new(
+                &repo.name,
+                format!(
+                    "unable to fetch releases: {:#}",
+                    anyhow::Error::new(e),
+                ),
+            ));
+
+            return;
+        },
+    };
+
+    debug!("{}: fetched {} releases", repo.name, releases.len());
+
+    let db_releases: Vec<_> = releases
+        .iter()
+        .map(database::Release::from)
+        .collect();
+
+    if let Err(e) = db.release_replace_all(repo.id, &db_releases) {
+        notes.warnings.push(Warning::new(
+            &repo.name,
+            format!("unable to save releases: {:#}", anyhow::Error::new(e)),
+        ));
+    }
+
+    let agent = match settings.asset_agent {
+        Some(agent) => agent,
+        None => return,
+    };
+
+    let releases_dir = settings.repo_path(repo).join(RELEASES_DIR);
+
+    for release in &releases {
+        for asset in &release.assets {
+            let result = download_asset(
+                agent,
+                &releases_dir,
+                release,
+                asset,
+                settings.max_asset_size_bytes,
+            );
+
+            match result {
+                Ok(true) => info!(
+                    "{}: downloaded '{}' of release '{}'",
+                    repo.name,
+                    asset.name,
+                    release.tag_name,
+                ),
+                Ok(false) => (),
+                Err(e) => notes.warnings.push(Warning::new(
+                    &repo.name,
+                    format!(
+                        "unable to download '{}' of release '{}': {:#}",
+                        asset.name,
+                        release.tag_name,
+                        e,
+                    ),
+                )),
+            }
+        }
+    }
+}
+
+/// Download `asset` of `release` to "TAG/NAME" in `releases_dir`, and return
+/// whether it was downloaded.
+///
+/// Assets larger than `max_size_bytes` are skipped. An asset that was
+/// already downloaded isn't downloaded again, since GitHub doesn't let
+/// assets be changed without uploading them again.
+fn download_asset(
+    agent: &ureq::Agent,
+    releases_dir: &Path,
+    release: &provider::Release,
+    asset: &provider::Asset,
+    max_size_bytes: Option<u64>,
+) -> anyhow::Result<bool> {
+    check_file_name("release tag", &release.tag_name)?;
+    check_file_name("asset", &asset.name)?;
+
+    if max_size_bytes.is_some_and(|max| asset.size > max) {
+        debug!(
+            "skipping '{}' of release '{}', larger than the maximum",
+            asset.name,
+            release.tag_name,
+        );
+
+        return Ok(false);
+    }
+
+    let dir = releases_dir.join(&release.tag_name);
+    let path = dir.join(&asset.name);
+
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() == asset.size) {
+        return Ok(false);
+    }
+
+    fs::create_dir_all(&dir)
+        .with_context(|| format!(
+            "unable to create directory '{}'",
+            dir.display(),
+        ))?;
+
+    let response = agent.get(&asset.url)
+        .call()
+        .with_context(|| format!("unable to request '{}'", asset.url))?;
+
+    // Download to a temporary file so that an interrupted download isn't
+    // taken for a complete one.
+    let partial_path = dir.join(format!(".{}.part", asset.name));
+
+    let result = fs::File::create(&partial_path)
+        .and_then(|mut file| io::copy(&mut response.into_reader(), &mut file))
+        .and_then(|_| fs::rename(&partial_path, &path));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial_path);
+
+        return Err(
+            anyhow::Error::new(e)
+                .context(format!("unable to write '{}'", path.display()))
+        );
+    }
+
+    Ok(true)
+}
+
+/// Get the path of the wiki mirror for the repository mirrored at
+/// `repo_path`, like "name.wiki.git" for "name.git".
+fn wiki_path(repo_path: &Path) -> PathBuf {
+    repo_path.with_extension("wiki.git")
+}
+
+/// Get the clone URL of the wiki for the repository at `url`.
+fn wiki_url(url: &str) -> String {
+    match url.strip_suffix(".git") {
+        Some(base) => format!("{}.wiki.git", base),
+        None => format!("{}.wiki", url),
+    }
+}
+
+/// Write a bundle of the mirror of `repo` at `path` into
+/// `settings.bundle_dir`, if it's set.
+///
+/// The bundle is only rewritten if `changed` is true or it doesn't exist yet.
+/// Failures are added to `warnings` rather than returned, so that they don't
+/// fail the repository, whose mirror is already up to date.
+fn write_bundle(
+    repo: &provider::Repo,
+    path: &Path,
+    settings: &Settings,
+    changed: bool,
+    warnings: &mut Vec<Warning>,
+) {
+    let bundle_dir = match settings.bundle_dir {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let bundle_path = clone_path(bundle_dir, settings.fork_subdir, repo)
+        .with_extension("bundle");
+
+    if !changed && bundle_path.exists() {
+        return;
+    }
+
+    let result = bundle_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(anyhow::Error::new)
+        .and_then(|_|
+            git::bundle(path, &bundle_path).map_err(anyhow::Error::new)
+        );
+
+    match result {
+        Ok(true) => info!(
+            "{}: wrote bundle '{}'",
+            repo.name,
+            bundle_path.display(),
+        ),
+        Ok(false) => debug!("{}: empty, not bundling", repo.name),
+        Err(e) => warnings.push(Warning::new(
+            &repo.name,
+            format!("unable to write bundle: {:#}", e),
+        )),
+    }
+}
+
+/// Repository metadata written to `METADATA_JSON_FILE`.
+#[derive(Debug, Serialize)]
+struct MetadataJson<'a> {
+    id: i64,
+    name: &'a str,
+    owner: &'a str,
+    description: Option<&'a str>,
+    default_branch: &'a str,
+    clone_url: &'a str,
+    homepage: Option<&'a str>,
+    language: Option<&'a str>,
+    topics: &'a [String],
+    fork: bool,
+    pushed_at: DateTime<FixedOffset>,
+}
+
+/// Write `repo`'s metadata as JSON into the mirror at `path` if
+/// `settings.write_metadata_json` is set, for tools that don't read the
+/// database.
+///
+/// The file is only written if the repository `changed` or if it's missing.
+/// Failures are added to `warnings` rather than returned, as the mirror
+/// itself is fine.
+fn write_metadata_json(
+    repo: &provider::Repo,
+    path: &Path,
+    settings: &Settings,
+    changed: bool,
+    warnings: &mut Vec<Warning>,
+) {
+    if !settings.write_metadata_json {
+        return;
+    }
+
+    let json_path = path.join(METADATA_JSON_FILE);
+
+    if !changed && json_path.exists() {
+        return;
+    }
+
+    let metadata = MetadataJson {
+        id: repo.id,
+        name: &repo.name,
+        owner: &repo.owner,
+        description: repo.description.as_deref(),
+        default_branch: &repo.default_branch,
+        clone_url: &repo.clone_url,
+        homepage: repo.homepage.as_deref(),
+        language: repo.language.as_deref(),
+        topics: &repo.topics,
+        fork: repo.fork,
+        pushed_at: repo.pushed_at,
+    };
+
+    let result = serde_json::to_string_pretty(&metadata)
+        .map_err(anyhow::Error::new)
+        .and_then(|json|
+            fs::write(&json_path, json + "\n").map_err(anyhow::Error::new)
+        );
+
+    if let Err(e) = result {
+        warnings.push(Warning::new(
+            &repo.name,
+            format!(
+                "unable to write '{}': {:#}",
+                json_path.display(),
+                e,
+            ),
+        ));
+    }
+}
+
+/// Get the size in bytes of the mirror of `name` at `path`, adding a warning
+/// and returning `None` if it can't be measured.
+fn measure_disk_size(
+    name: &str,
+    path: &Path,
+    warnings: &mut Vec<Warning>,
+) -> Option<u64> {
+    match dir_size(path) {
+        Ok(size) => Some(size),
+        Err(e) => {
+            warnings.push(Warning::new(
+                name,
+                format!(
+                    "unable to measure size of '{}': {}",
+                    path.display(),
+                    e,
+                ),
+            ));
+
+            None
+        },
+    }
+}
+
+/// Get the total size in bytes of the files under `path`. Symbolic links
+/// aren't followed.
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+/// Remove the partial mirror of `name` at `path`, warning if it can't be
+/// removed.
+fn remove_partial_mirror(name: &str, path: &Path) {
+    if let Err(e) = fs::remove_dir_all(path) {
+        warn!(
+            "{}: unable to remove partial mirror '{}': {}",
+            name,
+            path.display(),
+            e,
+        );
+    }
+}
+
+/// Rewrite the cgitrc file of every repository in the database from its
+/// stored metadata, without fetching anything.
+///
+/// Repositories owned by one of `users` get that user's base cgitrc. Returns
+/// the names of the repositories whose cgitrc was regenerated, or would be in
+/// a dry run.
+fn regenerate_db_cgitrc(
+    db: &database::Db,
+    users: &[config::User],
+    settings: &Settings,
+) -> Result<Vec<String>, MultiError> {
+    let db_repos = db.repo_all()
+        .context("unable to read repositories from database")?;
+
+    let results: Vec<_> = db_repos
+        .par_iter()
+        .filter_map(|db_repo| {
+            let repo = provider::Repo::from(db_repo);
+
+            let settings = settings.for_owner(users, &repo);
+
+            let path = settings.repo_path(&repo);
+
+            if !path.exists() {
+                warn!(
+                    "{}: skipping, '{}' doesn't exist",
+                    repo.name,
+                    path.display(),
+                );
+
+                return None;
+            }
+
+            if settings.dry_run {
+                return Some(Ok(repo.name));
+            }
+
+            debug!("{}: regenerating cgitrc", repo.name);
+
+            Some(
+                write_repo_cgitrc(&path, &repo, &settings)
+                    .map(|()| repo.name.clone())
+                    .map_err(|e| e.context(repo.name.clone()))
+            )
+        })
+        .collect();
+
+    let mut names = Vec::new();
+    let mut errors = Vec::new();
+
+    for result in results {
+        match result {
+            Ok(name) => names.push(name),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(names)
+    } else if errors.len() < db_repos.len() {
+        Err(MultiError::partial(errors))
+    } else {
+        Err(MultiError::from(errors))
+    }
+}
+
+/// Write a CGit include file at `index_path` listing every mirrored
+/// repository in the database.
+///
+/// Repositories are grouped under `section=` headings by their configured
+/// section, falling back to their owner.
+fn write_index<P: AsRef<Path>>(
+    index_path: P,
+    db: &database::Db,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let index_path = index_path.as_ref();
+
+    let db_repos = db.repo_all()
+        .context("unable to read repositories from database")?;
+
+    let mut entries: Vec<_> = db_repos
+        .iter()
+        .map(provider::Repo::from)
+        .filter_map(|repo| {
+            let path = settings.repo_path(&repo);
+
+            if !path.exists() {
+                return None;
+            }
+
+            let section = settings
+                .section_name(&repo)
+                .unwrap_or(&repo.owner)
+                .to_owned();
+
+            let url = path
+                .strip_prefix(settings.mirror_root)
+                .unwrap_or(&path)
+                .with_extension("")
+                .to_string_lossy()
+                .into_owned();
+
+            // CGit resolves relative paths against its own working
+            // directory, so write absolute ones.
+            let path = fs::canonicalize(&path).unwrap_or(path);
+
+            Some((section, url, path, repo))
+        })
+        .collect();
+
+    entries.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+    let mut index = String::new();
+    let mut current_section = None;
+
+    for (section, url, path, repo) in &entries {
+        if current_section != Some(section) {
+            if !section.is_empty() {
+                index.push_str(&format!("section={}\n\n", section));
+            }
+
+            current_section = Some(section);
+        }
+
+        index.push_str(&format!("repo.url={}\n", url));
+        index.push_str(&format!("repo.path={}\n", path.display()));
+
+        if !repo.description().is_empty() {
+            index.push_str(&format!(
+                "repo.desc={}\n",
+                repo.description().replace('\n', " "),
+            ));
+        }
+
+        if !repo.owner.is_empty() {
+            index.push_str(&format!("repo.owner={}\n", repo.owner));
+        }
+
+        if settings.private_mode == PrivateMode::Hide && repo.private {
+            index.push_str("repo.hide=1\n");
+        }
+
+        index.push('\n');
+    }
+
+    fs::write(index_path, index)
+        .with_context(|| format!(
+            "unable to write index '{}'",
+            index_path.display(),
+        ))?;
+
+    if let Some(mode) = settings.cgitrc_mode {
+        cgitrc::set_mode(index_path, mode)?;
+    }
+
+    Ok(())
+}
+
+/// Create or refresh a metadata-only mirror of an oversize repository.
+///
+/// The mirror gets a description and cgitrc, but no objects are fetched. If
+/// the repository later shrinks below the maximum size, it's filled in by a
+/// normal update.
+fn mirror_metadata(
+    repo: &provider::Repo,
+    db: &database::Db,
+    settings: &Settings,
+    warnings: &mut Vec<Warning>,
+) -> anyhow::Result<Option<PendingWrite>> {
+    let path = settings.repo_path(repo);
+    let mut db_repo = database::Repo::from(repo);
+
+    match db.repo_get(repo.id) {
+        Ok(current_repo) => {
+            if !db_repo.is_newer_than(&current_repo)
+                && !settings.update_cgitrc
+            {
+                return Ok(None);
+            }
+
+            if current_repo.description() != repo.description() {
+                git::update_description(&path, repo.description())?;
+            }
+
+            write_repo_cgitrc(&path, repo, settings)?;
+
+            db_repo.last_mirrored_at = current_repo.last_mirrored_at;
+            db_repo.disk_size = measure_disk_size(&repo.name, &path, warnings);
+
+            Ok(Some(PendingWrite {
+                name: repo.name.clone(),
+                write: database::RepoWrite::Update(db_repo),
+                new_mirror: None,
+            }))
+        },
+        Err(database::Error::Db(rusqlite::Error::QueryReturnedNoRows)) => {
+            info!(
+                "{}: creating metadata-only mirror '{}'",
+                repo.name,
+                path.display(),
+            );
+
+            git::init_mirror(&path, &settings.mirror_options(repo))?;
+
+            write_repo_cgitrc(&path, repo, settings)?;
+            update_mtime(&path, repo, settings.always_agefile)?;
+
+            db_repo.disk_size = measure_disk_size(&repo.name, &path, warnings);
+
+            // Leave `last_mirrored_at` unset, since nothing was fetched.
+            Ok(Some(PendingWrite {
+                name: repo.name.clone(),
+                write: database::RepoWrite::Insert(db_repo),
+                new_mirror: None,
+            }))
+        },
+        Err(e) => anyhow::bail!(e),
+    }
+}
+
+/// Return `true` if `size_kibibytes` is larger than `max_repo_size_bytes`.
+///
+/// GitHub reports repository sizes in KiB (1024 bytes). A repository exactly
+/// equal to the maximum size is not considered oversize.
+fn is_repo_oversize(
+    size_kibibytes: u64,
+    max_repo_size_bytes: u64,
+) -> bool {
+    let size_bytes = size_kibibytes.saturating_mul(1024);
+
+    if size_bytes > max_repo_size_bytes {
+        return true;
+    }
+
+    false
+}
+
+/// Get the clone path for a repository.
+///
+/// If `repo` is a fork, add `fork_subdir` to `base_path`. Forks aren't nested
+/// if `fork_subdir` is empty.
+fn clone_path<P: AsRef<Path>>(
+    base_path: P,
+    fork_subdir: &str,
+    repo: &provider::Repo,
+) -> PathBuf {
+    let git_dir = format!("{}.git", repo.name);
+
+    if repo.fork && !fork_subdir.is_empty() {
+        base_path
+            .as_ref()
+            .join(fork_subdir)
+            .join(git_dir)
+    } else {
+        base_path
+            .as_ref()
+            .join(git_dir)
+    }
+}
+
+/// Mirror a repository.
+///
+/// The mirror's mtime isn't set, since it may be empty. See `finish_fetch`.
+fn mirror<P: AsRef<Path>>(
+    clone_path: P,
+    repo: &provider::Repo,
+    settings: &Settings,
+) -> anyhow::Result<git::TransferStats> {
+    let transfer = git::mirror(&clone_path, &settings.mirror_options(repo))?;
+
+    // Copy or render the base cgitrc file into the newly-cloned repository,
+    // along with repository-specific settings.
+    write_repo_cgitrc(&clone_path, repo, settings)?;
+
+    Ok(transfer)
+}
+
+/// Update a previously-mirrored repository.
+///
+/// New objects are only fetched if the repository was pushed to since it was
+/// last stored. Returns the amount of data received, or `None` if there was no
+/// fetch.
+///
+/// The repository's "cgitrc" file is regenerated if its settings changed, or
+/// always if `settings.update_cgitrc` is true. As with `mirror`, the mtime is
+/// left to `finish_fetch`.
+fn update<P: AsRef<Path>>(
+    repo_path: P,
+    current_repo: &database::Repo,
+    updated_repo: &provider::Repo,
+    settings: &Settings,
+) -> anyhow::Result<Option<git::TransferStats>> {
+    let moved = current_repo.clone_url.as_ref()
+        != Some(&updated_repo.clone_url);
+
+    // After a rename or a transfer to another owner, the old URL only
+    // redirects, so fetch from the new one instead.
+    if moved {
+        let url = settings.mirror_options(updated_repo).url;
+
+        if git::set_remote_url(&repo_path, settings.remote_name, &url)? {
+            info!(
+                "{}: moved, fetching from '{}'",
+                updated_repo.name,
+                config::redact_url(&url),
+            );
+        }
+    }
+
+    // Metadata-only mirrors have never been fetched.
+    let fetch = current_repo.pushed_at != Some(updated_repo.pushed_at)
+        || current_repo.last_mirrored_at.is_none();
+
+    let transfer = if fetch {
+        Some(
+            git::update(
+                &repo_path,
+                settings.remote_name,
+                &settings.fetch_settings,
+            )?
+        )
+    } else {
+        debug!("{}: not pushed to, skipping fetch", updated_repo.name);
+
+        None
+    };
+
+    let remote_description = updated_repo.description();
+
+    if current_repo.description() != remote_description {
+        git::update_description(&repo_path, remote_description)?;
+    }
+
+    let mut cgitrc_changed = false;
+
+    if let Some(default_branch) = &current_repo.default_branch {
+        if default_branch != &updated_repo.default_branch {
+            git::change_current_branch(
+                &repo_path,
+                &updated_repo.default_branch,
+            )?;
+
+            cgitrc_changed = true;
+        }
+    }
+
+    if moved {
+        cgitrc_changed = true;
+    }
+
+    // Metadata-only mirrors have never been fetched, and their cgitrc notes
+    // that the repository is too large.
+    if current_repo.last_mirrored_at.is_none() {
+        cgitrc_changed = true;
+    }
+
+    if current_repo.topics != updated_repo.topics
+        || current_repo.homepage != updated_repo.homepage
+    {
+        cgitrc_changed = true;
+    }
+
+    if cgitrc_changed || settings.update_cgitrc {
+        write_repo_cgitrc(&repo_path, updated_repo, settings)?;
+    }
+
+    Ok(transfer)
+}
+
+/// Set the mtime of the mirror of `repo` at `path` after it was mirrored or
+/// updated, and return whether it's empty.
+///
+/// A repository without any commits yet has no refs to set the mtime on, so
+/// the empty mirror is left as is, apart from an agefile with
+/// `settings.always_agefile`, and noted in `notes`. It's stored as never
+/// having been fetched, so that `update` fetches it again once it's pushed
+/// to.
+fn finish_fetch(
+    repo: &provider::Repo,
+    path: &Path,
+    settings: &Settings,
+    notes: &mut Notes,
+) -> anyhow::Result<bool> {
+    let empty = git::is_empty(path)
+        .with_context(|| format!(
+            "unable to read refs of '{}'",
+            path.display(),
+        ))?;
+
+    if empty {
+        info!("{}: repository is empty", repo.name);
+
+        notes.empty = true;
+
+        if settings.always_agefile {
+            set_agefile_time(path, repo.pushed_at)?;
+        }
+    } else {
+        update_mtime(path, repo, settings.always_agefile)?;
+    }
+
+    Ok(empty)
+}
+
+/// Set the mtime of the repository to GitHub's `pushed_at` time.
+///
+/// Used for CGit "age" sorting. The CGit agefile is only written if there's
+/// no ref file to set the time on, unless `always_agefile` is true.
+fn update_mtime<P: AsRef<Path>>(
+    repo_path: P,
+    repo: &provider::Repo,
+    always_agefile: bool,
+) -> anyhow::Result<()> {
+    if always_agefile {
+        set_agefile_time(&repo_path, repo.pushed_at)?;
+    }
+
+    let update_time = filetime::FileTime::from_system_time(
+        repo.pushed_at.into()
+    );
+
+    let default_branch_ref = repo_path
+        .as_ref()
+        .join("refs/heads")
+        .join(&repo.default_branch);
+
+    // Try updating times on the default ref.
+    match filetime::set_file_times(
+        &default_branch_ref,
+        update_time,
+        update_time,
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // If the default ref file doesn't exist, update times on the
+            // 'packed-refs' file.
+            let packed_refs_path = repo_path
+                .as_ref()
+                .join("packed-refs");
+
+            match filetime::set_file_times(
+                &packed_refs_path,
+                update_time,
+                update_time,
+            ) {
+                Ok(_) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // In the absence of a 'packed-refs' file, create a CGit
+                    // agefile and add the update time to it.
+                    if let Err(e) = set_agefile_time(&repo_path, repo.pushed_at) {
+                        warn!(
+                            "{}: {:#}; setting mtime on repository directory",
+                            repo.name,
+                            e,
+                        );
+
+                        // As a last resort, set the time on the repository
+                        // itself so CGit can still sort by age.
+                        filetime::set_file_times(
+                            &repo_path,
+                            update_time,
+                            update_time,
+                        )
+                            .with_context(|| format!(
+                                "unable to set mtime on '{}'",
+                                repo_path.as_ref().display(),
+                            ))?;
+                    }
+
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            }
+                .with_context(|| format!(
+                    "unable to set mtime on '{}'",
+                    &packed_refs_path.display(),
+                ))?;
+
+            Ok(())
+        },
+        Err(e) => Err(e),
+    }
+        .with_context(|| format!(
+            "unable to set mtime on '{}'",
+            &default_branch_ref.display(),
+        ))?;
+
+    Ok(())
+}
+
+/// Write `update_time` into the repo's `info/web/last-modified` file.
+fn set_agefile_time<P: AsRef<Path>>(
+    repo_path: P,
+    update_time: DateTime<FixedOffset>,
+) -> anyhow::Result<()> {
+    let agefile_dir = repo_path.as_ref().join("info/web");
+    fs::DirBuilder::new()
+        .recursive(true)
+        .create(&agefile_dir)
+        .with_context(|| format!(
+            "unable to create directory '{}'",
+            &agefile_dir.display(),
+        ))?;
+
+    let agefile_path = agefile_dir.join("last-modified");
+    let mut agefile = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&agefile_path)
+        .with_context(|| format!(
+            "unable to open '{}'",
+            &agefile_path.display(),
+        ))?;
+
+    writeln!(
+        agefile,
+        "{}",
+        update_time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+    )
+        .with_context(|| format!(
+            "unable to write to '{}'",
+            &agefile_path.display(),
+        ))?;
+
+    Ok(())
+}
+
+/// Write `repo`'s "cgitrc" file in the mirror at `repo_path`.
+fn write_repo_cgitrc<P: AsRef<Path>>(
+    repo_path: P,
+    repo: &provider::Repo,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let extra_lines = cgitrc_extra_lines(repo, settings);
+
+    cgitrc::write(
+        repo_path,
+        repo,
+        &cgitrc_options(repo, settings, &extra_lines),
+    )?;
+
+    Ok(())
+}
+
+/// Get a unified diff of the changes `write_repo_cgitrc` would make to the
+/// "cgitrc" file in the mirror at `repo_path`, or `None` if there are none.
+fn diff_repo_cgitrc(
+    repo_path: &Path,
+    repo: &provider::Repo,
+    settings: &Settings,
+) -> anyhow::Result<Option<String>> {
+    let extra_lines = cgitrc_extra_lines(repo, settings);
+
+    let current = cgitrc::read(repo_path)?;
+    let regenerated = cgitrc::render(
+        &current,
+        repo,
+        &cgitrc_options(repo, settings, &extra_lines),
+    )?;
+
+    Ok(cgitrc::diff(&repo_path.join("cgitrc"), &current, &regenerated))
+}
+
+/// Get the lines to add to `repo`'s "cgitrc" file from the settings and
+/// `repo`'s override.
+fn cgitrc_extra_lines(
+    repo: &provider::Repo,
+    settings: &Settings,
+) -> Vec<String> {
+    let mut extra_lines = settings.cgitrc_lines.to_vec();
+
+    if let Some(repo_override) = settings.overrides.get(&repo.name) {
+        extra_lines.extend_from_slice(&repo_override.cgitrc_lines);
+    }
+
+    extra_lines
+}
+
+/// Get the options for writing `repo`'s "cgitrc" file.
+fn cgitrc_options<'a>(
+    repo: &'a provider::Repo,
+    settings: &'a Settings,
+    extra_lines: &'a [String],
+) -> cgitrc::Options<'a> {
+    cgitrc::Options {
+        base: settings.base_cgitrc(repo),
+        section: settings.section_name(repo),
+        too_large: settings.oversize_mode == OversizeMode::Metadata
+            && settings.is_oversize(repo),
+        hide: settings.private_mode == PrivateMode::Hide && repo.private,
+        extra_lines,
+        mode: settings.cgitrc_mode,
+    }
+}
@@ -17,12 +17,56 @@
 
 
 use std::fmt;
+use std::iter::FromIterator;
+use std::slice;
 
 
 /// Wraps a list of errors.
 #[derive(Debug, thiserror::Error)]
 pub struct MultiError {
     errors: Vec<anyhow::Error>,
+
+    /// Some of the work succeeded despite the errors.
+    partial: bool,
+}
+
+impl MultiError {
+    /// Wrap errors from a run in which some of the work succeeded.
+    pub fn partial(errors: Vec<anyhow::Error>) -> Self {
+        MultiError { errors, partial: true }
+    }
+
+    /// Return `true` if some of the work succeeded.
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Add `error` after the existing errors.
+    pub fn push(&mut self, error: anyhow::Error) {
+        self.errors.push(error);
+    }
+
+    /// Add the errors of `other`, as from a later stage of a run, after the
+    /// existing ones. The result is partial if either was.
+    pub fn extend(&mut self, other: MultiError) {
+        self.errors.extend(other.errors);
+        self.partial |= other.partial;
+    }
+
+    /// Iterate over the errors in the order they were added.
+    pub fn iter(&self) -> slice::Iter<'_, anyhow::Error> {
+        self.errors.iter()
+    }
+
+    /// The number of errors.
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// Return `true` if there are no errors.
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
 }
 
 impl fmt::Display for MultiError {
@@ -41,13 +85,19 @@ impl fmt::Display for MultiError {
 
 impl From<anyhow::Error> for MultiError {
     fn from(error: anyhow::Error) -> Self {
-        MultiError { errors: vec![error] }
+        MultiError { errors: vec![error], partial: false }
     }
 }
 
 impl From<Vec<anyhow::Error>> for MultiError {
     fn from(errors: Vec<anyhow::Error>) -> Self {
-        MultiError { errors: errors }
+        MultiError { errors, partial: false }
+    }
+}
+
+impl FromIterator<anyhow::Error> for MultiError {
+    fn from_iter<I: IntoIterator<Item = anyhow::Error>>(iter: I) -> Self {
+        MultiError::from(iter.into_iter().collect::<Vec<_>>())
     }
 }
 
@@ -59,3 +109,12 @@ impl IntoIterator for MultiError {
         self.errors.into_iter()
     }
 }
+
+impl<'a> IntoIterator for &'a MultiError {
+    type Item = &'a anyhow::Error;
+    type IntoIter = slice::Iter<'a, anyhow::Error>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
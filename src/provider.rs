@@ -0,0 +1,233 @@
+// Copyright (c) 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use chrono::{DateTime, FixedOffset};
+use thiserror;
+
+use crate::{gitea, github, gitlab};
+
+use std::error::Error as _;
+use std::io;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+
+pub(crate) const USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+);
+
+/// Default time to wait to connect to a provider's API or for data from it.
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Github(#[from] github::Error),
+
+    #[error(transparent)]
+    Gitlab(#[from] gitlab::Error),
+
+    #[error(transparent)]
+    Gitea(#[from] gitea::Error),
+
+    #[error("invalid proxy URL '{url}'")]
+    Proxy {
+        source: Box<ureq::Error>,
+        url: String,
+    },
+}
+
+impl Error {
+    /// Check whether the error was caused by a connection or response that
+    /// took longer than the agent's timeout.
+    pub fn is_timeout(&self) -> bool {
+        let mut source = self.source();
+
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) {
+                    return true;
+                }
+            }
+
+            source = e.source();
+        }
+
+        false
+    }
+}
+
+
+/// Build an HTTP agent for provider API requests.
+///
+/// If `proxy` is given, requests go through that proxy, as in
+/// "http://proxy.example.com:8080". Connecting and each read give up after
+/// `timeout`.
+pub fn new_agent(
+    proxy: Option<&str>,
+    timeout: Duration,
+) -> Result<ureq::Agent, Error> {
+    let mut builder = ureq::AgentBuilder::new()
+        .user_agent(USER_AGENT)
+        .timeout_connect(timeout)
+        .timeout_read(timeout);
+
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(
+            ureq::Proxy::new(proxy)
+                .map_err(|e| Error::Proxy {
+                    source: Box::new(e),
+                    url: proxy.to_owned(),
+                })?
+        );
+    }
+
+    Ok(builder.build())
+}
+
+/// Build an HTTP agent without a proxy and with the default timeout.
+pub(crate) fn default_agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .user_agent(USER_AGENT)
+        .timeout_connect(DEFAULT_HTTP_TIMEOUT)
+        .timeout_read(DEFAULT_HTTP_TIMEOUT)
+        .build()
+}
+
+
+/// A forge that hosts repositories.
+///
+/// Providers are shared with the threads that process fetched repositories.
+pub trait Provider: Send + Sync {
+    /// The name of the forge, for messages.
+    fn name(&self) -> &'static str;
+
+    /// Fetch the repositories belonging to `username` a page at a time,
+    /// calling `f` with each page as soon as it arrives.
+    ///
+    /// Stops fetching early if `f` returns `ControlFlow::Break`.
+    fn fetch_repos_each(
+        &self,
+        username: &str,
+        f: &mut dyn FnMut(Vec<Repo>) -> ControlFlow<()>,
+    ) -> Result<(), Error>;
+
+    /// Fetch all repositories belonging to `username`.
+    fn fetch_repos(&self, username: &str) -> Result<Vec<Repo>, Error> {
+        let mut repos = Vec::new();
+
+        self.fetch_repos_each(username, &mut |page| {
+            repos.extend(page);
+
+            ControlFlow::Continue(())
+        })?;
+
+        Ok(repos)
+    }
+
+    /// Whether `fetch_releases` is implemented.
+    fn supports_releases(&self) -> bool {
+        false
+    }
+
+    /// Fetch the releases of `owner`'s repository `name`.
+    ///
+    /// Providers that don't support releases return none.
+    fn fetch_releases(
+        &self,
+        _owner: &str,
+        _name: &str,
+    ) -> Result<Vec<Release>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+
+/// Repository metadata common to all providers.
+#[derive(Debug)]
+pub struct Repo {
+    pub id: i64,
+    pub name: String,
+
+    /// The login of the user or organisation that owns the repository.
+    pub owner: String,
+    pub description: Option<String>,
+    pub fork: bool,
+    pub language: Option<String>,
+    pub clone_url: String,
+    pub default_branch: String,
+
+    /// The project's website, if it has one.
+    pub homepage: Option<String>,
+
+    /// Repository size in KiB.
+    pub size: u64,
+    pub topics: Vec<String>,
+
+    /// When the repository was created. `None` for repositories rebuilt from
+    /// rows stored before the creation date was recorded.
+    pub created_at: Option<DateTime<FixedOffset>>,
+    pub updated_at: DateTime<FixedOffset>,
+    pub pushed_at: DateTime<FixedOffset>,
+
+    /// Whether the repository has a wiki. Only GitHub reports this, so it's
+    /// `false` for other providers.
+    pub has_wiki: bool,
+
+    /// Whether the repository is private. GitLab projects are always `false`.
+    pub private: bool,
+}
+
+impl Repo {
+    /// Get the repository description or an empty string if `None`.
+    pub fn description(&self) -> &str {
+        self.description
+            .as_deref()
+            .unwrap_or("")
+    }
+}
+
+
+/// A release of a repository, like a tagged version with downloads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Release {
+    pub tag_name: String,
+    pub name: Option<String>,
+
+    /// When the release was published. `None` for drafts.
+    pub published_at: Option<DateTime<FixedOffset>>,
+    pub assets: Vec<Asset>,
+}
+
+/// A file attached to a release.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asset {
+    pub name: String,
+
+    /// Size in bytes.
+    pub size: u64,
+
+    /// The URL to download the file from.
+    pub url: String,
+}
@@ -0,0 +1,186 @@
+// Copyright (c) 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use reflectub::{cgitrc, provider};
+
+mod common;
+use common::test_repo;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+
+#[test]
+fn render_template_substitutes_variables() {
+    assert_eq!(
+        cgitrc::render_template(
+            "desc={{ description }} by {{owner}}\nx={{unknown}}\n",
+            &test_repo(1, "reflectub"),
+        ),
+        "desc=A test repository by teddywing\nx=\n",
+    );
+}
+
+#[test]
+fn managed_adds_repo_settings() {
+    let options = cgitrc::Options {
+        base: Some(cgitrc::Base::Template("readme=:README.md")),
+        section: Some("Rust"),
+        too_large: true,
+        hide: true,
+        extra_lines: &["enable-html-serving=1".to_owned()],
+        mode: None,
+    };
+
+    assert_eq!(
+        cgitrc::managed(&test_repo(1, "reflectub"), &options).unwrap(),
+        "readme=:README.md\n\
+        defbranch=main\n\
+        clone-url=https://example.com/reflectub.git\n\
+        owner=teddywing\n\
+        desc=A test repository [too large to mirror]\n\
+        extra-info=topics: cli, git\n\
+        section=Rust\n\
+        hide=1\n\
+        enable-html-serving=1\n",
+    );
+}
+
+#[test]
+fn managed_sets_defbranch_for_master() {
+    let repo = provider::Repo {
+        default_branch: "master".to_owned(),
+        ..test_repo(1, "reflectub")
+    };
+
+    let cgitrc = cgitrc::managed(&repo, &cgitrc::Options::default()).unwrap();
+    assert!(cgitrc.starts_with("defbranch=master\n"), "{}", cgitrc);
+}
+
+#[test]
+fn managed_reports_missing_base_cgitrc() {
+    let options = cgitrc::Options {
+        base: Some(cgitrc::Base::Copy("/nonexistent/cgitrc".as_ref())),
+        ..cgitrc::Options::default()
+    };
+
+    match cgitrc::managed(&test_repo(1, "reflectub"), &options) {
+        Err(cgitrc::Error::ReadBase { path, .. }) =>
+            assert_eq!(path, "/nonexistent/cgitrc"),
+        other => panic!("expected a base cgitrc error, got {:?}", other),
+    }
+}
+
+#[test]
+fn write_preserves_custom_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    let cgitrc_path = dir.path().join("cgitrc");
+
+    fs::write(
+        &cgitrc_path,
+        format!("owner=someone\n{}\nlogo=/custom.png\n", cgitrc::MARKER),
+    )
+        .unwrap();
+
+    cgitrc::write(dir.path(), &test_repo(1, "reflectub"), &cgitrc::Options::default())
+        .unwrap();
+
+    let cgitrc = fs::read_to_string(&cgitrc_path).unwrap();
+    assert!(cgitrc.contains("owner=teddywing\n"));
+    assert!(!cgitrc.contains("owner=someone\n"));
+    assert!(cgitrc.ends_with(
+        &format!("{}\nlogo=/custom.png\n", cgitrc::MARKER),
+    ));
+}
+
+#[test]
+fn write_sets_mode() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let options = cgitrc::Options {
+        mode: Some(0o640),
+        ..cgitrc::Options::default()
+    };
+
+    cgitrc::write(dir.path(), &test_repo(1, "reflectub"), &options).unwrap();
+
+    let metadata = fs::metadata(dir.path().join("cgitrc")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+}
+
+#[test]
+fn diff_shows_changed_lines_with_context() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+    let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+
+    assert_eq!(
+        cgitrc::diff(Path::new("repo.git/cgitrc"), old, new).unwrap(),
+        "--- repo.git/cgitrc\n\
+        +++ repo.git/cgitrc\n\
+        @@ -1,7 +1,7 @@\n \
+        a\n \
+        b\n \
+        c\n\
+        -d\n\
+        +D\n \
+        e\n \
+        f\n \
+        g\n\
+        @@ -10,3 +10,4 @@\n \
+        j\n \
+        k\n \
+        l\n\
+        +m\n",
+    );
+
+    assert_eq!(
+        cgitrc::diff(Path::new("cgitrc"), "", "a\n").unwrap(),
+        "--- cgitrc\n+++ cgitrc\n@@ -0,0 +1,1 @@\n+a\n",
+    );
+
+    assert!(cgitrc::diff(Path::new("cgitrc"), old, old).is_none());
+}
+
+#[test]
+fn render_matches_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let cgitrc_path = dir.path().join("cgitrc");
+
+    let existing = format!(
+        "owner=someone\n{}\nlogo=/custom.png\n",
+        cgitrc::MARKER,
+    );
+    fs::write(&cgitrc_path, &existing).unwrap();
+
+    let options = cgitrc::Options::default();
+    let rendered = cgitrc::render(
+        &cgitrc::read(dir.path()).unwrap(),
+        &test_repo(1, "reflectub"),
+        &options,
+    )
+        .unwrap();
+
+    // Rendering doesn't write.
+    assert_eq!(fs::read_to_string(&cgitrc_path).unwrap(), existing);
+
+    cgitrc::write(dir.path(), &test_repo(1, "reflectub"), &options).unwrap();
+    assert_eq!(fs::read_to_string(&cgitrc_path).unwrap(), rendered);
+
+    assert_eq!(cgitrc::read(dir.path().join("missing")).unwrap(), "");
+}
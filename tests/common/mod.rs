@@ -0,0 +1,168 @@
+// Copyright (c) 2021, 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+// Not every test crate uses every helper.
+#![allow(dead_code)]
+
+
+use chrono::DateTime;
+
+use reflectub::{database, provider};
+use reflectub::config::Config;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+
+/// A repository with the ID `id` and `name`, owned by "teddywing".
+pub fn test_repo(id: i64, name: &str) -> provider::Repo {
+    let updated_at = DateTime::parse_from_rfc3339("2022-03-04T05:06:07Z")
+        .unwrap();
+
+    provider::Repo {
+        id,
+        name: name.to_owned(),
+        owner: "teddywing".to_owned(),
+        description: Some("A test repository".to_owned()),
+        fork: false,
+        language: Some("Rust".to_owned()),
+        clone_url: format!("https://example.com/{}.git", name),
+        default_branch: "main".to_owned(),
+        homepage: None,
+        size: 42,
+        topics: vec!["cli".to_owned(), "git".to_owned()],
+        created_at: None,
+        updated_at,
+        pushed_at: updated_at,
+        has_wiki: false,
+        private: false,
+    }
+}
+
+/// Create the database at `config.database`.
+pub fn test_db(config: &Config) -> database::Db {
+    let db = database::Db::connect(config.database.as_ref().unwrap(), None)
+        .unwrap();
+    db.create().unwrap();
+
+    db
+}
+
+/// Create a database in memory.
+pub fn test_db_in_memory() -> database::Db {
+    let db = database::Db::connect_in_memory().unwrap();
+    db.create().unwrap();
+
+    db
+}
+
+/// Add an empty commit to the `master` branch of `repo`.
+pub fn commit(repo: &git2::Repository, message: &str) -> git2::Oid {
+    let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+
+    let tree_id = repo.treebuilder(None).unwrap().write().unwrap();
+    let tree = repo.find_tree(tree_id).unwrap();
+
+    let parent = repo.refname_to_id("refs/heads/master")
+        .ok()
+        .map(|id| repo.find_commit(id).unwrap());
+
+    repo.commit(
+        Some("refs/heads/master"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+    )
+        .unwrap()
+}
+
+pub fn file_url(path: &Path) -> String {
+    format!("file://{}", path.display())
+}
+
+/// Serve a GitHub API on a local port whose users all have the repositories
+/// in the JSON array `repos`, and return its base URL.
+///
+/// `repos` can be changed between requests. The server runs until the test
+/// process exits.
+pub fn mock_github_api(repos: Arc<Mutex<String>>) -> String {
+    mock_github_api_with_releases(repos, Arc::new(Mutex::new("[]".to_owned())))
+}
+
+/// Like `mock_github_api`, but every repository has the releases in the JSON
+/// array `releases`, and files under "/assets/" are served with their path
+/// as their contents.
+pub fn mock_github_api_with_releases(
+    repos: Arc<Mutex<String>>,
+    releases: Arc<Mutex<String>>,
+) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            // Skip the rest of the request headers.
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            let path = request_line
+                .split(' ')
+                .nth(1)
+                .unwrap_or_default();
+
+            // Everything is on the first page.
+            let body = if path.starts_with("/assets/") {
+                path.to_owned()
+            } else if path.contains("/releases?page=1&") {
+                releases.lock().unwrap().clone()
+            } else if request_line.contains("page=1&") {
+                repos.lock().unwrap().clone()
+            } else {
+                "[]".to_owned()
+            };
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\
+                \r\n\
+                {}",
+                body.len(),
+                body,
+            )
+                .unwrap();
+        }
+    });
+
+    base_url
+}
@@ -0,0 +1,278 @@
+// Copyright (c) 2021, 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use chrono::DateTime;
+
+use reflectub::{database, provider};
+
+mod common;
+use common::{test_db_in_memory, test_repo};
+
+
+#[test]
+fn repo_insert_and_update_round_trip() {
+    let db = test_db_in_memory();
+
+    let repo = test_repo(1, "repo-1");
+    db.repo_insert(database::Repo::from(&repo)).unwrap();
+
+    let stored = db.repo_get(1).unwrap();
+    assert_eq!(stored.id(), 1);
+    assert_eq!(stored.name(), Some("repo-1"));
+    assert_eq!(stored.description(), "A test repository");
+    assert_eq!(stored.default_branch.as_deref(), Some("main"));
+    assert_eq!(stored.updated_at(), Some(repo.updated_at));
+    assert_eq!(stored.owner.as_deref(), Some("teddywing"));
+    assert_eq!(stored.fork, Some(false));
+    assert_eq!(stored.language.as_deref(), Some("Rust"));
+
+    let mut updated = test_repo(1, "repo-1");
+    updated.description = None;
+    updated.pushed_at = updated.updated_at + chrono::Duration::seconds(1);
+
+    let updated = database::Repo::from(&updated);
+    assert!(db.repo_is_updated(&updated).unwrap());
+
+    db.repo_update(&updated).unwrap();
+    assert!(!db.repo_is_updated(&updated).unwrap());
+    assert_eq!(db.repo_get(1).unwrap().description(), "");
+}
+
+#[test]
+fn connect_in_memory_gives_separate_databases() {
+    let db = test_db_in_memory();
+    db.repo_insert(database::Repo::from(&test_repo(1, "repo-1"))).unwrap();
+
+    assert!(test_db_in_memory().repo_get(1).is_err());
+}
+
+#[test]
+fn repo_insert_stores_created_at() {
+    let db = test_db_in_memory();
+
+    let mut repo = test_repo(1, "repo-1");
+    repo.created_at = Some(
+        DateTime::parse_from_rfc3339("2019-01-02T03:04:05Z").unwrap()
+    );
+
+    db.repo_insert(database::Repo::from(&repo)).unwrap();
+
+    let stored = db.repo_get(1).unwrap();
+    assert_eq!(stored.created_at, repo.created_at);
+    assert_eq!(stored.topics, repo.topics);
+    assert_eq!(stored.size, Some(42));
+}
+
+#[test]
+fn repo_get_reads_null_created_at() {
+    let db = test_db_in_memory();
+
+    db.repo_insert(database::Repo::from(&test_repo(1, "repo-1"))).unwrap();
+
+    let stored = db.repo_get(1).unwrap();
+    assert_eq!(stored.created_at, None);
+
+    let all = db.repo_all().unwrap();
+    assert_eq!(all.len(), 1);
+    assert_eq!(provider::Repo::from(&all[0]).created_at, None);
+}
+
+#[test]
+fn run_state_records_status_until_cleared() {
+    let db = test_db_in_memory();
+
+    db.run_state_set(1, database::RunStatus::Failed).unwrap();
+    db.run_state_set(2, database::RunStatus::Done).unwrap();
+    db.run_state_set(1, database::RunStatus::Done).unwrap();
+
+    let run_state = db.run_state_all().unwrap();
+    assert_eq!(run_state.len(), 2);
+    assert_eq!(run_state[&1], database::RunStatus::Done);
+    assert_eq!(run_state[&2], database::RunStatus::Done);
+
+    db.run_state_clear().unwrap();
+    assert!(db.run_state_all().unwrap().is_empty());
+}
+
+#[test]
+fn repo_write_all_applies_all_writes_or_none() {
+    let db = test_db_in_memory();
+
+    db.repo_insert(database::Repo::from(&test_repo(1, "repo-1"))).unwrap();
+
+    let mut updated = test_repo(1, "repo-1");
+    updated.size = 100;
+
+    db.repo_write_all(
+        &[
+            database::RepoWrite::Update(database::Repo::from(&updated)),
+            database::RepoWrite::Insert(database::Repo::from(&test_repo(2, "repo-2"))),
+        ],
+        &[(1, database::RunStatus::Done), (2, database::RunStatus::Done)],
+    )
+        .unwrap();
+
+    assert_eq!(db.repo_get(1).unwrap().size, Some(100));
+    assert!(db.repo_get(2).is_ok());
+    assert_eq!(db.run_state_all().unwrap().len(), 2);
+
+    // The duplicate insert fails, so the first one and the run state are
+    // rolled back.
+    assert!(
+        db.repo_write_all(
+            &[
+                database::RepoWrite::Insert(
+                    database::Repo::from(&test_repo(3, "repo-3")),
+                ),
+                database::RepoWrite::Insert(
+                    database::Repo::from(&test_repo(2, "repo-2")),
+                ),
+            ],
+            &[(3, database::RunStatus::Done)],
+        )
+            .is_err()
+    );
+    assert!(db.repo_get(3).is_err());
+    assert!(!db.run_state_all().unwrap().contains_key(&3));
+}
+
+#[test]
+fn metadata_hash_ignores_unmirrored_changes() {
+    let repo = test_repo(1, "repo-1");
+    let hash = database::Repo::from(&repo).metadata_hash;
+    assert!(hash.is_some());
+
+    let mut starred = test_repo(1, "repo-1");
+    starred.updated_at = repo.updated_at + chrono::Duration::days(1);
+    assert_eq!(database::Repo::from(&starred).metadata_hash, hash);
+
+    let mut pushed = test_repo(1, "repo-1");
+    pushed.pushed_at = repo.pushed_at + chrono::Duration::days(1);
+    assert_ne!(database::Repo::from(&pushed).metadata_hash, hash);
+
+    let mut described = test_repo(1, "repo-1");
+    described.description = Some("A changed description".to_owned());
+    assert_ne!(database::Repo::from(&described).metadata_hash, hash);
+
+    let db = test_db_in_memory();
+    db.repo_insert(database::Repo::from(&repo)).unwrap();
+
+    let stored = db.repo_get(1).unwrap();
+    assert_eq!(stored.metadata_hash, hash);
+    assert_eq!(stored.pushed_at, Some(repo.pushed_at));
+}
+
+#[test]
+fn is_newer_than_matches_repo_is_updated() {
+    let db = test_db_in_memory();
+
+    let repo = test_repo(1, "repo-1");
+    db.repo_insert(database::Repo::from(&repo)).unwrap();
+    let stored = db.repo_get(1).unwrap();
+
+    let offsets = [
+        chrono::Duration::seconds(-1),
+        chrono::Duration::zero(),
+        chrono::Duration::milliseconds(1),
+        chrono::Duration::days(1),
+    ];
+
+    for offset in &offsets {
+        let mut fetched = test_repo(1, "repo-1");
+        fetched.pushed_at = repo.pushed_at + *offset;
+        let fetched = database::Repo::from(&fetched);
+
+        assert_eq!(
+            fetched.is_newer_than(&stored),
+            db.repo_is_updated(&fetched).unwrap(),
+            "offset {}",
+            offset,
+        );
+    }
+
+    // Neither counts a repository that isn't stored as updated.
+    let missing = database::Repo::from(&test_repo(2, "repo-2"));
+    assert!(!db.repo_is_updated(&missing).unwrap());
+}
+
+#[test]
+fn connect_creates_missing_parent_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("var/lib/reflectub/reflectub.db");
+
+    let db = database::Db::connect(path.to_str().unwrap(), None).unwrap();
+    db.create().unwrap();
+    db.repo_insert(database::Repo::from(&test_repo(1, "repo-1"))).unwrap();
+
+    assert!(path.is_file());
+}
+
+#[test]
+fn connect_reports_uncreatable_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("file");
+    std::fs::write(&file_path, "").unwrap();
+
+    let path = file_path.join("reflectub.db");
+
+    match database::Db::connect(path.to_str().unwrap(), None) {
+        Err(database::Error::CreateDir { path, .. }) =>
+            assert_eq!(path, file_path.display().to_string()),
+        other => panic!("expected a create directory error, got {:?}", other),
+    }
+}
+
+#[test]
+fn release_replace_all_replaces_stored_releases() {
+    let db = test_db_in_memory();
+
+    let published_at = DateTime::parse_from_rfc3339("2022-03-04T05:06:07Z")
+        .unwrap();
+
+    let release = |tag_name: &str, published_at| database::Release {
+        tag_name: tag_name.to_owned(),
+        name: None,
+        published_at,
+        asset_urls: vec![
+            format!("https://example.com/{}/a.tar.gz", tag_name),
+            format!("https://example.com/{}/b.zip", tag_name),
+        ],
+    };
+
+    let releases = [
+        release("draft", None),
+        release("v1.0.0", Some(published_at)),
+        release("v1.1.0", Some(published_at + chrono::Duration::days(1))),
+    ];
+
+    db.release_replace_all(1, &releases).unwrap();
+    db.release_replace_all(2, &releases[..1]).unwrap();
+
+    // Newest first, with unpublished releases last.
+    assert_eq!(
+        db.release_all(1).unwrap(),
+        [releases[2].clone(), releases[1].clone(), releases[0].clone()],
+    );
+
+    db.release_replace_all(1, &releases[1..2]).unwrap();
+    assert_eq!(db.release_all(1).unwrap(), [releases[1].clone()]);
+
+    db.repo_insert(database::Repo::from(&test_repo(2, "repo-2"))).unwrap();
+    db.repo_delete(2).unwrap();
+    assert!(db.release_all(2).unwrap().is_empty());
+}
@@ -0,0 +1,562 @@
+// Copyright (c) 2021, 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use reflectub::git;
+
+mod common;
+use common::{commit, file_url};
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+
+
+
+#[test]
+fn mirror_copies_refs_and_description_and_update_fetches_new_commits() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    let first = commit(&source, "First commit");
+    let second = commit(&source, "Second commit");
+    source.tag_lightweight(
+        "v1",
+        &source.find_object(first, None).unwrap(),
+        false,
+    )
+        .unwrap();
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions {
+        description: "A test repository".to_owned(),
+        ..git::MirrorOptions::new(&file_url(&source_path))
+    };
+    git::mirror(&mirror_path, &options).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), second);
+    assert_eq!(mirror.refname_to_id("refs/tags/v1").unwrap(), first);
+    assert_eq!(
+        fs::read_to_string(mirror_path.join("description")).unwrap(),
+        "A test repository\n",
+    );
+    assert!(
+        mirror.config().unwrap()
+            .get_bool("remote.origin.mirror")
+            .unwrap()
+    );
+
+    let third = commit(&source, "Third commit");
+    git::update(&mirror_path, "origin", &git::FetchSettings::default())
+        .unwrap();
+
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), third);
+}
+
+#[test]
+fn mirror_and_update_description_write_the_same_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    for description in &["A test repository", ""] {
+        let mirror_path = dir.path().join("mirror.git");
+        let options = git::MirrorOptions {
+            description: description.to_string(),
+            ..git::MirrorOptions::new(&file_url(&source_path))
+        };
+        git::mirror(&mirror_path, &options).unwrap();
+
+        let mirrored = fs::read(mirror_path.join("description")).unwrap();
+
+        git::update_description(&mirror_path, description).unwrap();
+
+        let updated = fs::read(mirror_path.join("description")).unwrap();
+        assert_eq!(mirrored, updated);
+
+        fs::remove_dir_all(&mirror_path).unwrap();
+    }
+}
+
+#[test]
+fn update_prunes_deleted_branches() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    let id = commit(&source, "First commit");
+    source.reference("refs/heads/topic", id, false, "test").unwrap();
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions::new(&file_url(&source_path));
+    git::mirror(&mirror_path, &options).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert!(mirror.find_reference("refs/heads/topic").is_ok());
+
+    source.find_reference("refs/heads/topic").unwrap().delete().unwrap();
+    git::update(&mirror_path, "origin", &git::FetchSettings::default())
+        .unwrap();
+
+    assert!(mirror.find_reference("refs/heads/topic").is_err());
+}
+
+#[test]
+fn mirror_points_head_at_default_branch() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    let id = commit(&source, "First commit");
+    source.reference("refs/heads/main", id, false, "test").unwrap();
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions {
+        default_branch: "main".to_owned(),
+        ..git::MirrorOptions::new(&file_url(&source_path))
+    };
+    git::mirror(&mirror_path, &options).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert_eq!(mirror.head().unwrap().name(), Some("refs/heads/main"));
+}
+
+#[test]
+fn change_current_branch_reconciles_initial_head() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    let mirror_path = dir.path().join("mirror.git");
+    git::mirror(
+        &mirror_path,
+        &git::MirrorOptions::new(&file_url(&source_path)),
+    )
+        .unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert_eq!(mirror.head().unwrap().name(), Some("refs/heads/master"));
+
+    // HEAD on an existing branch stays there if the new one is missing.
+    git::change_current_branch(&mirror_path, "main").unwrap();
+    assert_eq!(mirror.head().unwrap().name(), Some("refs/heads/master"));
+
+    // As after `git init` with `init.defaultBranch` set to "trunk".
+    mirror.set_head("refs/heads/trunk").unwrap();
+
+    git::change_current_branch(&mirror_path, "master").unwrap();
+    assert_eq!(mirror.head().unwrap().name(), Some("refs/heads/master"));
+
+    // HEAD on a missing branch is moved even if the new one is missing.
+    mirror.set_head("refs/heads/trunk").unwrap();
+
+    git::change_current_branch(&mirror_path, "main").unwrap();
+    assert_eq!(
+        mirror.find_reference("HEAD").unwrap().symbolic_target(),
+        Some("refs/heads/main"),
+    );
+}
+
+#[test]
+fn update_restores_missing_mirror_config() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    // A hand-made mirror with a plain remote.
+    let mirror_path = dir.path().join("mirror.git");
+    let mirror = git2::Repository::init_bare(&mirror_path).unwrap();
+    mirror.remote_with_fetch(
+        "upstream",
+        &file_url(&source_path),
+        "+refs/heads/*:refs/remotes/upstream/*",
+    )
+        .unwrap();
+
+    // Another remote added by hand.
+    mirror.remote_with_fetch(
+        "other",
+        &file_url(&source_path),
+        "+refs/heads/*:refs/remotes/other/*",
+    )
+        .unwrap();
+
+    let second = commit(&source, "Second commit");
+    git::update(&mirror_path, "upstream", &git::FetchSettings::default())
+        .unwrap();
+
+    let other = mirror.find_remote("other").unwrap();
+    assert_eq!(
+        other.fetch_refspecs().unwrap().iter().collect::<Vec<_>>(),
+        vec![Some("+refs/heads/*:refs/remotes/other/*")],
+    );
+    assert!(mirror.config().unwrap().get_bool("remote.other.mirror").is_err());
+
+    let remote = mirror.find_remote("upstream").unwrap();
+    assert!(
+        remote.fetch_refspecs().unwrap()
+            .iter()
+            .any(|refspec| refspec == Some("+refs/*:refs/*"))
+    );
+    assert!(
+        mirror.config().unwrap()
+            .get_bool("remote.upstream.mirror")
+            .unwrap()
+    );
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), second);
+}
+
+#[test]
+fn mirror_and_update_with_proxy_settings() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    // Local transports don't use the proxy, but the options must still be
+    // accepted.
+    let fetch_settings = git::FetchSettings {
+        proxy: Some("http://127.0.0.1:3128".to_owned()),
+        ..Default::default()
+    };
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions {
+        fetch_settings: fetch_settings.clone(),
+        ..git::MirrorOptions::new(&file_url(&source_path))
+    };
+    git::mirror(&mirror_path, &options).unwrap();
+
+    let second = commit(&source, "Second commit");
+    git::update(&mirror_path, "origin", &fetch_settings).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), second);
+}
+
+#[test]
+fn mirror_and_update_set_git_config() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    let fetch_settings = git::FetchSettings {
+        git_config: vec![
+            ("gc.auto".to_owned(), "0".to_owned()),
+            ("pack.threads".to_owned(), "2".to_owned()),
+        ],
+        ..Default::default()
+    };
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions {
+        fetch_settings: fetch_settings.clone(),
+        ..git::MirrorOptions::new(&file_url(&source_path))
+    };
+    git::mirror(&mirror_path, &options).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    let mut config = mirror.config().unwrap();
+    assert_eq!(config.get_string("gc.auto").unwrap(), "0");
+    assert_eq!(config.get_string("pack.threads").unwrap(), "2");
+
+    config.set_str("gc.auto", "256").unwrap();
+    assert!(
+        fs::read_to_string(mirror_path.join("config"))
+            .unwrap()
+            .contains("auto = 256")
+    );
+
+    git::update(&mirror_path, "origin", &fetch_settings).unwrap();
+    git::update(&mirror_path, "origin", &fetch_settings).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    let config = mirror.config().unwrap();
+    assert_eq!(config.get_string("gc.auto").unwrap(), "0");
+    assert_eq!(config.get_string("pack.threads").unwrap(), "2");
+    assert!(config.get_bool("remote.origin.mirror").unwrap());
+}
+
+#[test]
+fn update_with_trace_still_fetches() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    let mirror_path = dir.path().join("mirror.git");
+    git::mirror(&mirror_path, &git::MirrorOptions::new(&file_url(&source_path)))
+        .unwrap();
+
+    let second = commit(&source, "Second commit");
+
+    let fetch_settings = git::FetchSettings {
+        trace: true,
+        ..Default::default()
+    };
+    git::update(&mirror_path, "origin", &fetch_settings).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), second);
+}
+
+#[test]
+fn update_sends_custom_headers() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/reflectub.git",
+        listener.local_addr().unwrap(),
+    );
+
+    let (tx, rx) = mpsc::channel();
+
+    // Record the request headers, and answer with a 404 so the fetch fails
+    // without needing a real Git server.
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut headers = Vec::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            headers.push(line.trim_end().to_owned());
+            line.clear();
+        }
+
+        stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\n\
+            Content-Length: 0\r\n\
+            Connection: close\r\n\
+            \r\n",
+        )
+            .unwrap();
+
+        tx.send(headers).unwrap();
+    });
+
+    let mirror_path = dir.path().join("mirror.git");
+    git::init_mirror(&mirror_path, &git::MirrorOptions::new(&url)).unwrap();
+
+    let fetch_settings = git::FetchSettings {
+        headers: vec!["X-Auth: secret".to_owned()],
+        ..Default::default()
+    };
+    assert!(git::update(&mirror_path, "origin", &fetch_settings).is_err());
+
+    let headers = rx.recv().unwrap();
+    assert!(headers.iter().any(|h| h == "X-Auth: secret"), "{:?}", headers);
+}
+
+#[test]
+fn update_times_out_on_stalled_remote() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/reflectub.git",
+        listener.local_addr().unwrap(),
+    );
+
+    // Accept connections but never answer, so no progress callback runs.
+    thread::spawn(move || {
+        let mut streams = Vec::new();
+
+        for stream in listener.incoming() {
+            streams.push(stream.unwrap());
+        }
+    });
+
+    let mirror_path = dir.path().join("mirror.git");
+    git::init_mirror(&mirror_path, &git::MirrorOptions::new(&url)).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let fetch_settings = git::FetchSettings {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        tx.send(git::update(&mirror_path, "origin", &fetch_settings))
+            .unwrap();
+    });
+
+    let result = rx.recv_timeout(Duration::from_secs(30))
+        .expect("update didn't return after timing out");
+
+    match result {
+        Err(git::Error::UpdateFetch { source, .. }) => {
+            assert!(source.message().contains("timed out"), "{}", source);
+        },
+        other => panic!("expected a fetch timeout, got {:?}", other),
+    }
+}
+
+#[test]
+fn bundle_writes_all_refs_and_skips_empty_repos() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+
+    let bundle_path = dir.path().join("source.bundle");
+    assert!(!git::bundle(&source_path, &bundle_path).unwrap());
+    assert!(!bundle_path.exists());
+
+    commit(&source, "First commit");
+
+    assert!(git::bundle(&source_path, &bundle_path).unwrap());
+
+    let bundle = fs::read(&bundle_path).unwrap();
+    assert!(bundle.starts_with(b"# v2 git bundle\n"));
+    assert!(!dir.path().join("source.bundle.tmp").exists());
+
+    let verify = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&source_path)
+        .args(["bundle", "verify", "--quiet"])
+        .arg(&bundle_path)
+        .status()
+        .unwrap();
+    assert!(verify.success());
+}
+
+#[test]
+fn update_prunes_deleted_refs_of_every_kind_when_packed() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    let id = commit(&source, "First commit");
+
+    let deleted = ["refs/heads/topic", "refs/tags/v1.0", "refs/notes/commits"];
+    for name in &deleted {
+        source.reference(name, id, false, "test").unwrap();
+    }
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions::new(&file_url(&source_path));
+    git::mirror(&mirror_path, &options).unwrap();
+
+    // Refs in "packed-refs" are deleted differently from loose ones, as
+    // after `git gc`.
+    let status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&mirror_path)
+        .args(["pack-refs", "--all"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    for name in &deleted {
+        source.find_reference(name).unwrap().delete().unwrap();
+    }
+
+    git::update(&mirror_path, "origin", &git::FetchSettings::default())
+        .unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    let refs: Vec<String> = mirror
+        .references()
+        .unwrap()
+        .map(|r| r.unwrap().name().unwrap().to_owned())
+        .collect();
+    assert_eq!(refs, ["refs/heads/master"]);
+}
+
+#[test]
+fn mirror_and_update_without_tags_fetch_only_branches() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    let id = commit(&source, "First commit");
+    source.reference("refs/tags/v1.0", id, false, "test").unwrap();
+    source.reference("refs/heads/topic", id, false, "test").unwrap();
+
+    let fetch_settings = git::FetchSettings {
+        no_tags: true,
+        ..Default::default()
+    };
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions {
+        fetch_settings: fetch_settings.clone(),
+        ..git::MirrorOptions::new(&file_url(&source_path))
+    };
+    git::mirror(&mirror_path, &options).unwrap();
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert!(mirror.find_reference("refs/heads/topic").is_ok());
+    assert!(mirror.find_reference("refs/tags/v1.0").is_err());
+
+    let second = commit(&source, "Second commit");
+    source.reference("refs/tags/v2.0", second, false, "test").unwrap();
+    source.find_reference("refs/heads/topic").unwrap().delete().unwrap();
+
+    git::update(&mirror_path, "origin", &fetch_settings).unwrap();
+
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), second);
+    assert!(mirror.find_reference("refs/heads/topic").is_err());
+    assert!(mirror.find_reference("refs/tags/v2.0").is_err());
+}
+
+#[test]
+fn mirror_and_update_return_transfer_stats() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "First commit");
+
+    let mirror_path = dir.path().join("mirror.git");
+    let options = git::MirrorOptions::new(&file_url(&source_path));
+    let stats = git::mirror(&mirror_path, &options).unwrap();
+    assert!(stats.received_objects > 0, "{:?}", stats);
+    assert!(stats.received_bytes > 0, "{:?}", stats);
+
+    let settings = git::FetchSettings::default();
+    assert_eq!(
+        git::update(&mirror_path, "origin", &settings).unwrap(),
+        git::TransferStats::default(),
+    );
+
+    commit(&source, "Second commit");
+    assert!(
+        git::update(&mirror_path, "origin", &settings)
+            .unwrap()
+            .received_objects > 0
+    );
+}
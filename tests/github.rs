@@ -0,0 +1,411 @@
+// Copyright (c) 2021, 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use reflectub::{github, provider};
+use reflectub::provider::Provider;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::ops::ControlFlow;
+use std::sync::mpsc;
+use std::thread;
+
+
+/// Serve `response` to a single HTTP request on a local port, and return a
+/// `Github` provider pointed at it, along with a receiver for the request
+/// line.
+fn mock_github(response: &str) -> (github::Github, mpsc::Receiver<String>) {
+    let response = response.to_owned();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        // Read the request headers.
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+
+        stream.write_all(response.as_bytes()).unwrap();
+
+        let _ = tx.send(request_line.trim_end().to_owned());
+    });
+
+    let github = github::Github {
+        base_url,
+        agent: provider::new_agent(None, provider::DEFAULT_HTTP_TIMEOUT).unwrap(),
+        order: github::Order::default(),
+        token: None,
+    };
+
+    (github, rx)
+}
+
+
+#[test]
+fn fetch_repos_returns_user_not_found() {
+    let (github, _) = mock_github(
+        "HTTP/1.1 404 Not Found\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 23\r\n\
+        \r\n\
+        {\"message\":\"Not Found\"}",
+    );
+
+    match github.fetch_repos("nobody") {
+        Err(provider::Error::Github(github::Error::UserNotFound(user))) =>
+            assert_eq!(user, "nobody"),
+        other => panic!("expected a user not found error, got {:?}", other),
+    }
+}
+
+#[test]
+fn fetch_repos_returns_typed_status_errors() {
+    let (github, _) = mock_github(
+        "HTTP/1.1 403 Forbidden\r\n\
+        Content-Type: application/json\r\n\
+        X-RateLimit-Remaining: 0\r\n\
+        Content-Length: 37\r\n\
+        \r\n\
+        {\"message\":\"API rate limit exceeded\"}",
+    );
+
+    match github.fetch_repos("teddywing") {
+        Err(provider::Error::Github(
+            github::Error::RateLimited { status, message }
+        )) => {
+            assert_eq!(status, 403);
+            assert_eq!(message, "API rate limit exceeded");
+        },
+        other => panic!("expected a rate limit error, got {:?}", other),
+    }
+
+    let (github, _) = mock_github(
+        "HTTP/1.1 401 Unauthorized\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 29\r\n\
+        \r\n\
+        {\"message\":\"Bad credentials\"}",
+    );
+
+    match github.fetch_repos("teddywing") {
+        Err(provider::Error::Github(
+            github::Error::Unauthorized { status, message, .. }
+        )) => {
+            assert_eq!(status, 401);
+            assert_eq!(message, "Bad credentials");
+        },
+        other => panic!("expected an unauthorized error, got {:?}", other),
+    }
+
+    // Without a JSON body, the status text is used.
+    let (github, _) = mock_github(
+        "HTTP/1.1 502 Bad Gateway\r\n\
+        Content-Length: 0\r\n\
+        \r\n",
+    );
+
+    match github.fetch_repos("teddywing") {
+        Err(provider::Error::Github(
+            github::Error::Server { status, message }
+        )) => {
+            assert_eq!(status, 502);
+            assert_eq!(message, "Bad Gateway");
+        },
+        other => panic!("expected a server error, got {:?}", other),
+    }
+}
+
+#[test]
+fn fetch_repos_accepts_user_without_repos() {
+    let (github, _) = mock_github(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 2\r\n\
+        \r\n\
+        []",
+    );
+
+    assert!(github.fetch_repos("empty").unwrap().is_empty());
+}
+
+#[test]
+fn fetch_repos_each_stops_on_break() {
+    let body = r#"[{
+        "id": 1,
+        "name": "reflectub",
+        "owner": {"login": "teddywing"},
+        "description": null,
+        "fork": false,
+        "language": "Rust",
+        "clone_url": "https://github.com/teddywing/reflectub.git",
+        "default_branch": "master",
+        "homepage": null,
+        "size": 1,
+        "topics": [],
+        "created_at": "2021-01-01T00:00:00Z",
+        "updated_at": "2022-03-04T05:06:07Z",
+        "pushed_at": "2022-03-04T05:06:07Z"
+    }]"#;
+
+    // The mock only answers one request, so fetching a second page would
+    // fail.
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        \r\n\
+        {}",
+        body.len(),
+        body,
+    );
+    let (github, _) = mock_github(&response);
+
+    let mut pages = Vec::new();
+
+    github.fetch_repos_each("teddywing", &mut |page| {
+        pages.push(page);
+
+        ControlFlow::Break(())
+    })
+        .unwrap();
+
+    assert_eq!(pages.len(), 1);
+    assert_eq!(pages[0][0].name, "reflectub");
+}
+
+#[test]
+fn fetch_repos_requests_order() {
+    let (mut github, request_line) = mock_github(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 2\r\n\
+        \r\n\
+        []",
+    );
+    github.order = github::Order {
+        sort: "full_name".parse().unwrap(),
+        direction: Some("desc".parse().unwrap()),
+    };
+
+    github.fetch_repos("teddywing").unwrap();
+
+    let request_line = request_line.recv().unwrap();
+    assert!(
+        request_line.contains("&sort=full_name&direction=desc"),
+        "{}",
+        request_line,
+    );
+
+    assert!(matches!(
+        "stars".parse::<github::Sort>(),
+        Err(github::Error::InvalidSort(_)),
+    ));
+}
+
+#[test]
+fn fetch_repos_uses_graphql_with_token() {
+    let body = r#"{"data": {"repositoryOwner": {"repositories": {
+        "pageInfo": {"hasNextPage": false, "endCursor": "Y3Vyc29y"},
+        "nodes": [{
+            "databaseId": 1,
+            "name": "reflectub",
+            "owner": {"login": "teddywing"},
+            "description": "Mirror GitHub repositories",
+            "isFork": false,
+            "isPrivate": false,
+            "url": "https://github.com/teddywing/reflectub",
+            "homepageUrl": null,
+            "diskUsage": 42,
+            "createdAt": "2021-01-01T00:00:00Z",
+            "updatedAt": "2022-03-04T05:06:07Z",
+            "pushedAt": null,
+            "hasWikiEnabled": true,
+            "primaryLanguage": {"name": "Rust"},
+            "defaultBranchRef": {"name": "main"},
+            "repositoryTopics": {"nodes": [{"topic": {"name": "cgit"}}]}
+        }]
+    }}}}"#;
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        \r\n\
+        {}",
+        body.len(),
+        body,
+    );
+    let (mut github, request_line) = mock_github(&response);
+    github.token = Some("secret".to_owned());
+
+    let repos = github.fetch_repos("teddywing").unwrap();
+
+    assert!(
+        request_line.recv().unwrap().starts_with("POST /graphql "),
+    );
+
+    assert_eq!(repos.len(), 1);
+
+    let repo = &repos[0];
+    assert_eq!(repo.id, 1);
+    assert_eq!(repo.owner, "teddywing");
+    assert_eq!(
+        repo.clone_url,
+        "https://github.com/teddywing/reflectub.git",
+    );
+    assert_eq!(repo.default_branch, "main");
+    assert_eq!(repo.language.as_deref(), Some("Rust"));
+    assert_eq!(repo.size, 42);
+    assert_eq!(repo.topics, ["cgit"]);
+
+    // A repository that was never pushed to falls back to `updated_at`, as
+    // through the REST API.
+    assert_eq!(repo.pushed_at, repo.updated_at);
+    assert!(repo.has_wiki);
+}
+
+#[test]
+fn fetch_releases_lists_repo_releases() {
+    let body = r#"[{
+        "tag_name": "v1.0.0",
+        "name": "",
+        "published_at": null,
+        "assets": [{
+            "name": "reflectub.tar.gz",
+            "size": 1024,
+            "browser_download_url": "https://example.com/reflectub.tar.gz"
+        }]
+    }]"#;
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        \r\n\
+        {}",
+        body.len(),
+        body,
+    );
+    let (github, request_line) = mock_github(&response);
+
+    assert!(github.supports_releases());
+
+    let releases = github.fetch_releases("teddywing", "reflectub").unwrap();
+
+    assert!(
+        request_line.recv().unwrap().starts_with(
+            "GET /repos/teddywing/reflectub/releases?page=1&per_page=100 ",
+        ),
+    );
+
+    assert_eq!(
+        releases,
+        [provider::Release {
+            tag_name: "v1.0.0".to_owned(),
+            name: None,
+            published_at: None,
+            assets: vec![provider::Asset {
+                name: "reflectub.tar.gz".to_owned(),
+                size: 1024,
+                url: "https://example.com/reflectub.tar.gz".to_owned(),
+            }],
+        }],
+    );
+
+    let (github, _) = mock_github(
+        "HTTP/1.1 404 Not Found\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 23\r\n\
+        \r\n\
+        {\"message\":\"Not Found\"}",
+    );
+
+    match github.fetch_releases("teddywing", "missing") {
+        Err(provider::Error::Github(github::Error::RepoNotFound(name))) =>
+            assert_eq!(name, "teddywing/missing"),
+        other => panic!("expected a repo not found error, got {:?}", other),
+    }
+}
+
+#[test]
+fn fetch_repos_falls_back_on_malformed_pushed_at() {
+    let repo = r#"{
+        "id": 1,
+        "name": "reflectub",
+        "owner": {"login": "teddywing"},
+        "description": null,
+        "fork": false,
+        "language": "Rust",
+        "clone_url": "https://github.com/teddywing/reflectub.git",
+        "default_branch": "master",
+        "homepage": null,
+        "size": 1,
+        "topics": [],
+        "created_at": "2021-01-01T00:00:00Z",
+        "updated_at": "2022-03-04T05:06:07Z",
+        "pushed_at": "2022-03-01T00:00:00Z"
+    }"#;
+
+    let body = format!(
+        "[{},{},{}]",
+        repo,
+        repo
+            .replace(r#""id": 1"#, r#""id": 2"#)
+            .replace("2022-03-01T00:00:00Z", "not a time"),
+        repo
+            .replace(r#""id": 1"#, r#""id": 3"#)
+            .replace(r#""2022-03-01T00:00:00Z""#, "null"),
+    );
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        \r\n\
+        {}",
+        body.len(),
+        body,
+    );
+    let (github, _) = mock_github(&response);
+
+    let mut pages = Vec::new();
+
+    github.fetch_repos_each("teddywing", &mut |page| {
+        pages.push(page);
+
+        ControlFlow::Break(())
+    })
+        .unwrap();
+
+    let repos = &pages[0];
+    assert_eq!(repos.len(), 3);
+    assert_eq!(repos[0].pushed_at.to_rfc3339(), "2022-03-01T00:00:00+00:00");
+
+    // Without a valid `pushed_at`, `updated_at` is used instead.
+    assert_eq!(repos[1].pushed_at, repos[1].updated_at);
+    assert_eq!(repos[2].pushed_at, repos[2].updated_at);
+}
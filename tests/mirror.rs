@@ -0,0 +1,1555 @@
+// Copyright (c) 2021, 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use chrono::DateTime;
+
+use reflectub::{config, database};
+use reflectub::config::Config;
+
+mod common;
+use common::{
+    commit,
+    file_url,
+    mock_github_api,
+    mock_github_api_with_releases,
+    test_db,
+    test_repo,
+};
+
+use std::fs;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+
+fn test_config(dir: &tempfile::TempDir) -> Config {
+    Config {
+        database: Some(
+            dir.path().join("reflectub.sqlite").to_string_lossy().into_owned()
+        ),
+        mirror_root: Some(dir.path().to_string_lossy().into_owned()),
+        ..Config::default()
+    }
+}
+
+
+/// Get a config for mirroring "teddywing"'s repositories from a mock GitHub
+/// API serving `repos` into the "mirrors" directory of `dir`.
+fn mock_github_config(
+    dir: &tempfile::TempDir,
+    repos: Arc<Mutex<String>>,
+) -> Config {
+    let mirror_root = dir.path().join("mirrors");
+    fs::create_dir_all(&mirror_root).unwrap();
+
+    Config {
+        api_base_url: Some(mock_github_api(repos)),
+        mirror_root: Some(mirror_root.to_string_lossy().into_owned()),
+        users: vec![config::User::new("teddywing")],
+        ..test_config(dir)
+    }
+}
+
+/// JSON for a GitHub API repository list containing a single repository.
+fn github_repos_json(
+    clone_url: &str,
+    description: Option<&str>,
+    updated_at: &str,
+    pushed_at: &str,
+) -> String {
+    format!(
+        r#"[{{
+            "id": 1,
+            "name": "reflectub",
+            "owner": {{"login": "teddywing"}},
+            "description": {},
+            "fork": false,
+            "language": null,
+            "clone_url": "{}",
+            "default_branch": "master",
+            "homepage": null,
+            "size": 1,
+            "topics": [],
+            "created_at": "2021-01-01T00:00:00Z",
+            "updated_at": "{}",
+            "pushed_at": "{}"
+        }}]"#,
+        description.map_or("null".to_owned(), |d| format!("\"{}\"", d)),
+        clone_url,
+        updated_at,
+        pushed_at,
+    )
+}
+
+
+#[test]
+fn mirror_user_requires_users() {
+    let dir = tempfile::tempdir().unwrap();
+
+    assert!(reflectub::mirror_user(&test_config(&dir)).is_err());
+}
+
+#[test]
+fn summary_into_result_marks_partial_failures() {
+    assert!(reflectub::Summary::default().into_result().is_ok());
+
+    let summary = reflectub::Summary {
+        mirrored: 1,
+        errors: vec![("broken".to_owned(), anyhow::anyhow!("failed"))],
+        ..reflectub::Summary::default()
+    };
+    assert!(summary.into_result().unwrap_err().is_partial());
+
+    let summary = reflectub::Summary {
+        errors: vec![("broken".to_owned(), anyhow::anyhow!("failed"))],
+        ..reflectub::Summary::default()
+    };
+    assert!(!summary.into_result().unwrap_err().is_partial());
+
+    let summary = reflectub::Summary {
+        mirrored: 1,
+        interrupted: 2,
+        stopped: true,
+        ..reflectub::Summary::default()
+    };
+    assert!(summary.into_result().is_err());
+}
+
+#[test]
+fn regenerate_cgitrc_writes_stored_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+
+    let db = test_db(&config);
+    db.repo_insert(database::Repo::from(&test_repo(1, "reflectub"))).unwrap();
+
+    let repo_path = dir.path().join("reflectub.git");
+    fs::create_dir(&repo_path).unwrap();
+
+    reflectub::regenerate_cgitrc(&config).unwrap();
+
+    let cgitrc = fs::read_to_string(repo_path.join("cgitrc")).unwrap();
+    assert!(cgitrc.contains("defbranch=main\n"));
+    assert!(cgitrc.contains("clone-url=https://example.com/reflectub.git\n"));
+}
+
+#[test]
+fn cgitrc_diff_leaves_cgitrc_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+
+    let db = test_db(&config);
+    db.repo_insert(database::Repo::from(&test_repo(1, "reflectub"))).unwrap();
+
+    let repo_path = dir.path().join("reflectub.git");
+    fs::create_dir(&repo_path).unwrap();
+    fs::write(repo_path.join("cgitrc"), "defbranch=master\n").unwrap();
+
+    reflectub::cgitrc_diff(&config, std::io::sink()).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(repo_path.join("cgitrc")).unwrap(),
+        "defbranch=master\n",
+    );
+}
+
+#[test]
+fn check_finds_missing_and_untracked_mirrors() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+
+    let db = test_db(&config);
+    db.repo_insert(database::Repo::from(&test_repo(1, "mirrored"))).unwrap();
+    db.repo_insert(database::Repo::from(&test_repo(2, "missing"))).unwrap();
+
+    fs::create_dir(dir.path().join("mirrored.git")).unwrap();
+    fs::create_dir(dir.path().join("untracked.git")).unwrap();
+
+    let inconsistencies = reflectub::check(&config).unwrap();
+    assert_eq!(inconsistencies.len(), 2);
+    assert!(matches!(
+        &inconsistencies[0],
+        reflectub::Inconsistency::MissingMirror { id: 2, .. }
+    ));
+    assert!(matches!(
+        &inconsistencies[1],
+        reflectub::Inconsistency::UntrackedMirror(path)
+            if path.ends_with("untracked.git")
+    ));
+
+    assert_eq!(reflectub::fix(&config, &inconsistencies).unwrap(), 1);
+    assert!(db.repo_get(2).is_err());
+    assert_eq!(reflectub::check(&config).unwrap().len(), 1);
+}
+
+#[test]
+fn check_finds_forks_stored_without_fork_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+
+    // As in rows stored before forks were recorded.
+    let mut db_repo = database::Repo::from(&test_repo(1, "forked"));
+    db_repo.fork = None;
+
+    let db = test_db(&config);
+    db.repo_insert(db_repo).unwrap();
+
+    fs::create_dir_all(dir.path().join("fork/forked.git")).unwrap();
+
+    assert!(reflectub::check(&config).unwrap().is_empty());
+}
+
+#[test]
+fn mirror_user_clears_description_removed_on_remote() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        Some("A test repository"),
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let mirror_root = dir.path().join("mirrors");
+    let config = mock_github_config(&dir, Arc::clone(&repos));
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    let description_path = mirror_root.join("reflectub.git/description");
+    assert_eq!(
+        fs::read_to_string(&description_path).unwrap(),
+        "A test repository\n",
+    );
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+
+    assert_eq!(fs::read_to_string(&description_path).unwrap(), "");
+
+    let conn = rusqlite::Connection::open(config.database.as_ref().unwrap())
+        .unwrap();
+    let description_is_null: bool = conn.query_row(
+        "SELECT description IS NULL FROM repositories WHERE id = 1",
+        [],
+        |row| row.get(0),
+    )
+        .unwrap();
+    assert!(description_is_null);
+}
+
+#[test]
+fn mirror_user_skips_fetch_unless_pushed_to() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let pushed_at = "2022-03-04T05:06:07Z";
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        Some("A test repository"),
+        pushed_at,
+        pushed_at,
+    )));
+
+    let mirror_root = dir.path().join("mirrors");
+    let config = mock_github_config(&dir, Arc::clone(&repos));
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert_eq!(summary.received_repos, 1);
+    assert!(summary.received.received_bytes > 0);
+
+    // Without the source, any fetch would fail.
+    fs::remove_dir_all(&source_path).unwrap();
+
+    // A bump to `updated_at` alone, like from a new star, changes nothing.
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        Some("A test repository"),
+        "2022-03-05T05:06:07Z",
+        pushed_at,
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.unchanged, 1);
+    assert_eq!(summary.received_repos, 0);
+    assert!(summary.errors.is_empty());
+
+    // A metadata change is applied without fetching.
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        Some("A renamed test repository"),
+        "2022-03-06T05:06:07Z",
+        pushed_at,
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert!(summary.errors.is_empty());
+    assert_eq!(
+        fs::read_to_string(mirror_root.join("reflectub.git/description"))
+            .unwrap(),
+        "A renamed test repository\n",
+    );
+
+    // A push is fetched.
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        Some("A renamed test repository"),
+        "2022-03-07T05:06:07Z",
+        "2022-03-07T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.errors.len(), 1);
+}
+
+#[test]
+fn mirror_user_keeps_most_recently_pushed_duplicate() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let older = github_repos_json(
+        &file_url(&source_path),
+        Some("older"),
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    );
+    let newer = github_repos_json(
+        &file_url(&source_path),
+        Some("newer"),
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    // Both entries have the same ID.
+    let repos = Arc::new(Mutex::new(format!(
+        "[{},{}]",
+        older.trim_start_matches('[').trim_end_matches(']'),
+        newer.trim_start_matches('[').trim_end_matches(']'),
+    )));
+
+    let config = mock_github_config(&dir, repos);
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert_eq!(summary.succeeded(), 1);
+    assert!(summary.errors.is_empty());
+
+    let db = test_db(&config);
+    assert_eq!(db.repo_get(1).unwrap().description(), "newer");
+}
+
+#[test]
+fn mirror_user_processes_repos_of_several_users_once() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    // The mock returns the same repository for every user.
+    let config = Config {
+        users: vec![
+            config::User::new("teddywing"),
+            config::User::new("reflectub-org"),
+        ],
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert_eq!(summary.succeeded(), 1);
+    assert!(summary.errors.is_empty());
+}
+
+#[test]
+fn mirror_user_skips_repos_in_ignore_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mirror_root = dir.path().join("mirrors");
+    fs::create_dir(&mirror_root).unwrap();
+    fs::write(
+        mirror_root.join(".reflectubignore"),
+        "# Skip everything from the mock\nreflect*\n",
+    )
+        .unwrap();
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        "file:///nonexistent.git",
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = mock_github_config(&dir, repos);
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+    assert!(summary.errors.is_empty());
+    assert!(!mirror_root.join("reflectub.git").exists());
+}
+
+#[test]
+fn mirror_user_skips_excluded_repos() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repo = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    );
+    let test_repo = repo
+        .replace(r#""id": 1"#, r#""id": 2"#)
+        .replace(r#""name": "reflectub""#, r#""name": "test-reflectub""#);
+
+    let repos = Arc::new(Mutex::new(format!(
+        "[{},{}]",
+        repo.trim_start_matches('[').trim_end_matches(']'),
+        test_repo.trim_start_matches('[').trim_end_matches(']'),
+    )));
+
+    let config = Config {
+        exclude: vec!["test-*".to_owned()],
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.errors.is_empty());
+
+    let mirror_root = dir.path().join("mirrors");
+    assert!(mirror_root.join("reflectub.git").is_dir());
+    assert!(!mirror_root.join("test-reflectub.git").exists());
+}
+
+#[test]
+fn mirror_user_refuses_more_than_max_repos() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        "file:///nonexistent.git",
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let mut config = Config {
+        max_repos: Some(0),
+        ..mock_github_config(&dir, repos)
+    };
+
+    let error = reflectub::mirror_user(&config).unwrap_err().to_string();
+    assert!(error.contains("found 1 repositories"));
+    assert!(error.contains("--force"));
+
+    // The mock's clone URL doesn't exist, so the repository fails instead.
+    config.force = true;
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.errors.len(), 1);
+}
+
+#[test]
+fn mirror_user_mirrors_wikis() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    let initial = commit(&source, "Initial commit");
+    source.reference("refs/heads/main", initial, false, "").unwrap();
+
+    let wiki_source = git2::Repository::init_bare(
+        dir.path().join("source.wiki.git"),
+    )
+        .unwrap();
+    let stale_commit = commit(&wiki_source, "Add stale page");
+    wiki_source.reference("refs/heads/main", stale_commit, false, "").unwrap();
+    let wiki_commit = commit(&wiki_source, "Add home page");
+
+    // The wiki's HEAD is "master" even though the repository's default
+    // branch is "main".
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&source_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""fork": false"#, r#""fork": false, "has_wiki": true"#)
+            .replace(r#""master""#, r#""main""#)
+    ));
+
+    let config = Config {
+        mirror_wikis: true,
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.errors.is_empty());
+    assert!(summary.warnings.is_empty(), "{:?}", summary.warnings);
+
+    let wiki = git2::Repository::open_bare(
+        dir.path().join("mirrors/reflectub.wiki.git"),
+    )
+        .unwrap();
+    assert_eq!(wiki.refname_to_id("refs/heads/master").unwrap(), wiki_commit);
+    assert_eq!(
+        wiki.find_reference("HEAD").unwrap().symbolic_target(),
+        Some("refs/heads/master"),
+    );
+
+    // The wiki isn't reported as an untracked mirror.
+    assert!(reflectub::check(&config).unwrap().is_empty());
+}
+
+#[test]
+fn mirror_user_skips_wiki_at_path_of_stored_repo() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let wiki_source = git2::Repository::init_bare(
+        dir.path().join("source.wiki.git"),
+    )
+        .unwrap();
+    commit(&wiki_source, "Add home page");
+
+    let other_path = dir.path().join("other.git");
+    let other = git2::Repository::init_bare(&other_path).unwrap();
+    let other_commit = commit(&other, "Other commit");
+
+    // A repository named "reflectub.wiki" is mirrored first.
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&other_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""id": 1"#, r#""id": 2"#)
+            .replace(r#""reflectub""#, r#""reflectub.wiki""#)
+    ));
+
+    let config = Config {
+        mirror_wikis: true,
+        ..mock_github_config(&dir, Arc::clone(&repos))
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )
+        .replace(r#""fork": false"#, r#""fork": false, "has_wiki": true"#);
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.errors.is_empty());
+    assert_eq!(summary.warnings.len(), 1);
+    assert!(
+        summary.warnings[0].message.contains("not mirroring wiki"),
+        "{}",
+        summary.warnings[0].message,
+    );
+
+    // The other repository's mirror is left alone.
+    let mirror = git2::Repository::open_bare(
+        dir.path().join("mirrors/reflectub.wiki.git"),
+    )
+        .unwrap();
+    assert_eq!(
+        mirror.refname_to_id("refs/heads/master").unwrap(),
+        other_commit,
+    );
+}
+
+#[test]
+fn disk_sizes_reports_mirrored_repos() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = mock_github_config(&dir, repos);
+
+    assert!(reflectub::disk_sizes(&config).unwrap().is_empty());
+
+    reflectub::mirror_user(&config).unwrap();
+
+    let sizes = reflectub::disk_sizes(&config).unwrap();
+    assert_eq!(sizes.len(), 1);
+    assert_eq!(sizes[0].0, "reflectub");
+    assert!(sizes[0].1 > 0);
+}
+
+#[test]
+fn mirror_user_refuses_unsafe_repo_names() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let json = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    );
+    let repos = Arc::new(Mutex::new(json.clone()));
+
+    let config = mock_github_config(&dir, Arc::clone(&repos));
+
+    for name in &["../evil", "nested/evil", "back\\\\slash"] {
+        *repos.lock().unwrap() = json.replace(
+            r#""name": "reflectub""#,
+            &format!(r#""name": "{}""#, name),
+        );
+
+        let summary = reflectub::mirror_user(&config).unwrap();
+        assert_eq!(summary.mirrored, 0, "{}", name);
+        assert_eq!(summary.errors.len(), 1, "{}", name);
+        assert!(
+            format!("{:#}", summary.errors[0].1).contains("unsafe"),
+            "{}",
+            name,
+        );
+    }
+
+    // Names starting with a dot are skipped without failing the run.
+    *repos.lock().unwrap() = json.replace(
+        r#""name": "reflectub""#,
+        r#""name": ".hidden""#,
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+    assert!(summary.errors.is_empty());
+    assert_eq!(summary.warnings.len(), 1);
+    assert!(summary.into_result().is_ok());
+
+    assert!(!dir.path().join("evil.git").exists());
+    assert!(!dir.path().join("mirrors/.hidden.git").exists());
+    assert!(!dir.path().join("mirrors/nested").exists());
+}
+
+#[test]
+fn mirror_user_mirrors_repos_exactly_at_maximum_size() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    // The repository's size is 1 KiB.
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let mut config = Config {
+        skip_larger_than: Some("1023".to_owned()),
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.skipped_size, 1);
+    assert_eq!(summary.mirrored, 0);
+
+    config.skip_larger_than = Some("1024".to_owned());
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.skipped_size, 0);
+    assert_eq!(summary.mirrored, 1);
+}
+
+#[test]
+fn mirror_user_refuses_unknown_size_with_maximum() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&source_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""size": 1"#, r#""size": 0"#)
+    ));
+
+    let mut config = Config {
+        skip_larger_than: Some("1M".to_owned()),
+        ..mock_github_config(&dir, Arc::clone(&repos))
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+    assert_eq!(summary.errors.len(), 1);
+    assert!(
+        format!("{:#}", summary.errors[0].1).contains("--mirror-unknown-size")
+    );
+
+    config.mirror_unknown_size = true;
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.errors.is_empty());
+
+    // Existing mirrors are updated without '--mirror-unknown-size'.
+    config.mirror_unknown_size = false;
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    )
+        .replace(r#""size": 1"#, r#""size": 0"#);
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert!(summary.errors.is_empty());
+}
+
+#[test]
+fn mirror_user_reports_wiki_update_failure_as_warning() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let wiki_source_path = dir.path().join("source.wiki.git");
+    let wiki_source = git2::Repository::init_bare(&wiki_source_path).unwrap();
+    commit(&wiki_source, "Add home page");
+
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&source_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""fork": false"#, r#""fork": false, "has_wiki": true"#)
+    ));
+
+    let config = Config {
+        mirror_wikis: true,
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert!(summary.warnings.is_empty());
+
+    fs::remove_dir_all(&wiki_source_path).unwrap();
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.unchanged, 1);
+    assert_eq!(summary.warnings.len(), 1);
+    assert_eq!(summary.warnings[0].repo, "reflectub");
+    assert!(summary.warnings[0].message.contains("unable to update wiki"));
+
+    // Warnings don't fail the run.
+    assert!(summary.into_result().is_ok());
+}
+
+#[test]
+fn mirror_user_applies_private_mode() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&source_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""fork": false"#, r#""fork": false, "private": true"#)
+    ));
+
+    let index_path = dir.path().join("index");
+    let mut config = Config {
+        private_mode: Some("skip".to_owned()),
+        generate_index: Some(index_path.clone()),
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+    assert!(!dir.path().join("mirrors/reflectub.git").exists());
+
+    config.private_mode = Some("hide".to_owned());
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    let cgitrc = fs::read_to_string(
+        dir.path().join("mirrors/reflectub.git/cgitrc"),
+    )
+        .unwrap();
+    assert!(cgitrc.contains("hide=1\n"), "{}", cgitrc);
+
+    let index = fs::read_to_string(&index_path).unwrap();
+    assert!(index.contains("repo.hide=1\n"), "{}", index);
+
+    config.private_mode = Some("secret".to_owned());
+    assert!(reflectub::mirror_user(&config).is_err());
+}
+
+#[test]
+fn mirror_user_applies_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = Config {
+        section: Some("Mirrors".to_owned()),
+        cgitrc_lines: vec!["enable-html-serving=1".to_owned()],
+        ..mock_github_config(&dir, repos)
+    };
+
+    let overrides_path = dir.path().join("mirrors/reflectub.overrides.toml");
+
+    fs::write(&overrides_path, "[reflectub]\nskip = true\n").unwrap();
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+
+    fs::write(
+        &overrides_path,
+        "[reflectub]\n\
+        section = \"Tools\"\n\
+        cgitrc_lines = [\"readme=:README.md\"]\n",
+    )
+        .unwrap();
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    let cgitrc = fs::read_to_string(
+        dir.path().join("mirrors/reflectub.git/cgitrc"),
+    )
+        .unwrap();
+    assert!(cgitrc.contains("section=Tools\n"), "{}", cgitrc);
+    assert!(
+        cgitrc.contains("enable-html-serving=1\nreadme=:README.md\n"),
+        "{}",
+        cgitrc,
+    );
+
+    fs::write(&overrides_path, "[reflectub]\nmirror = true\n").unwrap();
+    assert!(reflectub::mirror_user(&config).is_err());
+}
+
+#[test]
+fn mirror_user_skips_forks_unless_included() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&source_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""fork": false"#, r#""fork": true"#)
+    ));
+
+    let mut config = mock_github_config(&dir, repos);
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+    assert_eq!(summary.skipped_forks, 1);
+
+    config.include_forks = true;
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert_eq!(summary.skipped_forks, 0);
+    assert!(dir.path().join("mirrors/fork/reflectub.git").is_dir());
+}
+
+#[test]
+fn mirror_user_times_out_stalled_api_requests() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // Accept connections but never respond.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        let streams: Vec<_> = listener.incoming().collect();
+        drop(streams);
+    });
+
+    let config = Config {
+        api_base_url: Some(base_url),
+        http_timeout: Some("200ms".to_owned()),
+        users: vec![config::User::new("teddywing")],
+        ..test_config(&dir)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.errors.len(), 1);
+
+    let message = format!("{:#}", summary.errors[0].1);
+    assert!(message.contains("no response within 200ms"), "{}", message);
+    assert!(message.contains("--http-timeout"), "{}", message);
+}
+
+#[test]
+fn mirror_user_fetches_empty_repos_again_once_pushed_to() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+
+    let repos = Arc::new(Mutex::new(
+        github_repos_json(
+            &file_url(&source_path),
+            None,
+            "2022-03-04T05:06:07Z",
+            "2022-03-04T05:06:07Z",
+        )
+            .replace(r#""master""#, r#""main""#)
+    ));
+
+    let config = mock_github_config(&dir, Arc::clone(&repos));
+    let mirror_path = dir.path().join("mirrors/reflectub.git");
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert_eq!(summary.empty, 1);
+    assert!(summary.errors.is_empty());
+
+    let mirror = git2::Repository::open_bare(&mirror_path).unwrap();
+    assert_eq!(
+        mirror.find_reference("HEAD").unwrap().symbolic_target(),
+        Some("refs/heads/main"),
+    );
+    assert!(mirror_path.join("cgitrc").is_file());
+    assert!(!mirror_path.join("info/web/last-modified").exists());
+
+    let db = test_db(&config);
+    assert_eq!(db.repo_get(1).unwrap().last_mirrored_at, None);
+
+    commit(&source, "Initial commit");
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert_eq!(summary.empty, 0);
+    assert!(summary.errors.is_empty());
+
+    assert!(mirror.find_reference("refs/heads/master").is_ok());
+    assert!(db.repo_get(1).unwrap().last_mirrored_at.is_some());
+}
+
+#[test]
+fn mirror_user_writes_agefile_with_always_agefile() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let mut config = mock_github_config(&dir, Arc::clone(&repos));
+    let agefile_path = dir.path()
+        .join("mirrors/reflectub.git/info/web/last-modified");
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(!agefile_path.exists());
+
+    config.always_agefile = true;
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert_eq!(
+        fs::read_to_string(&agefile_path).unwrap(),
+        "2022-03-05T05:06:07Z\n",
+    );
+}
+
+#[test]
+fn mirror_user_rewrites_existing_agefile() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = Config {
+        always_agefile: true,
+        ..mock_github_config(&dir, Arc::clone(&repos))
+    };
+    let agefile_path = dir.path()
+        .join("mirrors/reflectub.git/info/web/last-modified");
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert_eq!(
+        fs::read_to_string(&agefile_path).unwrap(),
+        "2022-03-04T05:06:07Z\n",
+    );
+
+    // "info/web" already exists the second time.
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert!(summary.errors.is_empty());
+    assert_eq!(
+        fs::read_to_string(&agefile_path).unwrap(),
+        "2022-03-05T05:06:07Z\n",
+    );
+}
+
+#[test]
+fn mirror_user_uses_owner_base_cgitrc() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let base_cgitrc = dir.path().join("base-cgitrc");
+    fs::write(&base_cgitrc, "logo=/base.png\n").unwrap();
+
+    let owner_cgitrc = dir.path().join("teddywing-cgitrc");
+    fs::write(&owner_cgitrc, "logo=/teddywing.png\n").unwrap();
+
+    let config = Config {
+        cgitrc: Some(base_cgitrc),
+        cgitrc_for: std::iter::once(("teddywing".to_owned(), owner_cgitrc))
+            .collect(),
+        ..mock_github_config(&dir, repos)
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    let cgitrc = fs::read_to_string(
+        dir.path().join("mirrors/reflectub.git/cgitrc"),
+    )
+        .unwrap();
+    assert!(cgitrc.starts_with("logo=/teddywing.png\n"), "{}", cgitrc);
+}
+
+#[test]
+fn mirror_user_force_remirror_starts_over() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let mut config = mock_github_config(&dir, repos);
+    let stray_path = dir.path().join("mirrors/reflectub.git/stray");
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    fs::write(&stray_path, "").unwrap();
+
+    // Re-mirroring everything needs confirmation.
+    config.force_remirror = true;
+    assert!(reflectub::mirror_user(&config).is_err());
+
+    config.remirror_repos = vec!["reflectub".to_owned()];
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.errors.is_empty());
+    assert!(!stray_path.exists());
+
+    config.remirror_repos = vec!["other".to_owned()];
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.unchanged, 1);
+}
+
+#[test]
+fn mirror_user_with_shutdown_stops_only_its_own_run() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = mock_github_config(&dir, repos);
+
+    let shutdown = reflectub::Shutdown::new();
+    assert!(!shutdown.request());
+
+    let summary = reflectub::mirror_user_with_shutdown(&config, &shutdown)
+        .unwrap();
+    assert!(summary.stopped);
+    assert_eq!(summary.mirrored, 0);
+
+    let dry_run_config = Config {
+        dry_run: true,
+        ..config.clone()
+    };
+
+    let summary = reflectub::mirror_user(&dry_run_config).unwrap();
+    assert!(!summary.stopped);
+    assert_eq!(summary.plan, vec!["reflectub: mirror".to_owned()]);
+    assert!(!dir.path().join("mirrors/reflectub.git").exists());
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert!(!summary.stopped);
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.plan.is_empty());
+}
+
+#[test]
+fn mirror_user_dry_run_leaves_database_alone() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = mock_github_config(&dir, repos);
+    let dry_run_config = Config {
+        dry_run: true,
+        ..config.clone()
+    };
+    let database_path = dir.path().join("reflectub.sqlite");
+
+    let summary = reflectub::mirror_user(&dry_run_config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(!database_path.exists());
+
+    reflectub::mirror_user(&config).unwrap();
+
+    let database = fs::read(&database_path).unwrap();
+
+    let summary = reflectub::mirror_user(&dry_run_config).unwrap();
+    assert_eq!(summary.plan, vec!["reflectub: up to date".to_owned()]);
+    assert_eq!(fs::read(&database_path).unwrap(), database);
+}
+
+#[test]
+fn mirror_user_heal_keeps_mirror_on_fetch_failure() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = Config {
+        heal: true,
+        ..mock_github_config(&dir, Arc::clone(&repos))
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    let marker_path = dir.path().join("mirrors/reflectub.git/marker");
+    fs::write(&marker_path, "").unwrap();
+
+    // The fetch fails because the remote is unreachable, not because the
+    // mirror is corrupt.
+    fs::remove_dir_all(&source_path).unwrap();
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 0);
+    assert_eq!(summary.errors.len(), 1);
+    assert!(summary.warnings.is_empty());
+
+    assert!(marker_path.exists());
+
+    let db = test_db(&config);
+    assert!(db.repo_get(1).is_ok());
+}
+
+#[test]
+fn mirror_user_refuses_path_of_another_repo() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos_json = github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    );
+    let repos = Arc::new(Mutex::new(repos_json.clone()));
+
+    let config = mock_github_config(&dir, Arc::clone(&repos));
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    // A different repository with the same name, as after the first was
+    // deleted and another renamed to its name.
+    *repos.lock().unwrap() = repos_json.replace(r#""id": 1"#, r#""id": 2"#);
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 0);
+    assert_eq!(summary.errors.len(), 1);
+
+    let message = format!("{:#}", summary.errors[0].1);
+    assert!(
+        message.contains("is already the mirror of 'teddywing/reflectub'"),
+        "{}",
+        message,
+    );
+
+    let db = test_db(&config);
+    assert!(db.repo_get(2).is_err());
+    assert!(dir.path().join("mirrors/reflectub.git").is_dir());
+}
+
+#[test]
+fn mirror_user_fetches_moved_repos_from_new_url() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = mock_github_config(&dir, Arc::clone(&repos));
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+
+    // Nothing is left at the old URL after the move.
+    let moved_path = dir.path().join("moved.git");
+    fs::rename(&source_path, &moved_path).unwrap();
+
+    let moved = git2::Repository::open_bare(&moved_path).unwrap();
+    let second = commit(&moved, "Second commit");
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&moved_path),
+        None,
+        "2022-03-05T05:06:07Z",
+        "2022-03-05T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert!(summary.errors.is_empty());
+
+    let mirror = git2::Repository::open_bare(
+        dir.path().join("mirrors/reflectub.git"),
+    )
+        .unwrap();
+    assert_eq!(
+        mirror.find_remote("origin").unwrap().url(),
+        Some(file_url(&moved_path).as_str()),
+    );
+    assert_eq!(mirror.refname_to_id("refs/heads/master").unwrap(), second);
+
+    let db = test_db(&config);
+    assert_eq!(
+        db.repo_get(1).unwrap().clone_url,
+        Some(file_url(&moved_path)),
+    );
+}
+
+#[test]
+fn mirror_user_stores_releases_and_downloads_assets() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        None,
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+    let releases = Arc::new(Mutex::new(String::new()));
+
+    let base_url = mock_github_api_with_releases(
+        Arc::clone(&repos),
+        Arc::clone(&releases),
+    );
+
+    *releases.lock().unwrap() = format!(
+        r#"[{{
+            "tag_name": "v1.0.0",
+            "name": "First release",
+            "published_at": "2022-03-04T05:06:07Z",
+            "assets": [
+                {{
+                    "name": "small.tar.gz",
+                    "size": 20,
+                    "browser_download_url": "{0}/assets/small.tar.gz"
+                }},
+                {{
+                    "name": "large.tar.gz",
+                    "size": 2000,
+                    "browser_download_url": "{0}/assets/large.tar.gz"
+                }}
+            ]
+        }}]"#,
+        base_url,
+    );
+
+    let config = Config {
+        api_base_url: Some(base_url.clone()),
+        mirror_releases: true,
+        mirror_release_assets: true,
+        skip_assets_larger_than: Some("1K".to_owned()),
+        ..mock_github_config(&dir, Arc::clone(&repos))
+    };
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.warnings.is_empty(), "{:?}", summary.warnings);
+
+    let db = test_db(&config);
+    assert_eq!(
+        db.release_all(1).unwrap(),
+        [database::Release {
+            tag_name: "v1.0.0".to_owned(),
+            name: Some("First release".to_owned()),
+            published_at: Some(
+                DateTime::parse_from_rfc3339("2022-03-04T05:06:07Z").unwrap()
+            ),
+            asset_urls: vec![
+                format!("{}/assets/small.tar.gz", base_url),
+                format!("{}/assets/large.tar.gz", base_url),
+            ],
+        }],
+    );
+
+    let release_dir = dir.path().join("mirrors/reflectub.git/releases/v1.0.0");
+    assert_eq!(
+        fs::read_to_string(release_dir.join("small.tar.gz")).unwrap(),
+        "/assets/small.tar.gz",
+    );
+    assert!(!release_dir.join("large.tar.gz").exists());
+
+    // Releases are fetched again for up-to-date repositories.
+    *releases.lock().unwrap() = "[]".to_owned();
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.unchanged, 1);
+    assert!(db.release_all(1).unwrap().is_empty());
+}
+
+#[test]
+fn mirror_user_writes_metadata_json() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let source_path = dir.path().join("source.git");
+    let source = git2::Repository::init_bare(&source_path).unwrap();
+    commit(&source, "Initial commit");
+
+    let repos = Arc::new(Mutex::new(github_repos_json(
+        &file_url(&source_path),
+        Some("A test repository"),
+        "2022-03-04T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    )));
+
+    let config = Config {
+        write_metadata_json: true,
+        ..mock_github_config(&dir, Arc::clone(&repos))
+    };
+    let json_path = dir.path().join("mirrors/reflectub.git/reflectub.json");
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.mirrored, 1);
+    assert!(summary.warnings.is_empty());
+
+    let metadata: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(&json_path).unwrap(),
+    )
+        .unwrap();
+    assert_eq!(metadata["id"], 1);
+    assert_eq!(metadata["name"], "reflectub");
+    assert_eq!(metadata["description"], "A test repository");
+    assert_eq!(metadata["default_branch"], "master");
+    assert_eq!(metadata["pushed_at"], "2022-03-04T05:06:07+00:00");
+
+    *repos.lock().unwrap() = github_repos_json(
+        &file_url(&source_path),
+        Some("A renamed test repository"),
+        "2022-03-05T05:06:07Z",
+        "2022-03-04T05:06:07Z",
+    );
+
+    let summary = reflectub::mirror_user(&config).unwrap();
+    assert_eq!(summary.updated, 1);
+    assert!(
+        fs::read_to_string(&json_path)
+            .unwrap()
+            .contains(r#""description": "A renamed test repository""#)
+    );
+}
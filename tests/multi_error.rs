@@ -0,0 +1,61 @@
+// Copyright (c) 2022  Teddy Wing
+//
+// This file is part of Reflectub.
+//
+// Reflectub is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Reflectub is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Reflectub. If not, see <https://www.gnu.org/licenses/>.
+
+
+use reflectub::multi_error::MultiError;
+
+
+#[test]
+fn multi_error_iterates_errors_in_order() {
+    let error = MultiError::from(vec![
+        anyhow::anyhow!("first"),
+        anyhow::anyhow!("second"),
+    ]);
+
+    assert_eq!(error.len(), 2);
+    assert!(!error.is_empty());
+    assert!(!error.is_partial());
+
+    let messages: Vec<_> = error.iter().map(|e| e.to_string()).collect();
+    assert_eq!(messages, ["first", "second"]);
+
+    let mut count = 0;
+    for e in &error {
+        assert!(!e.to_string().is_empty());
+        count += 1;
+    }
+    assert_eq!(count, 2);
+
+    let messages: Vec<_> = error.into_iter().map(|e| e.to_string()).collect();
+    assert_eq!(messages, ["first", "second"]);
+
+    assert!(MultiError::partial(Vec::new()).is_empty());
+}
+
+#[test]
+fn multi_error_accumulates_errors_from_stages() {
+    let mut error: MultiError = vec![anyhow::anyhow!("fetch")]
+        .into_iter()
+        .collect();
+    assert!(!error.is_partial());
+
+    error.push(anyhow::anyhow!("process"));
+    error.extend(MultiError::partial(vec![anyhow::anyhow!("prune")]));
+
+    assert!(error.is_partial());
+    assert_eq!(error.to_string(), "fetch\nprocess\nprune");
+}